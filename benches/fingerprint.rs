@@ -0,0 +1,155 @@
+// Copyright 2024 Oxide Computer Company
+
+//! Measure what caching the fingerprint of a query saves.
+//!
+//! The `fingerprint` argument of `query-start` is a hash of the normalized
+//! text of the query. For a query with a fixed shape, it is only computed the
+//! first time, and looked up in a cache after that. This issues the same query
+//! through a `DTraceConnection` wrapping a mock connection that does no work,
+//! once as a query with a fixed shape, whose fingerprint is cached, and once as
+//! a query without one, whose fingerprint is computed every time, alternating
+//! between the two. It prints the median and 99th percentile of the time per
+//! query of each. No database is needed.
+//!
+//! Coalescing repeated queries computes the fingerprint of every query, so it
+//! is enabled here, to measure it without running under DTrace. Run it with
+//! `cargo bench --bench fingerprint`.
+
+use diesel::connection::{
+    AnsiTransactionManager, Connection, ConnectionSealed, Instrumentation, SimpleConnection,
+};
+use diesel::pg::Pg;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::result::{ConnectionResult, QueryResult};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_dtrace::{Config, DTraceConnection};
+use std::time::Instant;
+
+/// The number of queries timed together in each sample, so that the time of
+/// each is well above the resolution of the clock.
+const QUERIES_PER_SAMPLE: u32 = 100;
+
+/// The number of pairs of samples taken.
+const SAMPLES: usize = 10_000;
+
+diesel::table! {
+    users (id) {
+        id -> Integer,
+        name -> Text,
+        email -> Text,
+    }
+}
+
+/// A connection that completes every statement immediately, without a
+/// database.
+struct MockConnection {
+    transaction_manager: AnsiTransactionManager,
+    instrumentation: Option<Box<dyn Instrumentation>>,
+}
+
+impl SimpleConnection for MockConnection {
+    fn batch_execute(&mut self, _query: &str) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+impl ConnectionSealed for MockConnection {}
+
+impl Connection for MockConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(_database_url: &str) -> ConnectionResult<Self> {
+        Ok(Self {
+            transaction_manager: AnsiTransactionManager::default(),
+            instrumentation: None,
+        })
+    }
+
+    fn execute_returning_count<T>(&mut self, _source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        Ok(0)
+    }
+
+    fn transaction_state(&mut self) -> &mut AnsiTransactionManager {
+        &mut self.transaction_manager
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        &mut self.instrumentation
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.instrumentation = Some(Box::new(instrumentation));
+    }
+}
+
+/// A query that renders exactly as the one it wraps, with a fixed shape if
+/// `FIXED` is true, and without one otherwise.
+struct Shaped<T, const FIXED: bool>(T);
+
+impl<T: 'static, const FIXED: bool> QueryId for Shaped<T, FIXED> {
+    type QueryId = Self;
+    const HAS_STATIC_QUERY_ID: bool = FIXED;
+}
+
+impl<T: QueryFragment<Pg>, const FIXED: bool> QueryFragment<Pg> for Shaped<T, FIXED> {
+    fn walk_ast<'b>(&'b self, pass: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        self.0.walk_ast(pass)
+    }
+}
+
+/// Return the time per query of one sample of `query` on `conn`, in
+/// nanoseconds.
+fn sample<T>(conn: &mut DTraceConnection<MockConnection>, query: &T) -> f64
+where
+    T: QueryFragment<Pg> + QueryId,
+{
+    let start = Instant::now();
+    for _ in 0..QUERIES_PER_SAMPLE {
+        conn.execute_returning_count(query).unwrap();
+    }
+    start.elapsed().as_nanos() as f64 / f64::from(QUERIES_PER_SAMPLE)
+}
+
+/// Return the samples of the query with a fixed shape and of the one without,
+/// in pairs taken one after the other.
+fn measure(conn: &mut DTraceConnection<MockConnection>) -> Vec<(f64, f64)> {
+    let query = || {
+        users::table
+            .filter(users::name.eq("alice"))
+            .filter(users::email.like("%@example.com"))
+            .select((users::id, users::name))
+            .order(users::id.desc())
+            .limit(10)
+    };
+    let cached = Shaped::<_, true>(query());
+    let uncached = Shaped::<_, false>(query());
+    (0..SAMPLES)
+        .map(|_| (sample(conn, &cached), sample(conn, &uncached)))
+        .collect()
+}
+
+/// Return the value at quantile `q` of `samples`.
+fn quantile(samples: &mut [f64], q: f64) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    samples[((samples.len() - 1) as f64 * q).round() as usize]
+}
+
+fn main() {
+    let config = Config::new().coalesce_repeated_queries(true);
+    let mut conn = DTraceConnection::<MockConnection>::establish_with_config("", config).unwrap();
+
+    // Warm up the connection, and the cache, before measuring.
+    measure(&mut conn);
+    let pairs = measure(&mut conn);
+    let mut cached: Vec<_> = pairs.iter().map(|(cached, _)| *cached).collect();
+    let mut uncached: Vec<_> = pairs.iter().map(|(_, uncached)| *uncached).collect();
+    for (name, q) in [("median", 0.5), ("p99", 0.99)] {
+        let cached = quantile(&mut cached, q);
+        let uncached = quantile(&mut uncached, q);
+        println!("{name}: cached {cached:.1} ns, uncached {uncached:.1} ns per query");
+    }
+}
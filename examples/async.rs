@@ -3,11 +3,14 @@
 use async_bb8_diesel::{AsyncSimpleConnection, ConnectionManager};
 use bb8::Pool;
 use diesel::pg::PgConnection;
-use diesel_dtrace::DTraceConnection;
+use diesel_dtrace::{instrument_async_checkout, DTraceConnection};
+use std::time::Instant;
 
 #[tokio::main]
 async fn main() {
-    usdt::register_probes().unwrap();
+    if let Err(e) = diesel_dtrace::init() {
+        eprintln!("{e}, continuing without probes");
+    }
     let url = if let Some(url) = std::env::args().nth(1) {
         url
     } else {
@@ -18,7 +21,15 @@ async fn main() {
         .build(manager)
         .await
         .expect("Failed to build pool");
-    let conn = pool.get().await.expect("Failed to connect to DB");
+
+    // Checking out a connection can wait for a connection to become available,
+    // and this wrapper fires the `pool-checkout-*` probes around that wait.
+    let start = Instant::now();
+    let conn = instrument_async_checkout(pool.get())
+        .await
+        .expect("Failed to connect to DB");
+    println!("checked out a connection in {:?}", start.elapsed());
+
     let _ = conn
         .batch_execute_async(concat!(
             "CREATE DATABASE my_test; ",
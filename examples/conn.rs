@@ -8,7 +8,9 @@ use diesel::{
 use diesel_dtrace::DTraceConnection;
 
 fn main() {
-    usdt::register_probes().unwrap();
+    if let Err(e) = diesel_dtrace::init() {
+        eprintln!("{e}, continuing without probes");
+    }
     let url = if let Some(url) = std::env::args().nth(1) {
         url
     } else {
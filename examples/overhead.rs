@@ -0,0 +1,131 @@
+// Copyright 2024 Oxide Computer Company
+
+//! Measure the overhead `DTraceConnection` adds to each query.
+//!
+//! This issues the same statement through a mock connection that does no
+//! work, bare and wrapped in a `DTraceConnection`, alternating between the two
+//! so that each sample of the wrapped connection is paired with one of the
+//! bare connection taken right before it. It prints the median and 99th
+//! percentile of the time per query of each, and of the difference within
+//! each pair, the time the wrapper adds per query. No database is needed.
+//!
+//! Run it with `cargo run --release --example overhead` to measure the cost
+//! with the probes disabled. To measure it with the probes enabled, run it
+//! under DTrace, e.g., with
+//! `dtrace -Zqn 'diesel_db*:::query-* {}' -c 'target/release/examples/overhead'`.
+
+use diesel::connection::{
+    AnsiTransactionManager, Connection, ConnectionSealed, Instrumentation, SimpleConnection,
+};
+use diesel::pg::Pg;
+use diesel::query_builder::{QueryFragment, QueryId};
+use diesel::result::{ConnectionResult, QueryResult};
+use diesel_dtrace::DTraceConnection;
+use std::time::Instant;
+
+/// The number of queries timed together in each sample, so that the time of
+/// each is well above the resolution of the clock.
+const QUERIES_PER_SAMPLE: u32 = 100;
+
+/// The number of pairs of samples taken.
+const SAMPLES: usize = 10_000;
+
+/// A connection that completes every statement immediately, without a
+/// database.
+struct MockConnection {
+    transaction_manager: AnsiTransactionManager,
+    instrumentation: Option<Box<dyn Instrumentation>>,
+}
+
+impl SimpleConnection for MockConnection {
+    fn batch_execute(&mut self, _query: &str) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+impl ConnectionSealed for MockConnection {}
+
+impl Connection for MockConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(_database_url: &str) -> ConnectionResult<Self> {
+        Ok(Self {
+            transaction_manager: AnsiTransactionManager::default(),
+            instrumentation: None,
+        })
+    }
+
+    fn execute_returning_count<T>(&mut self, _source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        Ok(0)
+    }
+
+    fn transaction_state(&mut self) -> &mut AnsiTransactionManager {
+        &mut self.transaction_manager
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        &mut self.instrumentation
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.instrumentation = Some(Box::new(instrumentation));
+    }
+}
+
+/// Return the time per query of one sample of `conn`, in nanoseconds.
+fn sample<C: Connection<Backend = Pg>>(conn: &mut C) -> f64 {
+    let query = diesel::sql_query("SELECT 1");
+    let start = Instant::now();
+    for _ in 0..QUERIES_PER_SAMPLE {
+        conn.execute_returning_count(&query).unwrap();
+    }
+    start.elapsed().as_nanos() as f64 / f64::from(QUERIES_PER_SAMPLE)
+}
+
+/// Return the samples of `bare` and `wrapped`, in pairs taken one after the
+/// other, so that both samples of a pair see the same state of the machine.
+fn measure(
+    bare: &mut MockConnection,
+    wrapped: &mut DTraceConnection<MockConnection>,
+) -> Vec<(f64, f64)> {
+    (0..SAMPLES)
+        .map(|_| (sample(bare), sample(wrapped)))
+        .collect()
+}
+
+/// Return the value at quantile `q` of `samples`.
+fn quantile(samples: &mut [f64], q: f64) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    samples[((samples.len() - 1) as f64 * q).round() as usize]
+}
+
+fn main() {
+    if let Err(e) = diesel_dtrace::init() {
+        eprintln!("{e}, continuing without probes");
+    }
+    let mut bare = MockConnection::establish("").unwrap();
+    let mut wrapped = DTraceConnection::<MockConnection>::establish("").unwrap();
+
+    // Warm up both connections before measuring either.
+    measure(&mut bare, &mut wrapped);
+    let pairs = measure(&mut bare, &mut wrapped);
+    let mut bare: Vec<_> = pairs.iter().map(|(bare, _)| *bare).collect();
+    let mut wrapped: Vec<_> = pairs.iter().map(|(_, wrapped)| *wrapped).collect();
+    let mut added: Vec<_> = pairs.iter().map(|(bare, wrapped)| wrapped - bare).collect();
+
+    // The quantiles of the differences aren't the differences of the
+    // quantiles: the p99 of `added` is the time added to a query in the worst
+    // 1% of pairs, whatever the bare connection took in those pairs.
+    for (name, q) in [("median", 0.5), ("p99", 0.99)] {
+        let bare = quantile(&mut bare, q);
+        let wrapped = quantile(&mut wrapped, q);
+        let added = quantile(&mut added, q);
+        println!(
+            "{name}: bare {bare:.1} ns, wrapped {wrapped:.1} ns, added {added:.1} ns per query"
+        );
+    }
+}
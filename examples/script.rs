@@ -0,0 +1,43 @@
+// Copyright 2024 Oxide Computer Company
+
+//! Print a D script that counts the firings of every probe, and prints their
+//! arguments as they fire.
+//!
+//! Run it with, e.g., `cargo run --example script > all.d`, and then trace a
+//! process with `dtrace -Zqs all.d -p <PID>`.
+
+use diesel_dtrace::probe_definitions;
+
+fn main() {
+    for probe in probe_definitions() {
+        let mut format = Vec::new();
+        let mut args = Vec::new();
+        for (i, arg) in probe.args.iter().enumerate() {
+            if arg.ty.is_string() {
+                format.push(format!("{}=%s", arg.name));
+                args.push(format!("copyinstr(arg{i})"));
+            } else {
+                format.push(format!("{}=%d", arg.name));
+                args.push(format!("arg{i}"));
+            }
+        }
+        println!("{}*:::{}", probe.provider, probe.name);
+        println!("{{");
+        println!("    @counts[probename] = count();");
+        if args.is_empty() {
+            println!("    printf(\"%s\\n\", probename);");
+        } else {
+            println!(
+                "    printf(\"%s {}\\n\", probename, {});",
+                format.join(" "),
+                args.join(", ")
+            );
+        }
+        println!("}}");
+        println!();
+    }
+    println!("END");
+    println!("{{");
+    println!("    printa(@counts);");
+    println!("}}");
+}
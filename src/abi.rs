@@ -0,0 +1,600 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The argument lists of the probes, as data and as a compile-time guard.
+//!
+//! D scripts refer to probe arguments by position, so the argument lists of the
+//! probes are effectively an ABI. Changing the type of an argument, reordering
+//! arguments, or removing one silently breaks those scripts.
+//!
+//! [`probe_definitions`] describes each probe, for generating D scripts. And
+//! since `usdt` type-checks the arguments at every call site of a probe, the
+//! calls in `pin_probe_signatures`, with arguments of exactly the published
+//! types, fail to compile if a probe's signature changes.
+//!
+//! If a change is intentional, update the definition and the call for that
+//! probe here too. When adding a probe, or appending an argument to one, add it
+//! to both as well. The function is never called, so these calls never fire.
+
+use crate::probes;
+use crate::query::{QueryInfo, TransactionInfo};
+use usdt::UniqueId;
+use uuid::Uuid;
+
+const PROVIDER: &str = "diesel_db";
+
+/// The type of a probe argument, as seen from D.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgType {
+    /// A [`UniqueId`], which is a `uint64_t`.
+    UniqueId,
+    /// A [`Uuid`], which is passed as a JSON string, e.g., `{"ok":"..."}`.
+    Uuid,
+    /// A string.
+    Str,
+    /// A `uint8_t`, used for flags and small enumerations.
+    U8,
+    /// A `uint64_t`.
+    U64,
+    /// An `int64_t`.
+    I64,
+    /// A JSON object, whose keys are documented with the probe.
+    Json,
+}
+
+impl ArgType {
+    /// Return the D type of the argument.
+    pub fn d_type(&self) -> &'static str {
+        match self {
+            ArgType::UniqueId | ArgType::U64 => "uint64_t",
+            ArgType::Uuid | ArgType::Str | ArgType::Json => "char *",
+            ArgType::U8 => "uint8_t",
+            ArgType::I64 => "int64_t",
+        }
+    }
+
+    /// Return true if the argument is a string, which must be copied in with
+    /// `copyinstr()` to be used in D.
+    pub fn is_string(&self) -> bool {
+        matches!(self, ArgType::Uuid | ArgType::Str | ArgType::Json)
+    }
+}
+
+/// An argument to a probe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProbeArg {
+    /// The name of the argument.
+    pub name: &'static str,
+    /// The type of the argument.
+    pub ty: ArgType,
+}
+
+impl ProbeArg {
+    const fn new(name: &'static str, ty: ArgType) -> Self {
+        Self { name, ty }
+    }
+}
+
+/// The definition of a probe, as it appears to D.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProbeDef {
+    /// The name of the provider, without the process ID suffix.
+    pub provider: &'static str,
+    /// The name of the probe, e.g., `query-start`.
+    pub name: &'static str,
+    /// The arguments of the probe, in order, so the first is `arg0`.
+    pub args: &'static [ProbeArg],
+}
+
+/// Return the definitions of all the probes this crate fires.
+///
+/// This can be used to generate D scripts, or check existing ones, without
+/// copying the signatures of the probes by hand. See `examples/script.rs`.
+pub fn probe_definitions() -> &'static [ProbeDef] {
+    PROBES
+}
+
+const PROBES: &[ProbeDef] = &[
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-establish-wait",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("waited_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-establish-start",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("url", ArgType::Str),
+            ProbeArg::new("build_version", ArgType::Str),
+            ProbeArg::new("concurrent", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-establish-done",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("success", ArgType::U8),
+            ProbeArg::new("error_kind", ArgType::U8),
+            ProbeArg::new("timed_out", ArgType::U8),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-establish-give_up",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("attempts", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-format",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("format_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-truncated",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("bytes", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-start",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("query", ArgType::Str),
+            ProbeArg::new("in_transaction", ArgType::U8),
+            ProbeArg::new("kind", ArgType::U8),
+            ProbeArg::new("info", ArgType::Json),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-done",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("success", ArgType::U8),
+            ProbeArg::new("rows", ArgType::I64),
+            ProbeArg::new("query", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-slow",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("elapsed_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-error",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("error_kind", ArgType::U8),
+            ProbeArg::new("constraint", ArgType::Str),
+            ProbeArg::new("table", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-large_result",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("rows", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-empty",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-autocommit_write",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("query", ArgType::Str),
+            ProbeArg::new("kind", ArgType::U8),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-plan",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("plan", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-lock_wait",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("elapsed_nanos", ArgType::U64),
+            ProbeArg::new("execution_nanos", ArgType::U64),
+            ProbeArg::new("waited_on_lock", ArgType::U8),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-batch",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("count", ArgType::U64),
+            ProbeArg::new("total_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-repeated",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("fingerprint", ArgType::U64),
+            ProbeArg::new("count", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-first_error",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("error_kind", ArgType::U8),
+            ProbeArg::new("query", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-recovered",
+        args: &[ProbeArg::new("conn_id", ArgType::Uuid)],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-start",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("depth", ArgType::I64),
+            ProbeArg::new("is_nested", ArgType::U8),
+            ProbeArg::new("info", ArgType::Json),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-done",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("depth", ArgType::I64),
+            ProbeArg::new("committed", ArgType::U8),
+            ProbeArg::new("reason", ArgType::U8),
+            ProbeArg::new("is_nested", ArgType::U8),
+            ProbeArg::new("info", ArgType::Json),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-statements",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("statement_count", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "savepoint-start",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("depth", ArgType::I64),
+            ProbeArg::new("name", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "savepoint-done",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("depth", ArgType::I64),
+            ProbeArg::new("name", ArgType::Str),
+            ProbeArg::new("released", ArgType::U8),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-deep",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("depth", ArgType::I64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-retry",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("attempt", ArgType::U64),
+            ProbeArg::new("sqlstate", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-retries_exhausted",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("attempts", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "transaction-broken",
+        args: &[ProbeArg::new("conn_id", ArgType::Uuid)],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "two_phase-prepare",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("gid", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "two_phase-commit",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("gid", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "two_phase-rollback",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("gid", ArgType::Str),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "copy-progress",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("rows", ArgType::U64),
+            ProbeArg::new("bytes", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "pool-checkout-start",
+        args: &[ProbeArg::new("id", ArgType::UniqueId)],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "pool-checkout-done",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("success", ArgType::U8),
+            ProbeArg::new("waited_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "pool-wait-start",
+        args: &[ProbeArg::new("id", ArgType::UniqueId)],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "pool-wait-done",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("success", ArgType::U8),
+            ProbeArg::new("waited_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "validate-start",
+        args: &[ProbeArg::new("conn_id", ArgType::Uuid)],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "validate-done",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("success", ArgType::U8),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-reconnect",
+        args: &[
+            ProbeArg::new("old_id", ArgType::Uuid),
+            ProbeArg::new("new_id", ArgType::Uuid),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-close",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("queries_served", ArgType::U64),
+            ProbeArg::new("age_secs", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "uninstrumented-scope",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("elapsed_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "instrumentation-replaced",
+        args: &[ProbeArg::new("conn_id", ArgType::Uuid)],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-returned_dirty",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("depth", ArgType::I64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-first_query_delay",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("delay_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "connection-lock-wait",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("waited_nanos", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "statement_cache-size",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("entries", ArgType::U64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "query-cache",
+        args: &[
+            ProbeArg::new("id", ArgType::UniqueId),
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("hit", ArgType::U8),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "replica-lag",
+        args: &[
+            ProbeArg::new("conn_id", ArgType::Uuid),
+            ProbeArg::new("lag_millis", ArgType::I64),
+        ],
+    },
+    ProbeDef {
+        provider: PROVIDER,
+        name: "events-dropped",
+        args: &[ProbeArg::new("count", ArgType::U64)],
+    },
+];
+
+#[allow(dead_code)]
+fn pin_probe_signatures() {
+    let id = UniqueId::new();
+    let conn_id = Uuid::nil();
+    let text: &str = "";
+    let flag: u8 = 0;
+    let count: u64 = 0;
+    let depth: i64 = 0;
+
+    probes::connection__establish__wait!(|| (conn_id, count));
+    probes::connection__establish__start!(|| (&id, conn_id, text, text, count));
+    probes::connection__establish__done!(|| (&id, conn_id, flag, flag, flag));
+    probes::connection__establish__give_up!(|| (conn_id, count));
+    let info = QueryInfo {
+        bytes: count,
+        char_count: count,
+        op_name: String::new(),
+        correlation_id: conn_id,
+        role: flag,
+        fingerprint: count,
+        method: flag,
+        shard: String::new(),
+        context: String::new(),
+    };
+
+    probes::query__format!(|| (conn_id, count));
+    probes::query__truncated!(|| (&id, conn_id, count));
+    probes::query__start!(|| (&id, conn_id, text, flag, flag, info.clone()));
+    probes::query__done!(|| (&id, conn_id, flag, depth, text));
+    probes::query__slow!(|| (&id, conn_id, count));
+    probes::query__error!(|| (&id, conn_id, flag, text, text));
+    probes::query__large_result!(|| (conn_id, count));
+    probes::query__autocommit_write!(|| (&id, conn_id, text, flag));
+    probes::query__empty!(|| (&id, conn_id));
+    probes::query__plan!(|| (conn_id, text));
+    probes::query__lock_wait!(|| (conn_id, count, count, flag));
+    probes::query__batch!(|| (conn_id, count, count));
+    probes::query__repeated!(|| (conn_id, count, count));
+    probes::query__first_error!(|| (&id, conn_id, flag, text));
+    probes::query__recovered!(|| conn_id);
+    let transaction_info = TransactionInfo {
+        correlation_id: conn_id,
+        role: flag,
+        shard: String::new(),
+        context: String::new(),
+    };
+    probes::transaction__start!(|| (conn_id, depth, flag, transaction_info.clone()));
+    probes::transaction__done!(|| (conn_id, depth, flag, flag, flag, transaction_info.clone()));
+    probes::transaction__statements!(|| (conn_id, count));
+    probes::savepoint__start!(|| (conn_id, depth, text));
+    probes::savepoint__done!(|| (conn_id, depth, text, flag));
+    probes::transaction__deep!(|| (conn_id, depth));
+    probes::transaction__retry!(|| (conn_id, count, text));
+    probes::transaction__retries_exhausted!(|| (conn_id, count));
+    probes::transaction__broken!(|| conn_id);
+    probes::two_phase__prepare!(|| (conn_id, text));
+    probes::two_phase__commit!(|| (conn_id, text));
+    probes::two_phase__rollback!(|| (conn_id, text));
+    probes::copy__progress!(|| (conn_id, count, count));
+    probes::statement_cache__size!(|| (conn_id, count));
+    probes::query__cache!(|| (&id, conn_id, flag));
+    probes::replica__lag!(|| (conn_id, depth));
+    probes::events__dropped!(|| count);
+    probes::pool__checkout__start!(|| &id);
+    probes::pool__checkout__done!(|| (&id, conn_id, flag, count));
+    probes::pool__wait__start!(|| &id);
+    probes::pool__wait__done!(|| (&id, flag, count));
+    probes::validate__start!(|| conn_id);
+    probes::validate__done!(|| (conn_id, flag));
+    probes::connection__reconnect!(|| (conn_id, conn_id));
+    probes::connection__close!(|| (conn_id, count, count));
+    probes::uninstrumented__scope!(|| (conn_id, count));
+    probes::instrumentation__replaced!(|| conn_id);
+    probes::connection__returned_dirty!(|| (conn_id, depth));
+    probes::connection__first_query_delay!(|| (conn_id, count));
+    probes::connection__lock__wait!(|| (conn_id, count));
+}
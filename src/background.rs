@@ -0,0 +1,335 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Firing the per-query probes from a background thread.
+//!
+//! Once started with [`start_background_emission`], the query thread no
+//! longer fires `query-start`, `query-error` and `query-done` itself. It
+//! copies their arguments into a bounded channel, and a dedicated thread
+//! receives them and fires the probes, including serializing the JSON
+//! argument of `query-start`.
+//!
+//! Each query thread keeps its own sender on the channel, which it only
+//! replaces when background emission is started or stopped, so sending takes
+//! no lock of this crate's. The standard library's bounded channel hands each
+//! entry over with atomic operations on its head and tail, and only takes a
+//! lock to wake the background thread when it is waiting for work, or to
+//! block a query thread when the channel is full under
+//! [`OverflowPolicy::Block`]. Query threads do share the channel itself, and
+//! so contend for its head and for space in it.
+//!
+//! The arguments are rendered and copied for every query, whether or not the
+//! probes are enabled: `usdt` only tells whether a probe is enabled by running
+//! the closure that computes its arguments, on the thread that fires it.
+
+use crate::probes;
+use crate::query::QueryInfo;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SendError, SyncSender, TrySendError};
+use std::sync::{PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use usdt::UniqueId;
+use uuid::Uuid;
+
+/// What to do with the probes of a query when the channel to the background
+/// thread is full, see [`start_background_emission`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the probe, and count it in the next `events-dropped`.
+    Drop,
+    /// Block the query thread until the background thread makes room.
+    Block,
+}
+
+/// The arguments of a probe fired by the background thread.
+pub(crate) enum Deferred {
+    QueryStart {
+        id: UniqueId,
+        conn_id: Uuid,
+        query: String,
+        in_transaction: u8,
+        kind: u8,
+        info: QueryInfo,
+    },
+    QueryError {
+        id: UniqueId,
+        conn_id: Uuid,
+        error_kind: u8,
+        constraint: String,
+        table: String,
+    },
+    QueryDone {
+        id: UniqueId,
+        conn_id: Uuid,
+        success: u8,
+        rows: i64,
+        query: String,
+    },
+    /// Sent by `stop_background_emission`, after which the background thread
+    /// exits.
+    Stop,
+}
+
+impl Deferred {
+    fn fire(self) {
+        match self {
+            Deferred::QueryStart {
+                id,
+                conn_id,
+                query,
+                in_transaction,
+                kind,
+                info,
+            } => {
+                probes::query__start!(|| (
+                    &id,
+                    conn_id,
+                    query.as_str(),
+                    in_transaction,
+                    kind,
+                    info
+                ));
+            }
+            Deferred::QueryError {
+                id,
+                conn_id,
+                error_kind,
+                constraint,
+                table,
+            } => {
+                probes::query__error!(|| (
+                    &id,
+                    conn_id,
+                    error_kind,
+                    constraint.as_str(),
+                    table.as_str()
+                ));
+            }
+            Deferred::QueryDone {
+                id,
+                conn_id,
+                success,
+                rows,
+                query,
+            } => {
+                probes::query__done!(|| (&id, conn_id, success, rows, query.as_str()));
+            }
+            Deferred::Stop => {}
+        }
+    }
+}
+
+struct Emitter {
+    sender: SyncSender<Deferred>,
+    overflow: OverflowPolicy,
+    thread: JoinHandle<()>,
+}
+
+/// Whether the probes are fired from the background thread, so that the
+/// query thread can skip taking the lock when they aren't.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static EMITTER: RwLock<Option<Emitter>> = RwLock::new(None);
+
+/// The number of times background emission has been started or stopped, so
+/// that each thread can tell when its sender is out of date.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A query thread's sender on the channel to the background thread, if any,
+/// as of a `GENERATION`.
+struct Producer {
+    generation: u64,
+    sender: Option<(SyncSender<Deferred>, OverflowPolicy)>,
+}
+
+thread_local! {
+    static PRODUCER: RefCell<Producer> = const {
+        RefCell::new(Producer {
+            generation: 0,
+            sender: None,
+        })
+    };
+}
+
+/// The number of probes discarded since the last `events-dropped`.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Fire the `query-start`, `query-error` and `query-done` probes from a
+/// dedicated background thread, instead of the thread issuing the query.
+///
+/// This takes the serialization and firing of the probes off the query
+/// path, leaving only copying their arguments into a channel of `capacity`
+/// entries. When the channel is full, `overflow` determines whether the
+/// probe is discarded or the query thread waits for room. Discarded probes
+/// are counted, and reported by the `events-dropped` probe, which the
+/// background thread fires once it catches up.
+///
+/// This changes what the probes can be relied on for:
+///
+/// - They fire some time after the event they mark, so timestamps taken in
+///   a D script are late by how long the probe waited in the channel. Match
+///   `query-start` and `query-done` by their ID rather than by thread, and
+///   don't take durations from them.
+/// - They fire on the background thread, so `tid`, `ustack()` and
+///   thread-local variables in a D script refer to it, not to the thread
+///   issuing the query. The other probes still fire synchronously, on the
+///   query thread.
+/// - Their arguments are copied whether or not the probes are enabled, since
+///   the query thread can't tell, so the text of every query is rendered. This
+///   only pays off when the probes are enabled most of the time.
+///
+/// Connections in batched mode don't fire these probes, and are unaffected.
+/// `query-format` doesn't fire in this mode. Sending the arguments takes no
+/// lock of the crate's, but the channel is shared by all the query threads, and
+/// takes a lock of its own to wake the background thread when it's idle.
+/// Starting background emission while it is running replaces the thread,
+/// after the previous one fires the probes already in its channel.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero, or if the thread can't be spawned.
+pub fn start_background_emission(capacity: usize, overflow: OverflowPolicy) {
+    assert!(
+        capacity > 0,
+        "the channel needs room for at least one probe"
+    );
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let thread = thread::Builder::new()
+        .name(String::from("diesel-dtrace-probes"))
+        .spawn(move || run(receiver))
+        .expect("failed to spawn the thread firing the probes");
+    let previous = {
+        let mut emitter = EMITTER.write().unwrap_or_else(PoisonError::into_inner);
+        let previous = emitter.replace(Emitter {
+            sender,
+            overflow,
+            thread,
+        });
+        GENERATION.fetch_add(1, Ordering::Release);
+        previous
+    };
+    ENABLED.store(true, Ordering::Relaxed);
+    if let Some(previous) = previous {
+        previous.join();
+    }
+}
+
+/// Go back to firing the probes from the thread issuing each query.
+///
+/// This waits for the background thread to fire the probes already in its
+/// channel. A probe sent by another thread at the very moment the background
+/// thread exits may be lost.
+pub fn stop_background_emission() {
+    ENABLED.store(false, Ordering::Relaxed);
+    let previous = {
+        let mut emitter = EMITTER.write().unwrap_or_else(PoisonError::into_inner);
+        let previous = emitter.take();
+        GENERATION.fetch_add(1, Ordering::Release);
+        previous
+    };
+    if let Some(previous) = previous {
+        previous.join();
+    }
+}
+
+impl Emitter {
+    fn join(self) {
+        // The query threads' senders keep the channel open until they next
+        // send, so tell the thread to exit, once it has fired the probes sent
+        // before.
+        let _ = self.sender.send(Deferred::Stop);
+        let _ = self.thread.join();
+    }
+}
+
+/// Return true if the probes are fired from the background thread.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Send the arguments of a probe to the background thread.
+///
+/// If background emission was stopped since [`enabled`] returned true, the
+/// probe fires on the calling thread instead.
+pub(crate) fn send(deferred: Deferred) {
+    let mut deferred = Some(deferred);
+    // This fails only while the thread is exiting, in which case the probe
+    // fires here.
+    let _ = PRODUCER.try_with(|producer| {
+        let mut producer = producer.borrow_mut();
+        let generation = GENERATION.load(Ordering::Acquire);
+        if producer.generation != generation {
+            let emitter = EMITTER.read().unwrap_or_else(PoisonError::into_inner);
+            producer.sender = emitter
+                .as_ref()
+                .map(|emitter| (emitter.sender.clone(), emitter.overflow));
+            producer.generation = generation;
+        }
+        let Some((sender, overflow)) = &producer.sender else {
+            return;
+        };
+        let Some(entry) = deferred.take() else {
+            return;
+        };
+        // A disconnected channel means emission stopped since this thread's
+        // sender was last updated.
+        deferred = match overflow {
+            OverflowPolicy::Drop => match sender.try_send(entry) {
+                Ok(()) => None,
+                Err(TrySendError::Full(_)) => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+                Err(TrySendError::Disconnected(entry)) => Some(entry),
+            },
+            OverflowPolicy::Block => sender.send(entry).err().map(|SendError(entry)| entry),
+        };
+    });
+    if let Some(deferred) = deferred {
+        deferred.fire();
+    }
+}
+
+/// Fire the probes received from the query threads, until told to stop.
+fn run(receiver: Receiver<Deferred>) {
+    'run: while let Ok(deferred) = receiver.recv() {
+        let mut next = Some(deferred);
+        while let Some(deferred) = next {
+            if let Deferred::Stop = deferred {
+                break 'run;
+            }
+            deferred.fire();
+            next = receiver.try_recv().ok();
+        }
+        // Report the probes dropped while the channel was full once it has
+        // drained, rather than after every probe.
+        report_dropped();
+    }
+    // Fire the probes of threads that sent them just as emission stopped.
+    while let Ok(deferred) = receiver.try_recv() {
+        deferred.fire();
+    }
+    report_dropped();
+}
+
+/// Fire `events-dropped` with the number of probes dropped since it last
+/// fired, if any.
+fn report_dropped() {
+    if DROPPED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let count = DROPPED.swap(0, Ordering::Relaxed);
+    probes::events__dropped!(|| count);
+}
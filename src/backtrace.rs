@@ -0,0 +1,69 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capturing the backtraces of slow and failed queries.
+
+use crate::clock;
+use std::backtrace::Backtrace;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Which queries a backtrace is captured for, see
+/// [`Config::capture_backtraces`](crate::Config::capture_backtraces).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BacktraceOn {
+    /// Queries that take longer than the slow-query threshold.
+    Slow,
+    /// Queries that fail.
+    Error,
+    /// Queries that are slow or fail.
+    SlowOrError,
+}
+
+/// When backtraces are captured, see
+/// [`Config::capture_backtraces`](crate::Config::capture_backtraces).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BacktraceCapture {
+    pub(crate) on: BacktraceOn,
+    pub(crate) min_interval: Duration,
+}
+
+/// The time the last backtrace was captured, in any connection.
+static LAST_CAPTURED: Mutex<Option<Instant>> = Mutex::new(None);
+
+impl BacktraceCapture {
+    /// Capture a backtrace for a query that just completed, if it was `slow`
+    /// or `failed` as configured, and the last backtrace was captured at least
+    /// the minimum interval ago.
+    pub(crate) fn capture(&self, slow: bool, failed: bool) -> Option<Backtrace> {
+        let wanted = match self.on {
+            BacktraceOn::Slow => slow,
+            BacktraceOn::Error => failed,
+            BacktraceOn::SlowOrError => slow || failed,
+        };
+        if !wanted {
+            return None;
+        }
+        // If another thread is deciding whether to capture one right now,
+        // this query is within the interval anyway.
+        let mut last = LAST_CAPTURED.try_lock().ok()?;
+        let now = clock::now();
+        if last.is_some_and(|last| now.saturating_duration_since(last) < self.min_interval) {
+            return None;
+        }
+        *last = Some(now);
+        drop(last);
+        Some(Backtrace::force_capture())
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coalescing of completed queries into the `query-batch` probe.
+
+use crate::clock;
+use crate::probes;
+use crate::query::duration_nanos;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// When a batch of queries is emitted, see
+/// [`Config::batch_queries`](crate::Config::batch_queries).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BatchLimits {
+    pub(crate) max_queries: u64,
+    pub(crate) max_interval: Duration,
+}
+
+/// The queries completed on one connection since the last batch was emitted.
+struct Batch {
+    conn_id: Uuid,
+    started: Instant,
+    count: u64,
+    total: Duration,
+}
+
+impl Batch {
+    fn emit(&self) {
+        probes::query__batch!(|| (self.conn_id, self.count, duration_nanos(self.total)));
+    }
+}
+
+// Emit whatever is left of the batch when the thread exits.
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.emit();
+    }
+}
+
+thread_local! {
+    static BATCH: RefCell<Option<Batch>> = const { RefCell::new(None) };
+}
+
+/// Add a query that took `elapsed` on connection `conn_id` to this thread's
+/// batch, emitting the batch if it has reached `limits`.
+///
+/// A batch only holds the queries of one connection, so a query on a
+/// different connection emits the batch first.
+pub(crate) fn record(conn_id: Uuid, elapsed: Duration, limits: BatchLimits) {
+    let _ = BATCH.try_with(|batch| {
+        let mut batch = batch.borrow_mut();
+        if batch.as_ref().is_some_and(|b| b.conn_id != conn_id) {
+            // Dropping the batch emits it.
+            *batch = None;
+        }
+        let now = clock::now();
+        let current = batch.get_or_insert_with(|| Batch {
+            conn_id,
+            started: now,
+            count: 0,
+            total: Duration::ZERO,
+        });
+        current.count += 1;
+        current.total += elapsed;
+        if current.count >= limits.max_queries
+            || now.saturating_duration_since(current.started) >= limits.max_interval
+        {
+            *batch = None;
+        }
+    });
+}
+
+/// Emit this thread's batch, if it holds queries of connection `conn_id`.
+pub(crate) fn flush(conn_id: Uuid) {
+    let _ = BATCH.try_with(|batch| {
+        let mut batch = batch.borrow_mut();
+        if batch.as_ref().is_some_and(|b| b.conn_id == conn_id) {
+            *batch = None;
+        }
+    });
+}
@@ -0,0 +1,140 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking the size of a connection's prepared statement cache.
+//!
+//! Diesel doesn't expose the size of its statement cache, but reports each
+//! statement it adds to the cache to the connection's instrumentation, with
+//! `InstrumentationEvent::CacheQuery`. Statements are never evicted from the
+//! cache, so counting those events gives its size exactly. The counting is
+//! done by an instrumentation installed on the inner connection, which
+//! forwards every event to the instrumentation it replaces.
+//!
+//! Installing it means moving the connection's existing instrumentation out of
+//! the connection, which is only possible when the connection stores it as an
+//! `Option<Box<dyn Instrumentation>>` and returns that from
+//! `Connection::instrumentation`. For a connection that doesn't, the existing
+//! instrumentation is left in place and the cache is untracked: the
+//! `query-cache` and `statement_cache-size` probes don't fire for it, rather
+//! than replacing an instrumentation the application may have installed.
+//!
+//! The same count tells whether each query was served by a statement already
+//! in the cache: diesel only reports statements it prepares and caches, so a
+//! query during which the count didn't grow prepared nothing new for the
+//! cache.
+
+use crate::probes;
+use diesel::connection::{Connection, Instrumentation, InstrumentationEvent};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use usdt::UniqueId;
+use uuid::Uuid;
+
+/// Counts the statements added to a connection's cache, on behalf of another
+/// instrumentation.
+struct CacheCounter {
+    inner: Option<Box<dyn Instrumentation>>,
+    entries: Arc<AtomicU64>,
+}
+
+impl Instrumentation for CacheCounter {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        if matches!(event, InstrumentationEvent::CacheQuery { .. }) {
+            self.entries.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.on_connection_event(event);
+    }
+}
+
+/// The size of a connection's statement cache, and the size last reported by
+/// the `statement_cache-size` probe.
+#[derive(Debug, Default)]
+pub(crate) struct StatementCacheSize {
+    entries: Arc<AtomicU64>,
+    reported: u64,
+    untracked: bool,
+}
+
+impl StatementCacheSize {
+    /// Start counting the statements cached by `conn`, around the
+    /// instrumentation it has, if that can be moved out of it.
+    pub(crate) fn install(conn: &mut impl Connection) -> Self {
+        let mut size = Self::default();
+        let existing = conn
+            .instrumentation()
+            .downcast_mut::<Option<Box<dyn Instrumentation>>>()
+            .map(Option::take);
+        match existing {
+            Some(existing) => conn.set_instrumentation(size.instrument(existing)),
+            None => size.untracked = true,
+        }
+        size
+    }
+
+    /// Return an instrumentation that counts the statements cached by a
+    /// connection into this, and forwards all events to `inner`.
+    pub(crate) fn instrument(
+        &self,
+        inner: Option<Box<dyn Instrumentation>>,
+    ) -> impl Instrumentation {
+        CacheCounter {
+            inner,
+            entries: self.entries.clone(),
+        }
+    }
+
+    /// Fire the `query-cache` probe for the query `id` on the connection
+    /// `conn_id`, which just returned, and the `statement_cache-size` probe,
+    /// if the query grew the cache.
+    pub(crate) fn report(&mut self, id: &UniqueId, conn_id: Uuid) {
+        if self.untracked {
+            return;
+        }
+        let entries = self.entries.load(Ordering::Relaxed);
+        let hit = entries == self.reported;
+        probes::query__cache!(|| (id, conn_id, u8::from(hit)));
+        if !hit {
+            self.reported = entries;
+            probes::statement_cache__size!(|| (conn_id, entries));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockConnection;
+
+    /// Counts every event it sees.
+    struct Counting(Arc<AtomicU64>);
+
+    impl Instrumentation for Counting {
+        fn on_connection_event(&mut self, _: InstrumentationEvent<'_>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn install_keeps_the_existing_instrumentation() {
+        let events = Arc::new(AtomicU64::new(0));
+        let mut conn = MockConnection::default();
+        conn.set_instrumentation(Counting(events.clone()));
+        let size = StatementCacheSize::install(&mut conn);
+        conn.instrumentation()
+            .on_connection_event(InstrumentationEvent::cache_query("SELECT 1"));
+        assert!(!size.untracked);
+        assert_eq!(size.entries.load(Ordering::Relaxed), 1);
+        assert_eq!(events.load(Ordering::Relaxed), 1);
+    }
+}
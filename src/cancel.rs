@@ -0,0 +1,237 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reporting asynchronous transactions abandoned by a cancelled future.
+//!
+//! The transaction manager fires `transaction-done` when a transaction is
+//! committed or rolled back, but a future running a transaction can be dropped
+//! at any `.await` point in between, in which case neither ever happens. Each
+//! transaction run with [`instrument_async_transaction`] registers a watch on
+//! its connection, which the transaction manager updates as the outermost
+//! transaction on that connection starts and completes. If the future is
+//! dropped while the watch shows the transaction open, the guard reports the
+//! transaction as done on its behalf, as the transaction manager would have.
+
+use crate::clock;
+use crate::query::TransactionInfo;
+use crate::{transaction_done, TransactionDone, TransactionDoneReason};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// An outermost transaction that started while being watched.
+struct Started {
+    /// The details of the transaction passed to `transaction-done`.
+    info: TransactionInfo,
+    at: Instant,
+    /// The number of statements issued on the connection in the transaction,
+    /// shared with the connection.
+    statements: Arc<AtomicU64>,
+}
+
+/// The state of a transaction run with [`instrument_async_transaction`].
+#[derive(Default)]
+struct Watch {
+    started: Option<Started>,
+    completed: bool,
+}
+
+type WatchRef = Arc<Mutex<Watch>>;
+
+/// The number of watches registered, so that the transaction manager can skip
+/// taking the lock when there are none.
+static WATCHING: AtomicUsize = AtomicUsize::new(0);
+
+/// The watches registered for each connection.
+static WATCHES: Mutex<Option<HashMap<Uuid, Vec<WatchRef>>>> = Mutex::new(None);
+
+/// Apply `f` to the watches registered for the connection `conn_id`.
+fn update(conn_id: Uuid, mut f: impl FnMut(&mut Watch)) {
+    if WATCHING.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let watches = WATCHES.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(watches) = watches.as_ref().and_then(|watches| watches.get(&conn_id)) else {
+        return;
+    };
+    for watch in watches {
+        f(&mut watch.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+}
+
+/// Record that an outermost transaction started on the connection `conn_id`,
+/// which counts its `statements`, and whose details are returned by `info`.
+pub(crate) fn started(
+    conn_id: Uuid,
+    statements: &Arc<AtomicU64>,
+    info: impl Fn() -> TransactionInfo,
+) {
+    update(conn_id, |watch| {
+        if watch.started.is_none() {
+            watch.started = Some(Started {
+                info: info(),
+                at: clock::now(),
+                statements: statements.clone(),
+            });
+        }
+    });
+}
+
+/// Record that the outermost transaction on the connection `conn_id`
+/// completed, whether it was committed or not.
+pub(crate) fn completed(conn_id: Uuid) {
+    update(conn_id, |watch| {
+        if watch.started.is_some() {
+            watch.completed = true;
+        }
+    });
+}
+
+/// Reports the transaction it watches as done when dropped, unless disarmed,
+/// if the transaction started and hasn't completed.
+struct Guard {
+    conn_id: Uuid,
+    watch: WatchRef,
+    armed: bool,
+}
+
+impl Guard {
+    fn new(conn_id: Uuid) -> Self {
+        let watch = WatchRef::default();
+        WATCHES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(HashMap::new)
+            .entry(conn_id)
+            .or_default()
+            .push(watch.clone());
+        WATCHING.fetch_add(1, Ordering::Relaxed);
+        Self {
+            conn_id,
+            watch,
+            armed: true,
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // Unregister first, so that the transaction manager can no longer
+        // update the watch while it's being read.
+        {
+            let mut watches = WATCHES.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(watches) = watches.as_mut() {
+                if let Some(conn_watches) = watches.get_mut(&self.conn_id) {
+                    conn_watches.retain(|watch| !Arc::ptr_eq(watch, &self.watch));
+                    if conn_watches.is_empty() {
+                        watches.remove(&self.conn_id);
+                    }
+                }
+            }
+            WATCHING.fetch_sub(1, Ordering::Relaxed);
+        }
+        if !self.armed {
+            return;
+        }
+        let watch = self.watch.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(started) = watch.started.as_ref().filter(|_| !watch.completed) else {
+            return;
+        };
+        // Take the count of statements, as the transaction manager does, so
+        // that they aren't counted again if the transaction does complete.
+        let done = TransactionDone {
+            conn_id: self.conn_id,
+            depth: 0,
+            committed: false,
+            reason: TransactionDoneReason::Cancelled,
+            statements: Some(started.statements.swap(0, Ordering::Relaxed)),
+            started: Some(started.at),
+        };
+        transaction_done(&done, || started.info.clone());
+    }
+}
+
+/// Instrument an asynchronous transaction, so that `transaction-done` fires
+/// even if the future running it is cancelled.
+///
+/// Asynchronous transactions, such as those run with async-bb8-diesel's
+/// `transaction_async`, go through the transaction manager of the
+/// [`DTraceConnection`](crate::DTraceConnection) like any other, which fires
+/// `transaction-start`, and `transaction-done` with `committed == 1` or `0`
+/// when it commits or rolls back. If the future is dropped in between, e.g.,
+/// because the client of a server disconnected, it does neither, and the
+/// transaction appears to never end. Wrap the future with this function, e.g.,
+/// `instrument_async_transaction(conn_id, conn.transaction_async(...)).await`,
+/// and dropping it before the transaction completes fires `transaction-done`
+/// with `committed == 0` and a reason of
+/// [`TransactionDoneReason::Cancelled`], at depth `0`, after
+/// `transaction-statements`. The transaction is reported everywhere else a
+/// completed transaction is, e.g., to observers, as well.
+///
+/// `conn_id` is the ID of the connection that `transaction` runs on, as
+/// returned by [`DTraceConnection::id`](crate::DTraceConnection::id). Only
+/// the outermost transaction on that connection is watched. Nothing fires if
+/// the future is dropped before the transaction starts, or after it completes,
+/// and the probes the transaction manager fires are unchanged when the future
+/// runs to completion.
+///
+/// The commit or rollback of an asynchronous transaction may be running on
+/// another thread when the future is dropped. In that case, `transaction-done`
+/// fires with a reason of `Cancelled`, and then again with the real outcome
+/// once it completes.
+pub async fn instrument_async_transaction<F: Future>(conn_id: Uuid, transaction: F) -> F::Output {
+    let mut guard = Guard::new(conn_id);
+    let output = transaction.await;
+    guard.armed = false;
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{real_clock, MockConnection, Recorded, Recorder};
+    use crate::{Config, DTraceConnection, DTraceTransactionManager};
+    use diesel::connection::{Connection, TransactionManager};
+    use std::task::{Context, Waker};
+
+    #[test]
+    fn dropping_a_started_transaction_fires_done_once() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn =
+            DTraceConnection::<MockConnection>::establish_with_config("", Config::new()).unwrap();
+        let conn_id = conn.id();
+        let mut transaction = Box::pin(instrument_async_transaction(conn_id, async {
+            DTraceTransactionManager::begin_transaction(&mut conn).unwrap();
+            std::future::pending::<()>().await;
+        }));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(transaction.as_mut().poll(&mut cx).is_pending());
+        drop(transaction);
+        assert_eq!(
+            recorder.transactions(conn_id),
+            [
+                Recorded::TransactionStart { depth: 0 },
+                Recorded::TransactionDone {
+                    depth: 0,
+                    committed: false,
+                    reason: TransactionDoneReason::Cancelled,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Output of query and transaction timings in the Chrome trace-event format.
+//!
+//! This is meant for local profiling, where the timeline can be opened in
+//! `chrome://tracing` or Perfetto without any other infrastructure. Each query
+//! and transaction is written as a complete ("X") event as soon as it finishes,
+//! and the writer is flushed after each one, so the output remains usable even
+//! if the process exits without calling [`finish_chrome_trace`].
+
+use crate::clock;
+use serde::Serialize;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+#[cfg(feature = "wall-clock")]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+struct Sink {
+    writer: Box<dyn Write + Send>,
+    // The time that timestamps in the trace are relative to.
+    epoch: Instant,
+    // The wall-clock time at `epoch`.
+    #[cfg(feature = "wall-clock")]
+    wall_epoch: SystemTime,
+    // True until the first event is written, which is preceded by the opening
+    // bracket of the array of events.
+    empty: bool,
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+// Whether a sink is installed, to avoid taking the lock for every query when
+// tracing is off.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // A small integer identifying the current thread in the trace.
+    static TID: Cell<u64> = Cell::new(0);
+}
+
+#[derive(Serialize)]
+struct TraceEvent<'a> {
+    name: &'a str,
+    cat: &'a str,
+    ph: &'static str,
+    ts: f64,
+    dur: f64,
+    pid: u32,
+    tid: u64,
+    args: TraceArgs,
+}
+
+#[derive(Serialize)]
+struct TraceArgs {
+    conn_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth: Option<i64>,
+    // The wall-clock time the event started, in milliseconds since the Unix
+    // epoch.
+    #[cfg(feature = "wall-clock")]
+    unix_millis: u64,
+}
+
+/// Write a Chrome trace of all queries and transactions to `writer`.
+///
+/// This finishes the trace being written to the previous writer, if any. Times
+/// in the trace are relative to this call. With the `wall-clock` feature, the
+/// arguments of each event also include the wall-clock time it started, as
+/// `unix_millis`.
+pub fn set_chrome_trace_writer<W: Write + Send + 'static>(writer: W) -> io::Result<()> {
+    let sink = Sink {
+        writer: Box::new(writer),
+        epoch: clock::now(),
+        #[cfg(feature = "wall-clock")]
+        wall_epoch: SystemTime::now(),
+        empty: true,
+    };
+    let previous = SINK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .replace(sink);
+    ENABLED.store(true, Ordering::Relaxed);
+    match previous {
+        Some(previous) => finish(previous),
+        None => Ok(()),
+    }
+}
+
+/// Write a Chrome trace of all queries and transactions to the file at `path`,
+/// replacing it if it exists.
+///
+/// See [`set_chrome_trace_writer`].
+pub fn set_chrome_trace_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let file = File::create(path)?;
+    set_chrome_trace_writer(BufWriter::new(file))
+}
+
+/// Stop writing the Chrome trace, completing and flushing it.
+///
+/// Call this before the process exits, for the output to be a well-formed JSON
+/// document. Trace viewers accept a trace that was never finished, too.
+pub fn finish_chrome_trace() -> io::Result<()> {
+    ENABLED.store(false, Ordering::Relaxed);
+    let sink = SINK.lock().unwrap_or_else(PoisonError::into_inner).take();
+    match sink {
+        Some(sink) => finish(sink),
+        None => Ok(()),
+    }
+}
+
+fn finish(mut sink: Sink) -> io::Result<()> {
+    let close: &[u8] = if sink.empty { b"[]\n" } else { b"\n]\n" };
+    sink.writer.write_all(close)?;
+    sink.writer.flush()
+}
+
+/// Return true if a Chrome trace is being written.
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Write a query to the trace, if one is being written.
+pub(crate) fn record_query(query: &str, started: Instant, duration: Duration, conn_id: Uuid) {
+    record(query, "query", started, duration, conn_id, None);
+}
+
+/// Write a transaction to the trace, if one is being written.
+pub(crate) fn record_transaction(started: Instant, conn_id: Uuid, depth: i64) {
+    let duration = clock::now().saturating_duration_since(started);
+    record(
+        "transaction",
+        "transaction",
+        started,
+        duration,
+        conn_id,
+        Some(depth),
+    );
+}
+
+fn record(
+    name: &str,
+    cat: &str,
+    started: Instant,
+    duration: Duration,
+    conn_id: Uuid,
+    depth: Option<i64>,
+) {
+    let mut sink = SINK.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(sink) = sink.as_mut() else {
+        return;
+    };
+    let since_epoch = started.saturating_duration_since(sink.epoch);
+    let event = TraceEvent {
+        name,
+        cat,
+        ph: "X",
+        ts: micros(since_epoch),
+        dur: micros(duration),
+        pid: std::process::id(),
+        tid: current_tid(),
+        args: TraceArgs {
+            conn_id,
+            depth,
+            // Derived from the monotonic clock, rather than read from the wall
+            // clock, so that it's consistent with `ts` even if the wall clock
+            // is adjusted while tracing.
+            #[cfg(feature = "wall-clock")]
+            unix_millis: unix_millis(sink.wall_epoch + since_epoch),
+        },
+    };
+    let separator: &[u8] = if sink.empty { b"[\n" } else { b",\n" };
+    sink.empty = false;
+    // Errors writing the trace are deliberately ignored, rather than failing
+    // the query.
+    let _ = sink
+        .writer
+        .write_all(separator)
+        .and_then(|_| serde_json::to_writer(&mut sink.writer, &event).map_err(io::Error::from))
+        .and_then(|_| sink.writer.flush());
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+#[cfg(feature = "wall-clock")]
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+fn current_tid() -> u64 {
+    TID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(NEXT_TID.fetch_add(1, Ordering::Relaxed));
+        }
+        tid.get()
+    })
+}
@@ -0,0 +1,105 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The source of time for all duration measurements.
+//!
+//! Everything in the crate that measures elapsed time reads the current time
+//! through [`now()`]. By default that is just [`Instant::now()`]. With the
+//! `test-util` feature, the clock can be replaced with a [`MockClock`], so that
+//! timing-based behavior can be tested deterministically.
+
+use std::time::Instant;
+
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+#[cfg(any(test, feature = "test-util"))]
+use std::time::Duration;
+
+/// Return the current time.
+#[cfg(not(any(test, feature = "test-util")))]
+pub(crate) fn now() -> Instant {
+    Instant::now()
+}
+
+/// Return the current time, from the clock set with [`set_clock`] if any.
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn now() -> Instant {
+    match &*CLOCK.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    }
+}
+
+/// A source of the current time.
+#[cfg(any(test, feature = "test-util"))]
+pub trait Clock: Send + Sync + 'static {
+    /// Return the current time.
+    fn now(&self) -> Instant;
+}
+
+#[cfg(any(test, feature = "test-util"))]
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+/// Replace the clock used for all duration measurements in the crate.
+///
+/// This applies process-wide, including to queries that are already in
+/// progress.
+#[cfg(any(test, feature = "test-util"))]
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap_or_else(PoisonError::into_inner) = Some(clock);
+}
+
+/// Restore the default clock, which reads [`Instant::now()`].
+#[cfg(any(test, feature = "test-util"))]
+pub fn reset_clock() {
+    *CLOCK.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// A [`Clock`] that only moves when told to.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockClock {
+    /// Create a clock, stopped at the current time.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,614 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration of the probes fired by a connection.
+
+#[cfg(feature = "backtrace")]
+use crate::backtrace::{BacktraceCapture, BacktraceOn};
+use crate::batch::BatchLimits;
+use crate::establish::EstablishRetry;
+#[cfg(feature = "tracing")]
+use crate::trace::TracingMode;
+use diesel::result::{DatabaseErrorKind, Error};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::PoisonError;
+use std::sync::RwLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Options controlling how a [`DTraceConnection`](crate::DTraceConnection)
+/// fires its probes.
+///
+/// A configuration can be supplied when establishing a connection with
+/// [`DTraceConnection::establish_with_config`](crate::DTraceConnection::establish_with_config),
+/// or changed later with
+/// [`DTraceConnection::set_config`](crate::DTraceConnection::set_config).
+/// Connections established through [`diesel::Connection::establish`], such as
+/// those created by a connection pool, use the process-wide default set with
+/// [`set_default_config`].
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub(crate) flags: Flags,
+    pub(crate) rewrite_query_text: Option<fn(&str) -> Cow<'_, str>>,
+    pub(crate) query_done_semantics: QueryDoneSemantics,
+    pub(crate) slow_query_threshold: Option<Duration>,
+    pub(crate) large_result_rows: Option<u64>,
+    pub(crate) max_transaction_depth: Option<i64>,
+    pub(crate) max_query_text_len: Option<usize>,
+    pub(crate) slow_query_callback: Option<SlowQueryCallback>,
+    pub(crate) batch: Option<BatchLimits>,
+    pub(crate) role: Role,
+    pub(crate) shard: String,
+    pub(crate) establish_retry: Option<EstablishRetry>,
+    pub(crate) retry_transaction_if: Option<fn(&Error) -> bool>,
+    pub(crate) sample_one_in: u64,
+    #[cfg(feature = "backtrace")]
+    pub(crate) backtraces: Option<BacktraceCapture>,
+    #[cfg(feature = "tracing")]
+    pub(crate) tracing_mode: TracingMode,
+}
+
+impl Config {
+    /// Create a configuration with all options at their defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace literals in the query text with `?` before firing the
+    /// `query-start` probe.
+    ///
+    /// When enabled, numeric and string literals are replaced with `?`, and
+    /// SQL comments (including the bind parameters that diesel appends to the
+    /// rendered query) are removed. Runs of whitespace become a single space,
+    /// and lists of values after `IN` are collapsed to `IN (?)`. This produces
+    /// a canonical query "shape", so that `WHERE id = 1` and `WHERE id = 2`,
+    /// or `IN (1, 2)` and `IN (1, 2, 3)`, can be aggregated together.
+    /// This applies to queries issued via `batch_execute` too.
+    ///
+    /// The default is `false`, which passes the exact query text.
+    pub fn normalize_query_shape(mut self, normalize: bool) -> Self {
+        self.flags.set(Flags::NORMALIZE_QUERY_SHAPE, normalize);
+        self
+    }
+
+    /// Redact the values of the bind parameters from the text of each query
+    /// before it is passed to the probes.
+    ///
+    /// Diesel renders the values bound to a query as a trailing
+    /// `-- binds: [...]` comment, which can include sensitive data. When
+    /// enabled, each value in that comment is replaced with `?`, so that the
+    /// number of bind parameters is still visible, and the rest of the query
+    /// is left as-is, unlike [`Config::normalize_query_shape`], which also
+    /// replaces literals. The values are redacted before any other
+    /// transformation of the text.
+    ///
+    /// The default is `false`.
+    pub fn redact_bind_params(mut self, redact: bool) -> Self {
+        self.flags.set(Flags::REDACT_BIND_PARAMS, redact);
+        self
+    }
+
+    /// Choose whether the text of each query is reported at all.
+    ///
+    /// When disabled, the empty string is reported in place of the text of
+    /// every query, wherever the crate reports it: the `query` argument of
+    /// the probes, the OpenTelemetry spans, observers, and slow-query
+    /// callbacks. The `bytes` and `kind` of the query are still derived from
+    /// its text. This is for connections whose queries mustn't leave the
+    /// process in any form.
+    ///
+    /// The default is `true`.
+    pub fn capture_query_text(mut self, capture: bool) -> Self {
+        self.flags.set(Flags::OMIT_QUERY_TEXT, !capture);
+        self
+    }
+
+    /// Transform the text of each query with `rewrite` before it is passed to
+    /// the probes.
+    ///
+    /// This receives the query as rendered by
+    /// [`RenderQuery`](crate::RenderQuery), including the bind parameters, or
+    /// the statement passed to `batch_execute`. It can be used to mask
+    /// sensitive values, add a prefix, truncate very long queries, or
+    /// normalize queries in some way other than
+    /// [`Config::normalize_query_shape`]. If both are enabled, the query is
+    /// normalized first.
+    ///
+    /// The rewritten text is also what the OpenTelemetry spans, slow-query
+    /// callbacks, and other outputs of the crate report. The `bytes` argument
+    /// of the `query-start` probe is the size of the original text.
+    pub fn rewrite_query_text(mut self, rewrite: fn(&str) -> Cow<'_, str>) -> Self {
+        self.rewrite_query_text = Some(rewrite);
+        self
+    }
+
+    /// Choose when the `query-done` probe fires for queries that return rows.
+    ///
+    /// The default is [`QueryDoneSemantics::OnDispatch`].
+    pub fn query_done_semantics(mut self, semantics: QueryDoneSemantics) -> Self {
+        self.query_done_semantics = semantics;
+        self
+    }
+
+    /// Fire the `query-slow` probe for queries that take longer than
+    /// `threshold`.
+    ///
+    /// The duration is measured up to the point where the `query-done` probe
+    /// fires, see [`Config::query_done_semantics`]. By default there is no
+    /// threshold, and the time of each query is not measured at all.
+    pub fn slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Call `callback` for queries that take longer than the slow-query
+    /// threshold, in addition to firing the `query-slow` probe.
+    ///
+    /// This provides the same signal in-process, e.g., to record a metric or
+    /// capture a backtrace, where DTrace is not available. The callback is
+    /// called synchronously on the thread that completes the query, so it
+    /// should be cheap. A panic in the callback is caught and ignored, rather
+    /// than unwinding through the query.
+    ///
+    /// This has no effect unless a threshold is set with
+    /// [`Config::slow_query_threshold`]. Note that when a callback is
+    /// registered, the text of every query is rendered in advance, in case
+    /// the query turns out to be slow. The event includes the operation name of
+    /// the query, if any, which is a convenient label for it.
+    pub fn on_slow_query<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&SlowQueryEvent<'_>) + Send + Sync + 'static,
+    {
+        self.slow_query_callback = Some(SlowQueryCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Choose whether the callback registered with [`Config::on_slow_query`]
+    /// is called.
+    ///
+    /// This pauses the callback of a connection without unregistering it,
+    /// e.g., for a connection whose configuration is derived from a shared
+    /// one with [`DTraceConnection::set_config`](crate::DTraceConnection::set_config).
+    /// The `query-slow` probe still fires. The default is `true`.
+    pub fn slow_query_callback_enabled(mut self, enabled: bool) -> Self {
+        self.flags
+            .set(Flags::SLOW_QUERY_CALLBACK_DISABLED, !enabled);
+        self
+    }
+
+    /// Fire the `query-start` and `query-done` probes for only one in every
+    /// `one_in` queries on the connection.
+    ///
+    /// This reduces the cost of the probes on connections with high query
+    /// rates, while keeping the per-query detail of those that are sampled,
+    /// unlike [`Config::batch_queries`]. The first query of the connection is
+    /// sampled, and then every `one_in`th after it. The `query-error`,
+    /// `query-slow`, and other probes still fire for every query, and
+    /// observers still see every query. A value of `0` or `1`, the default,
+    /// samples every query.
+    pub fn sample_queries(mut self, one_in: u64) -> Self {
+        self.sample_one_in = one_in;
+        self
+    }
+
+    /// Coalesce the `query-start` and `query-done` probes of consecutive
+    /// queries of the same shape into a single `query-repeated` probe.
+    ///
+    /// When enabled, a query whose fingerprint, the hash of its normalized
+    /// shape, is the same as that of the previous query on the connection
+    /// fires neither probe. Once a query of a different shape starts, or the
+    /// connection is closed, `query-repeated` fires with the fingerprint and
+    /// the number of queries coalesced. This keeps scripts that issue
+    /// thousands of near-identical statements, like seeding a table one
+    /// `INSERT` at a time, from flooding the probe stream. The queries are
+    /// executed exactly as they would be otherwise, and the `query-error`,
+    /// `query-slow`, and other probes still fire for each of them. This is
+    /// disabled by default, since computing the fingerprint of a query built
+    /// without a static `QueryId`, like `sql_query`, renders and hashes its
+    /// text.
+    pub fn coalesce_repeated_queries(mut self, coalesce: bool) -> Self {
+        self.flags.set(Flags::COALESCE_REPEATED_QUERIES, coalesce);
+        self
+    }
+
+    /// Fire the `query-large_result` probe for queries that return more than
+    /// `rows` rows.
+    ///
+    /// The probe fires while the rows are being read, as soon as the cap is
+    /// exceeded, rather than after the whole result has been consumed. This
+    /// makes it useful for catching runaway queries, such as a `SELECT`
+    /// without a `LIMIT` on a huge table. By default there is no cap.
+    pub fn large_result_rows(mut self, rows: u64) -> Self {
+        self.large_result_rows = Some(rows);
+        self
+    }
+
+    /// Fire the `transaction-deep` probe for transactions nested deeper than
+    /// `depth`.
+    ///
+    /// The depth is that of the `transaction-start` probe, so a transaction
+    /// that isn't nested inside another is at depth `0`, and `depth` is the
+    /// deepest level of savepoints expected. Runaway recursion that wraps each
+    /// call in a transaction otherwise only shows up once the database
+    /// rejects a savepoint. By default there is no limit.
+    pub fn max_transaction_depth(mut self, depth: u32) -> Self {
+        self.max_transaction_depth = Some(i64::from(depth));
+        self
+    }
+
+    /// Truncate the query text passed to the probes to `len` bytes.
+    ///
+    /// DTrace copies at most `strsize` bytes of a string argument, 256 by
+    /// default, and silently cuts off the rest, so that a long query can look
+    /// complete in a trace when it isn't. With a maximum set here to match,
+    /// longer text is cut at a character boundary, and ends in
+    /// [`TRUNCATION_MARKER`](crate::TRUNCATION_MARKER), within the `len`
+    /// bytes, and the `query-truncated` probe fires before `query-start`
+    /// with the length of the full text. The text is truncated after any
+    /// other transformation, such as normalizing it. By default there is no
+    /// maximum.
+    pub fn max_query_text_len(mut self, len: usize) -> Self {
+        self.max_query_text_len = Some(len);
+        self
+    }
+
+    /// Panic in debug builds when the text of a query is longer than the
+    /// maximum set with [`Config::max_query_text_len`].
+    ///
+    /// This makes tests fail at the query that is too long to trace, rather
+    /// than leaving it to be noticed in a truncated trace. Release builds
+    /// truncate the text as usual. The default is `false`.
+    pub fn strict_query_text_len(mut self, strict: bool) -> Self {
+        self.flags.set(Flags::STRICT_QUERY_TEXT_LEN, strict);
+        self
+    }
+
+    /// Retry establishing the connection up to `max_attempts` times in all,
+    /// waiting `backoff` before the first retry, and doubling the wait before
+    /// each one after that.
+    ///
+    /// Only failures to reach the database, or being rejected by it, are
+    /// retried, since they may be transient, e.g., while DNS or the database
+    /// itself is starting up. Every attempt fires the
+    /// `connection-establish-start` and `connection-establish-done` probes,
+    /// with the same connection ID, and if the last one fails, the
+    /// `connection-establish-give_up` probe fires too. The waits block the
+    /// calling thread, as establishing the connection itself does, without
+    /// holding a place under the limit set with
+    /// [`set_establish_concurrency_limit`](crate::set_establish_concurrency_limit).
+    /// In an async program, establish connections on a thread where blocking
+    /// is allowed, e.g., with `tokio::task::spawn_blocking`, as
+    /// `async-bb8-diesel` does for its pools. By default, each connection is
+    /// attempted only once.
+    pub fn retry_establish(mut self, max_attempts: u32, backoff: Duration) -> Self {
+        self.establish_retry = Some(EstablishRetry {
+            max_attempts,
+            backoff,
+        });
+        self
+    }
+
+    /// Set which errors `DTraceConnection::transaction_with_retries` retries
+    /// a transaction for.
+    ///
+    /// `retryable` is called with the error of each failed attempt, and the
+    /// transaction is retried if it returns true. By default, only
+    /// serialization failures are retried, which is what the database asks
+    /// for when a transaction conflicts with a concurrent one. Diesel doesn't
+    /// classify other errors worth retrying, such as a PostgreSQL deadlock,
+    /// whose message a custom predicate can check.
+    pub fn retry_transactions_if(mut self, retryable: fn(&Error) -> bool) -> Self {
+        self.retry_transaction_if = Some(retryable);
+        self
+    }
+
+    /// Return true if a transaction that failed with `error` should be
+    /// retried, with the predicate set with
+    /// [`Config::retry_transactions_if`], or the default.
+    pub(crate) fn is_retryable(&self, error: &Error) -> bool {
+        match self.retry_transaction_if {
+            Some(retryable) => retryable(error),
+            None => matches!(
+                error,
+                Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _)
+            ),
+        }
+    }
+
+    /// Set the role of the connection in a replicated topology.
+    ///
+    /// The role is passed to the query and transaction probes, so that a D
+    /// script can, for example, check that writes never go to a replica. The
+    /// default is [`Role::Unknown`].
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Set the shard the connection's database holds, in a sharded topology.
+    ///
+    /// The shard is passed to the query and transaction probes, so that a D
+    /// script can group them by shard, e.g., to find the shard taking the
+    /// most load, without mapping connection IDs to shards itself. It can be
+    /// any identifier the application routes queries by. The default is the
+    /// empty string.
+    pub fn shard(mut self, shard: impl Into<String>) -> Self {
+        self.shard = shard.into();
+        self
+    }
+
+    /// Choose whether each query gets its own ID.
+    ///
+    /// The ID of a query is the first argument to the `query-*` probes, and
+    /// is what lets a D script match the `query-done` probe of a query with
+    /// its `query-start`. Allocating it is a small cost on every query. When
+    /// disabled, all queries on a thread share one ID instead, which is enough
+    /// for scripts that only count or time individual probes, but breaks any
+    /// script that joins probes by query ID. The ID reported by
+    /// [`current_query_context`](crate::current_query_context) is likewise
+    /// shared.
+    ///
+    /// The default is `true`, giving each query a unique ID.
+    pub fn unique_query_ids(mut self, unique: bool) -> Self {
+        self.flags.set(Flags::SHARED_QUERY_IDS, !unique);
+        self
+    }
+
+    /// Pass the text of each query to its `query-done` probe too.
+    ///
+    /// By default, the `query` argument of `query-done` is the empty string,
+    /// and a D script attributes the probe to a query by matching its ID with
+    /// that of a `query-start`. When enabled, it is the same text passed to
+    /// `query-start`, after normalization and rewriting, so that a script
+    /// hooking only `query-done` can attribute it on its own. The text is then
+    /// kept for the duration of each query, even if the probe isn't enabled.
+    ///
+    /// The default is `false`.
+    pub fn query_text_on_done(mut self, enabled: bool) -> Self {
+        self.flags.set(Flags::QUERY_TEXT_ON_DONE, enabled);
+        self
+    }
+
+    /// Measure the time spent rendering the text of each query for the
+    /// `query-start` probe, and report it with the `query-format` probe.
+    ///
+    /// This includes normalizing and rewriting the text, if configured. The
+    /// text is rendered at most once per query, for whichever of the probes,
+    /// observers, and other consumers need it first, and the time to render
+    /// it is only reported when `query-start` is enabled, at the cost of
+    /// reading the clock a few times per query.
+    ///
+    /// The default is `false`.
+    pub fn measure_query_formatting(mut self, enabled: bool) -> Self {
+        self.flags.set(Flags::MEASURE_QUERY_FORMATTING, enabled);
+        self
+    }
+
+    /// Fire the `query-autocommit_write` probe for each `INSERT`, `UPDATE`, or
+    /// `DELETE` statement issued outside a transaction with
+    /// `execute_returning_count`, the method diesel uses for all of them.
+    ///
+    /// These are the writes committed on their own rather than in an explicit
+    /// transaction. While this is enabled, every such statement is rendered
+    /// and classified, whether or not the probe is enabled, reusing the text
+    /// rendered for the other probes if there is one.
+    ///
+    /// The default is `false`.
+    pub fn flag_autocommit_writes(mut self, enabled: bool) -> Self {
+        self.flags.set(Flags::FLAG_AUTOCOMMIT_WRITES, enabled);
+        self
+    }
+
+    /// Coalesce completed queries into batches, firing one `query-batch`
+    /// probe per batch instead of `query-start` and `query-done` per query.
+    ///
+    /// This is for connections with extremely high query rates, where the
+    /// cost of the per-query probes adds up. The queries of a connection are
+    /// accumulated on the thread that completes them, and a batch is emitted
+    /// once it holds `max_queries` queries, or when a query completes at
+    /// least `max_interval` after the first one in the batch. A partial batch
+    /// is emitted when the connection is closed, or the thread completes a
+    /// query on another connection, or exits.
+    ///
+    /// Batching gives up all per-query detail: the text, kind, and operation
+    /// name of each query aren't reported, only how many queries there were
+    /// and how long they took in total. The `query-slow`, `query-error`, and
+    /// `query-large_result` probes still fire for individual queries, but
+    /// without a matching `query-start`. By default, every query fires its
+    /// own probes.
+    pub fn batch_queries(mut self, max_queries: u64, max_interval: Duration) -> Self {
+        self.batch = Some(BatchLimits {
+            max_queries,
+            max_interval,
+        });
+        self
+    }
+
+    /// Capture a backtrace of the code that issued each query that is slow,
+    /// fails, or both, as chosen by `on`.
+    ///
+    /// The backtrace is passed to the callback registered with
+    /// [`Config::on_slow_query`], for slow queries, and to the
+    /// [`Observer`](crate::Observer) in the [`QueryDoneEvent`](crate::QueryDoneEvent)
+    /// of every such query. Capturing a backtrace is expensive, so at most one
+    /// is captured every `min_interval`, across all connections, and other
+    /// queries get none. Backtraces are captured even if `RUST_BACKTRACE`
+    /// isn't set. By default, none are captured.
+    #[cfg(feature = "backtrace")]
+    pub fn capture_backtraces(mut self, on: BacktraceOn, min_interval: Duration) -> Self {
+        self.backtraces = Some(BacktraceCapture { on, min_interval });
+        self
+    }
+
+    /// Choose how queries are reported to `tracing`.
+    ///
+    /// Each query can be a span of its own, covering it from start to
+    /// completion, or a pair of events, one when it starts and one when it
+    /// completes or fails, or both. Spans suit subscribers that build a
+    /// timeline of requests, and events those that produce structured logs.
+    /// Either way, they carry the ID, connection ID, kind, and text of the
+    /// query, and its duration, and the number of rows or the kind of error,
+    /// as fields. The default is [`TracingMode::Spans`].
+    #[cfg(feature = "tracing")]
+    pub fn tracing_mode(mut self, mode: TracingMode) -> Self {
+        self.tracing_mode = mode;
+        self
+    }
+
+    /// Return this configuration, overridden to report every query in full
+    /// detail, see
+    /// [`DTraceConnection::set_detailed_tracing`](crate::DTraceConnection::set_detailed_tracing).
+    pub(crate) fn detailed(&self) -> Self {
+        let mut config = self.clone();
+        config.flags.set(Flags::NORMALIZE_QUERY_SHAPE, false);
+        config.flags.set(Flags::REDACT_BIND_PARAMS, false);
+        config.flags.set(Flags::OMIT_QUERY_TEXT, false);
+        config.flags.set(Flags::QUERY_TEXT_ON_DONE, true);
+        config.rewrite_query_text = None;
+        config.sample_one_in = 1;
+        #[cfg(feature = "backtrace")]
+        {
+            config.backtraces = Some(BacktraceCapture {
+                on: BacktraceOn::SlowOrError,
+                min_interval: Duration::ZERO,
+            });
+        }
+        config
+    }
+}
+
+/// The options of a [`Config`] that are either on or off, packed into a single
+/// word.
+///
+/// Each flag is named so that it is off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Flags(u16);
+
+impl Flags {
+    pub(crate) const NORMALIZE_QUERY_SHAPE: Self = Self(1 << 0);
+    pub(crate) const REDACT_BIND_PARAMS: Self = Self(1 << 1);
+    pub(crate) const OMIT_QUERY_TEXT: Self = Self(1 << 2);
+    pub(crate) const SLOW_QUERY_CALLBACK_DISABLED: Self = Self(1 << 3);
+    pub(crate) const SHARED_QUERY_IDS: Self = Self(1 << 4);
+    pub(crate) const QUERY_TEXT_ON_DONE: Self = Self(1 << 5);
+    pub(crate) const MEASURE_QUERY_FORMATTING: Self = Self(1 << 6);
+    pub(crate) const COALESCE_REPEATED_QUERIES: Self = Self(1 << 7);
+    pub(crate) const FLAG_AUTOCOMMIT_WRITES: Self = Self(1 << 8);
+    pub(crate) const STRICT_QUERY_TEXT_LEN: Self = Self(1 << 9);
+
+    /// Return true if all of the flags in `other` are set.
+    pub(crate) fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn set(&mut self, other: Self, enabled: bool) {
+        if enabled {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+}
+
+/// A query that took longer than the slow-query threshold, passed to the
+/// callback registered with [`Config::on_slow_query`].
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct SlowQueryEvent<'a> {
+    /// The text of the query, as passed to the `query-start` probe.
+    pub query: &'a str,
+    /// How long the query took.
+    pub duration: Duration,
+    /// The ID of the connection that issued the query.
+    pub conn_id: Uuid,
+    /// The operation name the query was issued under, see
+    /// [`DTraceConnection::with_op_name`](crate::DTraceConnection::with_op_name).
+    ///
+    /// This is the empty string if there is none.
+    pub op_name: &'a str,
+    /// A backtrace of the code that issued the query, if one was captured,
+    /// see [`Config::capture_backtraces`].
+    #[cfg(feature = "backtrace")]
+    pub backtrace: Option<&'a Backtrace>,
+}
+
+#[derive(Clone)]
+pub(crate) struct SlowQueryCallback(pub(crate) Arc<dyn Fn(&SlowQueryEvent<'_>) + Send + Sync>);
+
+impl fmt::Debug for SlowQueryCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SlowQueryCallback")
+    }
+}
+
+/// When the `query-done` probe fires for a query that returns rows.
+///
+/// This only affects queries issued through
+/// [`LoadConnection::load`](diesel::connection::LoadConnection::load). The
+/// probe for statements issued with `execute_returning_count` or
+/// `batch_execute` always fires as soon as the inner connection returns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryDoneSemantics {
+    /// Fire the probe as soon as the inner connection returns the cursor.
+    ///
+    /// For most backends, this is when the server has finished executing the
+    /// query.
+    #[default]
+    OnDispatch,
+    /// Fire the probe once all rows have been consumed from the cursor, or the
+    /// cursor is dropped, whichever comes first.
+    ///
+    /// This includes the time the application spends processing the rows.
+    OnCursorDrain,
+}
+
+/// The role of a connection's database in a primary/replica topology.
+///
+/// This is set with [`Config::role`], and passed to the probes as a `u8`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Role {
+    /// The database accepts writes.
+    Primary = 0,
+    /// The database is a read-only replica.
+    Replica = 1,
+    /// The role hasn't been configured.
+    #[default]
+    Unknown = 2,
+}
+
+static DEFAULT_CONFIG: RwLock<Option<Config>> = RwLock::new(None);
+
+/// Set the configuration used by connections established through
+/// [`diesel::Connection::establish`].
+///
+/// This only affects connections established after the call.
+pub fn set_default_config(config: Config) {
+    *DEFAULT_CONFIG
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(config);
+}
+
+/// Return the current process-wide default configuration.
+pub(crate) fn default_config() -> Config {
+    DEFAULT_CONFIG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_default()
+}
@@ -0,0 +1,137 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of the query currently in progress, for correlating application
+//! logs with the probes, and of the correlation ID of the current unit of work.
+
+use std::cell::Cell;
+use uuid::Uuid;
+
+#[cfg(feature = "async")]
+use std::future::Future;
+
+/// Identifies a query, as reported to the probes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryContext {
+    /// The ID of the query, which is the first argument to the `query-*`
+    /// probes.
+    pub id: u64,
+    /// The ID of the connection issuing the query.
+    pub conn_id: Uuid,
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<QueryContext>> = Cell::new(None);
+    static CORRELATION_ID: Cell<Uuid> = Cell::new(Uuid::nil());
+}
+
+#[cfg(feature = "async")]
+tokio::task_local! {
+    static TASK_CURRENT: Cell<Option<QueryContext>>;
+    static TASK_CORRELATION_ID: Uuid;
+}
+
+/// Return the query in progress, if any.
+///
+/// On the current thread, this is the query that has fired its `query-start`
+/// probe and not yet its `query-done` probe. Inside a task run with
+/// [`with_query_context`], this is instead the query most recently started by
+/// that task, which remains available after the query completes, and across
+/// `.await` points and the task moving between threads.
+pub fn current_query_context() -> Option<QueryContext> {
+    #[cfg(feature = "async")]
+    if let Ok(Some(context)) = TASK_CURRENT.try_with(Cell::get) {
+        return Some(context);
+    }
+    CURRENT.with(Cell::get)
+}
+
+/// Run `future`, tracking the queries that it starts, so that
+/// [`current_query_context`] returns the most recent one anywhere within it.
+///
+/// A thread-local query context doesn't work well in asynchronous code: the
+/// query has usually completed by the time the task that issued it logs
+/// anything, and the task may since have moved to another thread. Note that
+/// queries issued from another task, including a blocking task used to run the
+/// query on behalf of this one, are not visible to this task.
+#[cfg(feature = "async")]
+pub async fn with_query_context<F: Future>(future: F) -> F::Output {
+    TASK_CURRENT.scope(Cell::new(None), future).await
+}
+
+/// Run `f` with `id` as the correlation ID of every query and transaction it
+/// issues, on any connection.
+///
+/// The correlation ID is passed to the `query-start`, `transaction-start`, and
+/// `transaction-done` probes, so that queries issued on several connections on
+/// behalf of one logical unit of work, such as an HTTP request, can be
+/// aggregated together. Outside any such scope, the probes are passed
+/// `Uuid::nil()`. Calls can be nested, in which case the innermost ID applies,
+/// and the enclosing one is restored when `f` returns.
+///
+/// The ID is stored in a thread-local, and applies only to queries issued on
+/// this thread. See [`with_task_correlation_id`] for asynchronous code.
+pub fn with_correlation_id<R>(id: Uuid, f: impl FnOnce() -> R) -> R {
+    // Restore the enclosing ID even if `f` panics.
+    struct Restore(Uuid);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CORRELATION_ID.with(|current| current.set(self.0));
+        }
+    }
+    let _restore = Restore(CORRELATION_ID.with(|current| current.replace(id)));
+    f()
+}
+
+/// Run `future` with `id` as the correlation ID of every query and transaction
+/// it issues, on any connection.
+///
+/// This is the asynchronous equivalent of [`with_correlation_id`], and takes
+/// precedence over it. The ID follows the task across `.await` points and
+/// threads, but isn't visible to other tasks, including a blocking task used
+/// to run queries on behalf of this one. Such a task can be given the same ID
+/// by capturing [`current_correlation_id`] and passing it to
+/// [`with_correlation_id`] within the blocking task.
+#[cfg(feature = "async")]
+pub async fn with_task_correlation_id<F: Future>(id: Uuid, future: F) -> F::Output {
+    TASK_CORRELATION_ID.scope(id, future).await
+}
+
+/// Return the correlation ID in effect, or `Uuid::nil()` if there is none.
+pub fn current_correlation_id() -> Uuid {
+    #[cfg(feature = "async")]
+    if let Ok(id) = TASK_CORRELATION_ID.try_with(|id| *id) {
+        return id;
+    }
+    CORRELATION_ID.with(Cell::get)
+}
+
+/// Record the start of a query on this thread and task.
+pub(crate) fn enter(context: QueryContext) {
+    CURRENT.with(|current| current.set(Some(context)));
+    #[cfg(feature = "async")]
+    let _ = TASK_CURRENT.try_with(|current| current.set(Some(context)));
+}
+
+/// Record that the query with ID `id` has completed on this thread.
+///
+/// This leaves any other query current, which happens when a second query is
+/// issued while the rows of the first are still being read.
+pub(crate) fn exit(id: u64) {
+    CURRENT.with(|current| {
+        if current.get().is_some_and(|context| context.id == id) {
+            current.set(None);
+        }
+    });
+}
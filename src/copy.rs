@@ -0,0 +1,137 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reporting the progress of PostgreSQL `COPY` streams.
+
+use crate::clock;
+use crate::probes;
+use std::io::{self, BufRead, Read, Write};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The data of a PostgreSQL `COPY`, which reports its progress as it flows.
+///
+/// This implements [`Read`], [`BufRead`], or [`Write`] when the stream it
+/// wraps does.
+#[derive(Debug)]
+pub struct CopyProgress<T> {
+    inner: T,
+    conn_id: Uuid,
+    interval: Duration,
+    last_fired: Instant,
+    rows: u64,
+    bytes: u64,
+}
+
+impl<T> CopyProgress<T> {
+    /// Wrap the data of a `COPY` on the connection `conn_id`, to report its
+    /// progress.
+    ///
+    /// `stream` is either the writer passed to the callback of diesel's
+    /// `copy_from(...).from_raw_data(...)`, or the reader returned by
+    /// `copy_to(...).load_raw(...)`. Diesel only implements `COPY` for
+    /// `PgConnection` itself, so the statement is issued on the inner
+    /// connection of a [`DTraceConnection`](crate::DTraceConnection), e.g.,
+    /// `&mut *conn`, and its ID is passed here.
+    ///
+    /// The `copy-progress` probe fires at most once every `interval` as data
+    /// flows through the stream, and once more when it is dropped, with the
+    /// rows and bytes transferred so far. Rows are counted as lines, which is
+    /// exact for the text and CSV formats, unless a CSV value contains a
+    /// newline, and meaningless for the binary format.
+    pub fn new(conn_id: Uuid, stream: T, interval: Duration) -> Self {
+        Self {
+            inner: stream,
+            conn_id,
+            interval,
+            last_fired: clock::now(),
+            rows: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Return the rows and bytes transferred so far.
+    pub fn progress(&self) -> (u64, u64) {
+        (self.rows, self.bytes)
+    }
+
+    /// Account for `data`, which just flowed through the stream.
+    fn record(&mut self, data: &[u8]) {
+        let rows = lines(data);
+        self.advance(rows, data.len());
+    }
+
+    /// Account for `rows` rows and `bytes` bytes, which just flowed through
+    /// the stream.
+    fn advance(&mut self, rows: u64, bytes: usize) {
+        self.rows += rows;
+        self.bytes += bytes as u64;
+        let now = clock::now();
+        if now.saturating_duration_since(self.last_fired) >= self.interval {
+            self.last_fired = now;
+            self.fire();
+        }
+    }
+
+    fn fire(&self) {
+        probes::copy__progress!(|| (self.conn_id, self.rows, self.bytes));
+    }
+}
+
+impl<T: Read> Read for CopyProgress<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: BufRead> BufRead for CopyProgress<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // Only the data the caller consumes has been transferred.
+        if let Ok(data) = self.inner.fill_buf() {
+            let data = &data[..amt.min(data.len())];
+            let (rows, bytes) = (lines(data), data.len());
+            self.advance(rows, bytes);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+impl<T: Write> Write for CopyProgress<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Return the number of lines ending in `data`.
+fn lines(data: &[u8]) -> u64 {
+    data.iter().filter(|&&b| b == b'\n').count() as u64
+}
+
+impl<T> Drop for CopyProgress<T> {
+    fn drop(&mut self) {
+        self.fire();
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cursor wrapper that fires probes as the results of a query are consumed.
+
+use crate::probes;
+use crate::query::PendingQuery;
+use diesel::result::QueryResult;
+use usdt::UniqueId;
+use uuid::Uuid;
+
+/// The cursor returned by [`DTraceConnection`](crate::DTraceConnection)'s
+/// implementation of [`LoadConnection`](diesel::connection::LoadConnection).
+///
+/// This yields exactly the rows of the inner connection's cursor. When the
+/// connection is configured with
+/// [`QueryDoneSemantics::OnCursorDrain`](crate::QueryDoneSemantics::OnCursorDrain),
+/// the `query-done` probe fires when this cursor is exhausted, or when it is
+/// dropped, whichever comes first.
+///
+/// When the connection is configured with a
+/// [`large_result_rows`](crate::Config::large_result_rows) cap, this also fires
+/// the `query-large_result` probe as soon as the number of rows read from the
+/// cursor exceeds that cap.
+///
+/// If the cursor is exhausted without yielding any row, it fires the
+/// `query-empty` probe. If it yields an error instead of a row, the query
+/// fails with that error, firing `query-error`, and `query-done` if it hasn't
+/// fired yet, as a failure.
+///
+/// This is also the cursor of a bare
+/// [`MaybeInstrumented`](crate::MaybeInstrumented) connection, in which case it
+/// fires no probes.
+pub struct DTraceCursor<I> {
+    inner: I,
+    conn_id: Uuid,
+    // The ID of the query, until the cursor is exhausted.
+    query_id: Option<UniqueId>,
+    // The query, if its `query-done` probe has not yet fired.
+    pending: Option<PendingQuery>,
+    // The number of rows yielded so far, and the cap above which we fire the
+    // `query-large_result` probe, if any. The cap is cleared once it fires.
+    rows: u64,
+    large_result_rows: Option<u64>,
+}
+
+impl<I> DTraceCursor<I> {
+    /// Wrap a cursor.
+    ///
+    /// If `pending` is `Some(_)`, the `query-done` probe fires when the cursor
+    /// is drained. Otherwise it must already have fired.
+    pub(crate) fn new(
+        inner: I,
+        conn_id: Uuid,
+        query_id: UniqueId,
+        pending: Option<PendingQuery>,
+        large_result_rows: Option<u64>,
+    ) -> Self {
+        Self {
+            inner,
+            conn_id,
+            query_id: Some(query_id),
+            pending,
+            rows: 0,
+            large_result_rows,
+        }
+    }
+
+    /// Wrap the cursor of a connection that isn't instrumented, which fires
+    /// no probes.
+    pub(crate) fn bare(inner: I) -> Self {
+        Self {
+            inner,
+            conn_id: Uuid::nil(),
+            query_id: None,
+            pending: None,
+            rows: 0,
+            large_result_rows: None,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(query) = self.pending.take() {
+            query.finish_cursor(self.rows);
+        }
+    }
+}
+
+impl<I, R> Iterator for DTraceCursor<I>
+where
+    I: Iterator<Item = QueryResult<R>>,
+{
+    type Item = QueryResult<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.inner.next();
+        match &item {
+            Some(Err(error)) => {
+                self.query_id = None;
+                if let Some(query) = self.pending.take() {
+                    query.fail_cursor(error);
+                }
+            }
+            Some(Ok(_)) => {
+                self.rows += 1;
+                if self.large_result_rows.is_some_and(|cap| self.rows > cap) {
+                    self.large_result_rows = None;
+                    probes::query__large_result!(|| (self.conn_id, self.rows));
+                }
+            }
+            None => {
+                self.finish();
+                if let Some(id) = self.query_id.take().filter(|_| self.rows == 0) {
+                    probes::query__empty!(|| (&id, self.conn_id));
+                }
+            }
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<I> Drop for DTraceCursor<I> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
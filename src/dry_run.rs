@@ -0,0 +1,238 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Computing the arguments of the probes without issuing any query.
+//!
+//! The arguments are built by the same code that builds them for the probes,
+//! so a dry run reports exactly what the probes would.
+
+use crate::establish;
+use crate::query::{QueryStart, RenderQuery};
+use crate::DTraceConnection;
+use crate::DTraceTransactionManager;
+use crate::QueryKind;
+use crate::QueryMethod;
+use crate::Role;
+use diesel::connection::{AnsiTransactionManager, Connection};
+use diesel::query_builder::{QueryFragment, QueryId};
+use std::any::TypeId;
+use std::borrow::Cow;
+use uuid::Uuid;
+
+/// The arguments the `query-start` probe fires with for a query, returned by
+/// [`DTraceConnection::query_start_args`].
+///
+/// The query ID is left out, since each query gets a new one. The fields of
+/// the `info` argument are returned individually, as typed values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryStartArgs {
+    /// The ID of the connection.
+    pub conn_id: Uuid,
+    /// The text of the query, after the transformations set in the
+    /// connection's [`Config`](crate::Config).
+    pub query: String,
+    /// Whether the query would be issued inside an open transaction.
+    pub in_transaction: bool,
+    /// The kind of statement.
+    pub kind: QueryKind,
+    /// The length of the rendered query, in bytes.
+    pub bytes: u64,
+    /// The length of the rendered query, in characters.
+    pub char_count: u64,
+    /// The operation name, or the empty string.
+    pub op_name: String,
+    /// The correlation ID of the current scope.
+    pub correlation_id: Uuid,
+    /// The role of the connection's database.
+    pub role: Role,
+    /// The shard of the connection's database.
+    pub shard: String,
+    /// The key-value context of the connection, serialized.
+    pub context: String,
+    /// The fingerprint of the query.
+    pub fingerprint: u64,
+    /// The diesel method the query would be issued with.
+    pub method: QueryMethod,
+}
+
+/// The arguments the `connection-establish-start` probe fires with, returned
+/// by [`DTraceConnection::establish_start_args`].
+///
+/// The IDs of the attempt and the connection are left out, since each attempt
+/// gets new ones, as is the number of connections being established at the
+/// time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EstablishStartArgs {
+    /// The URL of the database, with any password replaced by `***`.
+    pub url: String,
+    /// The build version of the application, see
+    /// [`set_build_version`](crate::set_build_version).
+    pub build_version: String,
+}
+
+impl<C> DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    /// Return the arguments the `query-start` probe would fire with if
+    /// `query` were issued now with `method`, without issuing it.
+    ///
+    /// This renders, transforms, and classifies the query exactly as issuing
+    /// it does, so that the values the probes report can be checked without
+    /// DTrace or a database, e.g., over a mock connection. No probe fires,
+    /// and the connection's query count is unchanged.
+    pub fn query_start_args<T>(&mut self, query: &T, method: QueryMethod) -> QueryStartArgs
+    where
+        T: QueryFragment<C::Backend> + QueryId,
+    {
+        let text = <C::Backend as RenderQuery>::render(query);
+        let shape_key = <C::Backend as RenderQuery>::shape_key(query);
+        self.dry_run(Cow::Owned(text), shape_key, method)
+    }
+
+    /// Return the arguments the `query-start` probe would fire with if
+    /// `query` were issued now with [`SimpleConnection::batch_execute`],
+    /// without issuing it.
+    ///
+    /// [`SimpleConnection::batch_execute`]: diesel::connection::SimpleConnection::batch_execute
+    pub fn batch_execute_start_args(&mut self, query: &str) -> QueryStartArgs {
+        self.dry_run(Cow::Borrowed(query), None, QueryMethod::BatchExecute)
+    }
+
+    /// Return the arguments the `connection-establish-start` probe fires
+    /// with when a connection is established with `database_url`, without
+    /// establishing one.
+    pub fn establish_start_args(database_url: &str) -> EstablishStartArgs {
+        EstablishStartArgs {
+            url: establish::probe_url(database_url).into_owned(),
+            build_version: establish::build_version(),
+        }
+    }
+
+    fn dry_run(
+        &mut self,
+        text: Cow<'_, str>,
+        shape_key: Option<TypeId>,
+        method: QueryMethod,
+    ) -> QueryStartArgs {
+        let in_transaction = DTraceTransactionManager::<C>::depth(self) > 0;
+        let QueryStart {
+            query,
+            in_transaction,
+            kind,
+            info,
+        } = self.query_start(text, shape_key, method, in_transaction, |text| {
+            self.query_text(text)
+        });
+        QueryStartArgs {
+            conn_id: self.id,
+            query: query.into_owned(),
+            in_transaction,
+            kind,
+            bytes: info.bytes,
+            char_count: info.char_count,
+            op_name: info.op_name,
+            correlation_id: info.correlation_id,
+            role: self.config.role,
+            shard: info.shard,
+            context: info.context,
+            fingerprint: info.fingerprint,
+            method,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{real_clock, MockConnection, Recorder};
+    use crate::{Config, TRUNCATION_MARKER};
+    use diesel::connection::SimpleConnection;
+    use diesel::pg::Pg;
+    use diesel::sql_types::Text;
+    use diesel::RunQueryDsl;
+
+    fn connection(config: Config) -> DTraceConnection<MockConnection> {
+        DTraceConnection::establish_with_config("", config).unwrap()
+    }
+
+    fn update() -> impl QueryFragment<Pg> + QueryId {
+        diesel::sql_query("UPDATE users SET name = $1 WHERE id = 7").bind::<Text, _>("hunter2")
+    }
+
+    #[test]
+    fn query_start_args_are_those_of_the_query() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection(Config::new().redact_bind_params(true));
+        let args = conn.query_start_args(&update(), QueryMethod::Execute);
+        assert_eq!(conn.query_count(), 0);
+        update().execute(&mut conn).unwrap();
+        assert_eq!(
+            recorder.queries(conn.id()).last(),
+            Some(&(args.query.clone(), args.kind, args.method)),
+        );
+        assert_eq!(
+            args.query,
+            "UPDATE users SET name = $1 WHERE id = 7 -- binds: [?]"
+        );
+        assert_eq!(args.kind, QueryKind::Update);
+        assert!(!args.in_transaction);
+        assert_eq!(args.conn_id, conn.id());
+    }
+
+    #[test]
+    fn query_start_args_are_truncated_but_measure_the_whole_query() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection(Config::new().max_query_text_len(20));
+        let args = conn.query_start_args(&update(), QueryMethod::Execute);
+        update().execute(&mut conn).unwrap();
+        assert_eq!(
+            recorder.queries(conn.id()).last(),
+            Some(&(args.query.clone(), args.kind, args.method)),
+        );
+        assert_eq!(args.query, format!("UPDATE users SET {TRUNCATION_MARKER}"));
+        let rendered = <Pg as RenderQuery>::render(&update());
+        assert_eq!(args.bytes, rendered.len() as u64);
+        assert_eq!(args.kind, QueryKind::Update);
+    }
+
+    #[test]
+    fn batch_execute_start_args_are_those_of_the_batch() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection(Config::new());
+        conn.begin_test_transaction().unwrap();
+        let args = conn.batch_execute_start_args("DELETE FROM t; SELECT 1");
+        conn.batch_execute("DELETE FROM t; SELECT 1").unwrap();
+        assert_eq!(
+            recorder.queries(conn.id()).last(),
+            Some(&(args.query.clone(), args.kind, args.method)),
+        );
+        assert_eq!(args.kind, QueryKind::Delete);
+        assert_eq!(args.method, QueryMethod::BatchExecute);
+        assert!(args.in_transaction);
+        assert_eq!(args.bytes, 23);
+    }
+
+    #[test]
+    fn establish_start_args_redact_the_password() {
+        let args = DTraceConnection::<MockConnection>::establish_start_args(
+            "postgres://app:hunter2@db/app",
+        );
+        assert_eq!(args.url, "postgres://app:***@db/app");
+    }
+}
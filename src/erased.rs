@@ -0,0 +1,117 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An object-safe interface to a [`DTraceConnection`].
+
+use crate::DTraceConnection;
+use crate::RenderQuery;
+use diesel::backend::Backend;
+use diesel::connection::{AnsiTransactionManager, Connection, SimpleConnection};
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::result::QueryResult;
+use std::any::Any;
+use uuid::Uuid;
+
+/// The operations of a [`DTraceConnection`] available through a trait object.
+///
+/// Diesel's [`Connection`] can't be used as a trait object, since its methods
+/// are generic over the query. Frameworks that can't be generic over the
+/// connection type can store a `Box<dyn DynDTraceConnection<Pg>>` instead,
+/// which still fires every probe of the connection it holds, but only offers
+/// this subset of its operations:
+///
+/// - statements whose results aren't read, built with the query builder or
+///   [`diesel::sql_query`], with [`execute`](Self::execute), and raw SQL with
+///   [`batch_execute`](Self::batch_execute);
+/// - transactions, with [`transaction`](Self::transaction);
+/// - the connection's ID and query count.
+///
+/// Queries returning rows can't be issued through the trait object, since
+/// their row types are generic. For those, and anything else, a boxed
+/// connection can be downcast back to its concrete type with
+/// [`as_any_mut`](Self::as_any_mut).
+pub trait DynDTraceConnection<DB: Backend> {
+    /// Return the ID of the connection, as passed to the probes.
+    fn id(&self) -> Uuid;
+
+    /// Return the number of queries issued on the connection so far.
+    fn query_count(&self) -> u64;
+
+    /// Execute a statement, returning the number of rows it affected, as
+    /// [`Connection::execute_returning_count`] does.
+    fn execute(&mut self, query: &dyn QueryFragment<DB>) -> QueryResult<usize>;
+
+    /// Execute one or more SQL statements, as
+    /// [`SimpleConnection::batch_execute`] does.
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()>;
+
+    /// Run `f` in a transaction, as [`Connection::transaction`] does,
+    /// committing it if `f` returns `Ok`, and rolling it back otherwise.
+    fn transaction(
+        &mut self,
+        f: &mut dyn FnMut(&mut dyn DynDTraceConnection<DB>) -> QueryResult<()>,
+    ) -> QueryResult<()>;
+
+    /// Return the connection as [`Any`], to downcast it to its concrete type,
+    /// e.g., `DTraceConnection<PgConnection>`.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<C> DynDTraceConnection<C::Backend> for DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager> + 'static,
+    C::Backend: RenderQuery,
+{
+    fn id(&self) -> Uuid {
+        DTraceConnection::id(self)
+    }
+
+    fn query_count(&self) -> u64 {
+        DTraceConnection::query_count(self)
+    }
+
+    fn execute(&mut self, query: &dyn QueryFragment<C::Backend>) -> QueryResult<usize> {
+        self.execute_returning_count(&Erased(query))
+    }
+
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        SimpleConnection::batch_execute(self, query)
+    }
+
+    fn transaction(
+        &mut self,
+        f: &mut dyn FnMut(&mut dyn DynDTraceConnection<C::Backend>) -> QueryResult<()>,
+    ) -> QueryResult<()> {
+        Connection::transaction(self, |conn| f(conn))
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A query whose type has been erased, which therefore has no static
+/// `QueryId`.
+struct Erased<'a, DB>(&'a dyn QueryFragment<DB>);
+
+impl<DB: Backend> QueryFragment<DB> for Erased<'_, DB> {
+    fn walk_ast<'b>(&'b self, pass: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        self.0.walk_ast(pass)
+    }
+}
+
+impl<DB> QueryId for Erased<'_, DB> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
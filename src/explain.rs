@@ -0,0 +1,311 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fetching the plans of slow PostgreSQL queries.
+
+use crate::probes;
+use crate::query::{self, duration_nanos};
+use crate::DTraceConnection;
+use crate::QueryKind;
+use diesel::connection::{AnsiTransactionManager, Connection};
+use diesel::pg::PgConnection;
+use diesel::result::QueryResult;
+use diesel::sql_types::Text;
+use diesel::{QueryableByName, RunQueryDsl};
+use std::time::Duration;
+
+/// A function that fetches the plan of a statement on a connection.
+pub(crate) type ExplainFn<C> = fn(&mut C, &str) -> QueryResult<String>;
+
+impl DTraceConnection<PgConnection> {
+    /// Enable or disable fetching the plans of slow queries.
+    ///
+    /// When enabled, each `SELECT` statement that takes longer than the
+    /// connection's [`slow_query_threshold`](crate::Config::slow_query_threshold)
+    /// is followed by an `EXPLAIN` of the same statement, and its plan is
+    /// passed to the `query-plan` probe. This is disabled by default, since it
+    /// issues an extra query for every slow one.
+    ///
+    /// The `EXPLAIN` is issued directly on the inner connection, so it fires
+    /// no probes of its own, and is never itself explained. It only plans the
+    /// statement, without running it again. Statements with bind parameters
+    /// are planned with `EXPLAIN (GENERIC_PLAN)`, which requires PostgreSQL 16
+    /// or later; on older servers, and whenever the `EXPLAIN` fails for any
+    /// other reason, the probe doesn't fire.
+    ///
+    /// The plan of a slow `batch_execute` or `execute_returning_count` is
+    /// fetched as soon as it completes. The results of a `load` are still
+    /// borrowing the connection at that point, so its plan is fetched just
+    /// before the next query on the connection instead. Loads are only
+    /// explained with the default
+    /// [`QueryDoneSemantics::OnDispatch`](crate::QueryDoneSemantics::OnDispatch),
+    /// since otherwise their duration isn't known until the cursor has
+    /// already been handed to the caller. A load consumes its query, which
+    /// therefore has to be rendered before it is known to be slow, so while
+    /// this is enabled, every load on a connection with a slow query
+    /// threshold renders its text once more.
+    ///
+    /// Connections from a pool can be configured as they are acquired, e.g.,
+    /// with r2d2's `CustomizeConnection::on_acquire`.
+    pub fn explain_slow_queries(&mut self, enabled: bool) {
+        self.explain = enabled.then_some(explain as ExplainFn<PgConnection>);
+        self.analyze = false;
+        if !enabled {
+            self.pending_explain = None;
+        }
+    }
+
+    /// Enable or disable analyzing slow queries, to estimate whether they
+    /// spent their time waiting on a lock.
+    ///
+    /// This is like [`DTraceConnection::explain_slow_queries`], except that
+    /// the slow statement is run again under `EXPLAIN (ANALYZE, BUFFERS)`, so
+    /// that the plan passed to the `query-plan` probe includes actual
+    /// timings. The `query-lock_wait` probe then fires with the duration of
+    /// the original query and the execution time the server reports for the
+    /// re-run. When the query was much slower the first time, it most likely
+    /// spent that time waiting, typically on a lock that was released since.
+    /// This is only a heuristic: it also catches, for example, a query that
+    /// waited on I/O the first time, and found its data cached the second.
+    ///
+    /// Since this executes the statement a second time, only statements
+    /// without bind parameters, whose values a re-run would need, and without
+    /// a locking clause like `FOR UPDATE`, are analyzed. Others are only
+    /// planned, as with `explain_slow_queries`. Note that a `SELECT` calling
+    /// a function with side effects, like `nextval`, has those effects again.
+    pub fn analyze_slow_queries(&mut self, enabled: bool) {
+        self.explain_slow_queries(enabled);
+        self.analyze = enabled;
+    }
+}
+
+impl<C> DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    /// Fetch the plan of `sql`, which just took `elapsed`, longer than the
+    /// slow-query threshold, and fire the `query-plan` probe with it.
+    ///
+    /// This does nothing unless fetching plans is enabled, and `sql` is a
+    /// `SELECT` statement.
+    pub(crate) fn fetch_plan(&mut self, sql: &str, elapsed: Duration) {
+        let Some(explain) = self.explain else {
+            return;
+        };
+        let Some((statement, analyzed)) = statement(sql, self.analyze) else {
+            return;
+        };
+        let Ok(plan) = explain(&mut self.inner, &statement) else {
+            return;
+        };
+        probes::query__plan!(|| (self.id, &plan));
+        if let Some(execution) = analyzed.then(|| execution_time(&plan)).flatten() {
+            probes::query__lock_wait!(|| (
+                self.id,
+                duration_nanos(elapsed),
+                duration_nanos(execution),
+                u8::from(elapsed > execution.saturating_mul(2))
+            ));
+        }
+    }
+
+    /// Fetch the plan of the last slow `load`, if it hasn't been yet.
+    pub(crate) fn fetch_pending_plan(&mut self) {
+        if let Some((sql, elapsed)) = self.pending_explain.take() {
+            self.fetch_plan(&sql, elapsed);
+        }
+    }
+}
+
+/// Build the `EXPLAIN` statement for the rendered query `sql`, or return `None`
+/// if it isn't a `SELECT` statement.
+///
+/// If `analyze` is true, the statement runs the query under `EXPLAIN ANALYZE`
+/// where that's safe, and the returned flag is true if so.
+fn statement(sql: &str, analyze: bool) -> Option<(String, bool)> {
+    if query::classify(sql) != QueryKind::Select {
+        return None;
+    }
+    // `debug_query` renders the bind parameters as a trailing comment, which
+    // the statement being planned mustn't include. Their values aren't needed
+    // by a generic plan.
+    let (sql, has_binds) = match sql.rsplit_once(" -- binds: ") {
+        Some((sql, binds)) => (sql, binds.trim() != "[]"),
+        None => (sql, false),
+    };
+    if has_binds {
+        Some((format!("EXPLAIN (GENERIC_PLAN) {sql}"), false))
+    } else if analyze && !has_locking_clause(sql) {
+        Some((format!("EXPLAIN (ANALYZE, BUFFERS) {sql}"), true))
+    } else {
+        Some((format!("EXPLAIN {sql}"), false))
+    }
+}
+
+/// Return true if `sql` might contain a locking clause, like `FOR UPDATE`,
+/// which would take row locks if the statement were run again.
+///
+/// This errs on the side of caution, matching the keywords anywhere in the
+/// statement, including inside literals.
+fn has_locking_clause(sql: &str) -> bool {
+    let words: Vec<_> = sql
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .collect();
+    words.windows(2).any(|pair| {
+        pair[0].eq_ignore_ascii_case("FOR")
+            && ["UPDATE", "SHARE", "NO", "KEY"]
+                .iter()
+                .any(|word| pair[1].eq_ignore_ascii_case(word))
+    })
+}
+
+/// Parse the execution time from the output of `EXPLAIN ANALYZE`, which ends
+/// with a line like `Execution Time: 0.123 ms`.
+fn execution_time(plan: &str) -> Option<Duration> {
+    let millis = plan
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix("Execution Time:"))?
+        .trim()
+        .strip_suffix("ms")?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    Duration::try_from_secs_f64(millis / 1000.0).ok()
+}
+
+/// A row of the output of `EXPLAIN`.
+#[derive(QueryableByName)]
+struct PlanLine {
+    #[diesel(sql_type = Text, column_name = "QUERY PLAN")]
+    line: String,
+}
+
+/// Fetch the plan of `statement`, an `EXPLAIN` statement, as text.
+fn explain(conn: &mut PgConnection, statement: &str) -> QueryResult<String> {
+    let lines = diesel::sql_query(statement).load::<PlanLine>(conn)?;
+    let lines: Vec<_> = lines.into_iter().map(|l| l.line).collect();
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_selects_are_explained() {
+        assert_eq!(statement("UPDATE users SET name = 'a'", true), None);
+        assert_eq!(statement("DELETE FROM users", false), None);
+        assert_eq!(
+            statement("SELECT 1 FROM users", false),
+            Some((String::from("EXPLAIN SELECT 1 FROM users"), false))
+        );
+    }
+
+    #[test]
+    fn statements_with_binds_get_a_generic_plan() {
+        let sql = r#"SELECT name FROM users WHERE id = $1 -- binds: [7, "a"]"#;
+        let expected = "EXPLAIN (GENERIC_PLAN) SELECT name FROM users WHERE id = $1";
+        assert_eq!(statement(sql, true), Some((String::from(expected), false)));
+        assert_eq!(statement(sql, false), Some((String::from(expected), false)));
+    }
+
+    #[test]
+    fn statements_without_binds_are_analyzed_if_asked() {
+        let sql = "SELECT name FROM users -- binds: []";
+        assert_eq!(
+            statement(sql, true),
+            Some((
+                String::from("EXPLAIN (ANALYZE, BUFFERS) SELECT name FROM users"),
+                true
+            ))
+        );
+        assert_eq!(
+            statement(sql, false),
+            Some((String::from("EXPLAIN SELECT name FROM users"), false))
+        );
+    }
+
+    #[test]
+    fn statements_with_a_locking_clause_are_never_analyzed() {
+        for sql in [
+            "SELECT id FROM users WHERE id = 1 FOR UPDATE",
+            "SELECT * FROM (SELECT id FROM users FOR UPDATE) AS locked",
+            "SELECT id FROM users FOR NO KEY UPDATE SKIP LOCKED",
+        ] {
+            assert_eq!(
+                statement(sql, true),
+                Some((format!("EXPLAIN {sql}"), false)),
+                "{sql}"
+            );
+        }
+    }
+
+    #[test]
+    fn locking_clauses_are_found_anywhere() {
+        for sql in [
+            "SELECT id FROM users FOR UPDATE",
+            "SELECT id FROM users for share",
+            "SELECT id FROM users FOR KEY SHARE",
+            "SELECT id FROM users FOR NO KEY UPDATE",
+            "SELECT id FROM users\nFOR\nUPDATE",
+            "WITH locked AS (SELECT id FROM users FOR UPDATE) SELECT * FROM locked",
+            // Literals aren't parsed, so these are caught too.
+            "SELECT 'waiting for update' FROM users",
+            "SELECT \"for\".\"update\" FROM users",
+        ] {
+            assert!(has_locking_clause(sql), "{sql}");
+        }
+    }
+
+    #[test]
+    fn lookalike_words_are_not_locking_clauses() {
+        for sql in [
+            "SELECT id FROM users",
+            "SELECT for_update FROM users",
+            "SELECT id FROM fortunes WHERE updated",
+            "SELECT id FROM users ORDER BY id FOR",
+            "SELECT format(name) FROM users WHERE name = 'update'",
+        ] {
+            assert!(!has_locking_clause(sql), "{sql}");
+        }
+    }
+
+    #[test]
+    fn execution_time_is_parsed_from_the_last_line() {
+        let plan = "Seq Scan on users  (cost=0.00..1.01 rows=1 width=4) \
+            (actual time=0.010..0.011 rows=1 loops=1)\n\
+            Planning Time: 0.500 ms\n\
+            Execution Time: 250.000 ms";
+        assert_eq!(execution_time(plan), Some(Duration::from_millis(250)));
+        assert_eq!(
+            execution_time("Result\n  Execution Time: 1500 ms  \n"),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn plans_without_an_execution_time_have_none() {
+        assert_eq!(execution_time(""), None);
+        assert_eq!(
+            execution_time("Seq Scan on users  (cost=0.00..1.01 rows=1 width=4)"),
+            None
+        );
+        assert_eq!(execution_time("Planning Time: 0.500 ms"), None);
+        assert_eq!(execution_time("Execution Time: fast"), None);
+        assert_eq!(execution_time("Execution Time: 1.5 s"), None);
+        assert_eq!(execution_time("Execution Time: -1.000 ms"), None);
+    }
+}
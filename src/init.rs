@@ -0,0 +1,102 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Registering the probes with DTrace.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// The probes could not be registered with DTrace, returned by [`init`].
+#[derive(Clone, Debug)]
+pub struct InitError {
+    message: String,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to register DTrace probes: {}", self.message)
+    }
+}
+
+impl std::error::Error for InitError {}
+
+static REGISTERED: OnceLock<Result<(), InitError>> = OnceLock::new();
+
+/// Whether the first connection established registers the probes, if [`init`]
+/// hasn't been called yet.
+static LAZY: AtomicBool = AtomicBool::new(true);
+
+/// Register the probes of this crate, and any others in the program, with
+/// DTrace.
+///
+/// This calls [`usdt::register_probes`] the first time, and returns the same
+/// result on every later call, so it is safe to call unconditionally, from
+/// anywhere in the program's startup code. Registration can fail, for
+/// example, if the process lacks the privileges to open the DTrace helper
+/// device, if the device doesn't exist, or if the kernel rejects the probe
+/// definitions. On platforms without DTrace it typically succeeds without
+/// doing anything.
+///
+/// A failure isn't fatal: the probes stay in place, but are never enabled, so
+/// firing them does nothing and connections work as usual. Everything else in
+/// the crate, such as the Chrome trace or an [`Observer`](crate::Observer),
+/// is unaffected. The caller can log the error, and carry on.
+///
+/// This only knows whether it has been called itself. A program that calls
+/// `usdt::register_probes` directly, e.g., to register the probes of its own
+/// providers, should call `init` instead, which registers those too, or
+/// disable lazy registration with [`set_lazy_init`], which would otherwise
+/// register every probe in the program a second time.
+pub fn init() -> Result<(), InitError> {
+    REGISTERED
+        .get_or_init(|| {
+            usdt::register_probes().map_err(|e| InitError {
+                message: e.to_string(),
+            })
+        })
+        .clone()
+}
+
+/// Choose whether establishing a connection registers the probes, if they
+/// haven't been registered yet.
+///
+/// This is enabled by default. When enabled, the first connection
+/// established by a [`DTraceConnection`](crate::DTraceConnection) or a
+/// [`DTraceConnectionManager`](crate::DTraceConnectionManager) calls [`init`]
+/// before firing any probe, if it hasn't been called yet, so that the probes
+/// are registered even if the program never calls it, or only calls it after
+/// creating a connection, e.g., a pool that connects eagerly. The error, if
+/// any, is discarded, but is returned by any later call to `init`. Later
+/// connections just check that registration was attempted.
+///
+/// Disable this in programs that call `usdt::register_probes` themselves
+/// rather than calling `init`: `init` can't tell that they have, and would
+/// register the probes a second time.
+pub fn set_lazy_init(enabled: bool) {
+    LAZY.store(enabled, Ordering::Relaxed);
+}
+
+/// Register the probes on behalf of a connection being established, unless
+/// that is disabled with [`set_lazy_init`].
+pub(crate) fn lazy() {
+    if REGISTERED.get().is_none() && LAZY.load(Ordering::Relaxed) {
+        let _ = init();
+    }
+}
+
+/// Return true if [`init`] has registered the probes successfully.
+pub fn probes_registered() -> bool {
+    matches!(REGISTERED.get(), Some(Ok(())))
+}
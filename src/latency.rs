@@ -0,0 +1,241 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-process histograms of query latency, by kind of statement.
+//!
+//! Each kind has a fixed histogram of atomic counters, with buckets spaced
+//! logarithmically: every power of two is split into 8 linear sub-buckets, so
+//! that a percentile is accurate to within 12.5%, from nanoseconds to the
+//! longest representable duration. Recording a query is a handful of relaxed
+//! atomic increments, with no locks or allocation. The histograms take about
+//! 24 KiB in all, allocated statically.
+
+use crate::QueryKind;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The number of bits of each value that select its sub-bucket.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// The kinds of statement, in the order of their values.
+pub(crate) const KINDS: [QueryKind; 6] = [
+    QueryKind::Select,
+    QueryKind::Insert,
+    QueryKind::Update,
+    QueryKind::Delete,
+    QueryKind::Other,
+    QueryKind::Introspection,
+];
+
+/// The latency of the queries of one kind, returned by
+/// [`query_latency_summary`].
+///
+/// Percentiles are the upper bound of the histogram bucket they fall in, so
+/// they may overestimate the true value by up to 12.5%, but never exceed the
+/// maximum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// The number of queries completed.
+    pub count: u64,
+    /// The total time taken by those queries.
+    pub sum: Duration,
+    /// The median latency.
+    pub p50: Duration,
+    /// The 90th percentile latency.
+    pub p90: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+    /// The longest latency.
+    pub max: Duration,
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, nanos: u64) {
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> Option<LatencySummary> {
+        // Counts may be updated while they are read, so the percentiles are
+        // computed from the buckets alone, and are consistent with each
+        // other, if not exactly with `count`.
+        let counts: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let max = self.max_nanos.load(Ordering::Relaxed);
+        let percentile = |p: u64| {
+            // The rank of the percentile, rounding up, and at least 1.
+            let rank = (total * p).div_ceil(100).max(1);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            Duration::from_nanos(upper_bound(index).min(max))
+        };
+        Some(LatencySummary {
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: Duration::from_nanos(max),
+        })
+    }
+}
+
+static HISTOGRAMS: [Histogram; KINDS.len()] = [const { Histogram::new() }; KINDS.len()];
+
+/// Return the index of the bucket holding `nanos`.
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exp = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (exp - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exp - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+/// Return the largest value held by the bucket at `index`.
+fn upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub = (SUB_BUCKETS + index % SUB_BUCKETS) as u64;
+    // The last bucket ends at `u64::MAX`, so its end, `(sub + 1) << shift`,
+    // isn't representable, but its start and width are.
+    (sub << shift) + ((1 << shift) - 1)
+}
+
+/// Return a summary of the latency of the queries completed so far on any
+/// connection, by kind of statement.
+///
+/// Kinds of which no query has completed are omitted.
+pub fn query_latency_summary() -> HashMap<QueryKind, LatencySummary> {
+    KINDS
+        .iter()
+        .zip(&HISTOGRAMS)
+        .filter_map(|(&kind, histogram)| Some((kind, histogram.summary()?)))
+        .collect()
+}
+
+/// Return the number of queries of each kind counted so far, in the order of
+/// [`KINDS`].
+pub(crate) fn counts() -> [u64; KINDS.len()] {
+    std::array::from_fn(|i| HISTOGRAMS[i].count.load(Ordering::Relaxed))
+}
+
+/// Record a query of `kind` that took `elapsed`.
+pub(crate) fn record(kind: QueryKind, elapsed: Duration) {
+    let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+    HISTOGRAMS[kind as usize].record(nanos);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_have_a_bucket_each() {
+        for nanos in 0..SUB_BUCKETS as u64 {
+            assert_eq!(bucket(nanos), nanos as usize);
+            assert_eq!(upper_bound(nanos as usize), nanos);
+        }
+    }
+
+    #[test]
+    fn buckets_are_contiguous() {
+        assert_eq!(bucket(0), 0);
+        for index in 0..BUCKETS - 1 {
+            let bound = upper_bound(index);
+            assert_eq!(bucket(bound), index, "{bound}");
+            assert_eq!(bucket(bound + 1), index + 1, "{}", bound + 1);
+        }
+    }
+
+    #[test]
+    fn the_last_bucket_ends_at_the_longest_duration() {
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        assert_eq!(upper_bound(BUCKETS - 1), u64::MAX);
+        assert_eq!(bucket(1 << 63), BUCKETS - SUB_BUCKETS);
+    }
+
+    #[test]
+    fn upper_bounds_overestimate_by_at_most_an_eighth() {
+        for nanos in (SUB_BUCKETS as u64..100_000).chain([u64::MAX / 3, u64::MAX - 1]) {
+            let bound = upper_bound(bucket(nanos));
+            assert!(bound >= nanos, "{nanos}");
+            assert!(bound - nanos <= nanos / SUB_BUCKETS as u64, "{nanos}");
+        }
+    }
+
+    #[test]
+    fn percentiles_are_the_bounds_of_their_buckets() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.summary(), None);
+        for nanos in 1..=100 {
+            histogram.record(nanos);
+        }
+        let summary = histogram.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.sum, Duration::from_nanos(5050));
+        // The buckets of 48..=51 and 88..=95 nanoseconds.
+        assert_eq!(summary.p50, Duration::from_nanos(51));
+        assert_eq!(summary.p90, Duration::from_nanos(95));
+        // The bucket of 96..=103 nanoseconds, capped at the maximum.
+        assert_eq!(summary.p99, Duration::from_nanos(100));
+        assert_eq!(summary.max, Duration::from_nanos(100));
+    }
+
+    #[test]
+    fn the_longest_durations_are_summarized() {
+        let histogram = Histogram::new();
+        histogram.record(u64::MAX);
+        let summary = histogram.summary().unwrap();
+        assert_eq!(summary.p50, Duration::from_nanos(u64::MAX));
+        assert_eq!(summary.p99, Duration::from_nanos(u64::MAX));
+        assert_eq!(summary.max, Duration::from_nanos(u64::MAX));
+    }
+}
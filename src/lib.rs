@@ -15,31 +15,322 @@
 #![cfg_attr(usdt_need_asm, feature(asm))]
 #![cfg_attr(all(target_os = "macos", usdt_need_asm_sym), feature(asm_sym))]
 
-use diesel::backend::Backend;
+use background::Deferred;
+use config::Flags;
 use diesel::connection::{
     AnsiTransactionManager, LoadConnection, SimpleConnection, TransactionManager,
     TransactionManagerStatus,
 };
-use diesel::debug_query;
 use diesel::expression::QueryMetadata;
 use diesel::prelude::*;
 use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
 use diesel::r2d2::R2D2Connection;
+use diesel::result::{ConnectionError, DatabaseErrorKind};
+use establish::EstablishPermit;
+use otel::QuerySpan;
+use query::{duration_nanos, PendingQuery, QueryInfo, QueryStart, TransactionInfo};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell};
 use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use usdt::UniqueId;
 use uuid::Uuid;
 
+mod abi;
+mod background;
+#[cfg(feature = "backtrace")]
+mod backtrace;
+mod batch;
+mod cache;
+mod cancel;
+#[cfg(feature = "chrome-trace")]
+mod chrome;
+mod clock;
+mod config;
+mod context;
+#[cfg(feature = "postgres")]
+mod copy;
+mod cursor;
+#[cfg(any(test, feature = "test-util"))]
+mod dry_run;
+mod erased;
+mod establish;
+#[cfg(feature = "postgres")]
+mod explain;
+mod init;
+#[cfg(feature = "latency-summary")]
+mod latency;
+mod maybe;
+#[cfg(test)]
+mod mock;
+mod observer;
+mod otel;
+mod pool;
+#[cfg(feature = "prometheus-text")]
+mod prometheus;
+mod query;
+#[cfg(feature = "postgres")]
+mod replica;
+#[cfg(feature = "ring-buffer")]
+mod ring;
+mod shared;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "postgres")]
+mod two_phase;
+
+pub use abi::{probe_definitions, ArgType, ProbeArg, ProbeDef};
+pub use background::{start_background_emission, stop_background_emission, OverflowPolicy};
+#[cfg(feature = "backtrace")]
+pub use backtrace::BacktraceOn;
+pub use cancel::instrument_async_transaction;
+#[cfg(feature = "chrome-trace")]
+pub use chrome::{finish_chrome_trace, set_chrome_trace_file, set_chrome_trace_writer};
+#[cfg(feature = "test-util")]
+pub use clock::{reset_clock, set_clock, Clock, MockClock};
+pub use config::{set_default_config, Config, QueryDoneSemantics, Role, SlowQueryEvent};
+pub use context::{
+    current_correlation_id, current_query_context, with_correlation_id, QueryContext,
+};
+#[cfg(feature = "async")]
+pub use context::{with_query_context, with_task_correlation_id};
+#[cfg(feature = "postgres")]
+pub use copy::CopyProgress;
+pub use cursor::DTraceCursor;
+#[cfg(feature = "test-util")]
+pub use dry_run::{EstablishStartArgs, QueryStartArgs};
+pub use erased::DynDTraceConnection;
+pub use establish::{set_build_version, set_establish_concurrency_limit, EstablishTimeout};
+pub use init::{init, probes_registered, set_lazy_init, InitError};
+#[cfg(feature = "latency-summary")]
+pub use latency::{query_latency_summary, LatencySummary};
+pub use maybe::{set_instrument_connections, MaybeInstrumented, MaybeTransactionManager};
+pub use observer::{
+    clear_event_callbacks, clear_global_observer, on_event, set_global_observer, EstablishError,
+    Event, Observer, QueryDoneEvent, QueryStartEvent,
+};
+pub use pool::{
+    instrument_async_checkout, DTraceConnectionManager, DTracePool, DTracePooledConnection,
+    ReuseConnectionIds,
+};
+#[cfg(feature = "prometheus-text")]
+pub use prometheus::{prometheus_text, snapshot_and_reset, Stats};
+pub use query::{render_with, RenderQuery};
+#[cfg(feature = "ring-buffer")]
+pub use ring::{recent_events, set_recent_events_capacity, RecentEvent, RecentEventKind};
+pub use shared::SharedDTraceConnection;
+#[cfg(feature = "tracing")]
+pub use trace::TracingMode;
+
 #[usdt::provider(provider = "diesel_db")]
 pub mod probes {
-    /// Fires right before we attempt to establish a connection.
-    pub fn connection__establish__start(_: &UniqueId, conn_id: Uuid, url: &str) {}
+    use crate::query::{QueryInfo, TransactionInfo};
+
+    /// Fires before `connection-establish-start`, when the attempt to
+    /// establish a connection had to wait for others to complete, under the
+    /// limit set with `set_establish_concurrency_limit`, with how long it
+    /// waited in nanoseconds.
+    pub fn connection__establish__wait(conn_id: Uuid, waited_nanos: u64) {}
+    /// Fires right before we attempt to establish a connection, with the
+    /// build version of the application, see `set_build_version`.
+    ///
+    /// The URL has any password replaced by `***`, as does a libpq
+    /// keyword/value string. Other strings, such as the path of a SQLite
+    /// database, are passed through unchanged.
+    ///
+    /// The last argument is the number of connections being established
+    /// across the process at that moment, including this one.
+    pub fn connection__establish__start(
+        _: &UniqueId,
+        conn_id: Uuid,
+        url: &str,
+        build_version: &str,
+        concurrent: u64,
+    ) {
+    }
     /// Fires when we finish establishing a connection, with a flag indicating
     /// whether it succeeded or failed.
-    pub fn connection__establish__done(_: &UniqueId, conn_id: Uuid, success: u8) {}
+    ///
+    /// This also includes a classification of the error, if any, as a
+    /// `ConnectionErrorKind`. This is `0` if the connection succeeded.
+    ///
+    /// The last argument is `1` if the connection failed because it was
+    /// established with `DTraceConnection::establish_with_timeout`, and the
+    /// timeout expired, and `0` otherwise.
+    pub fn connection__establish__done(
+        _: &UniqueId,
+        conn_id: Uuid,
+        success: u8,
+        error_kind: u8,
+        timed_out: u8,
+    ) {
+    }
+    /// Fires when establishing a connection configured with
+    /// `Config::retry_establish()` has failed for the last time, with the
+    /// number of attempts made.
+    pub fn connection__establish__give_up(conn_id: Uuid, attempts: u64) {}
+    /// Fires just before `query-start`, for connections configured with
+    /// `Config::measure_query_formatting()`, with the time spent rendering
+    /// the text of the query for it in nanoseconds, including normalizing and
+    /// rewriting the text.
+    pub fn query__format(conn_id: Uuid, format_nanos: u64) {}
+    /// Fires just before `query-start`, for connections configured with
+    /// `Config::max_query_text_len()`, when the text of the query is longer
+    /// than the maximum and is passed to `query-start` truncated, with the
+    /// length of the full text in bytes.
+    pub fn query__truncated(_: &UniqueId, conn_id: Uuid, bytes: u64) {}
     /// Fires just before issuing a SQL query.
-    pub fn query__start(_: &UniqueId, conn_id: Uuid, query: &str) {}
+    ///
+    /// This includes a flag indicating whether the query is issued inside an
+    /// open transaction (`in_transaction == 1`) or not (`in_transaction == 0`),
+    /// and the kind of statement, as a `QueryKind`.
+    ///
+    /// The last argument is a JSON object with further details of the query:
+    ///
+    /// - `bytes`: the size of the query text in bytes, including any bind
+    ///   parameters rendered into it, measured before the text is normalized.
+    /// - `char_count`: the size of the same text in characters, which is less
+    ///   than `bytes` when it contains characters outside of ASCII.
+    /// - `op_name`: the name of the operation the query is part of, as set
+    ///   with `DTraceConnection::with_op_name()`, or the empty string.
+    /// - `correlation_id`: the ID of the unit of work the query is part of,
+    ///   as set with `with_correlation_id()`, or the nil UUID.
+    /// - `role`: the role of the connection in a replicated topology, as a
+    ///   `Role`.
+    /// - `fingerprint`: a hash of the normalized shape of the query, which is
+    ///   the same for every execution of the same statement, and stable
+    ///   across processes.
+    /// - `method`: the diesel method that issued the query, as a
+    ///   `QueryMethod`.
+    /// - `shard`: the shard of the connection's database, as set with
+    ///   `Config::shard()`, or the empty string.
+    /// - `context`: the key-value context of the connection, as set with
+    ///   `DTraceConnection::set_context()`, as `key=value` pairs separated by
+    ///   commas, or the empty string.
+    pub fn query__start(
+        _: &UniqueId,
+        conn_id: Uuid,
+        query: &str,
+        in_transaction: u8,
+        kind: u8,
+        info: QueryInfo,
+    ) {
+    }
     /// Fires when a query completes.
-    pub fn query__done(_: &UniqueId, conn_id: Uuid) {}
+    ///
+    /// For queries that return rows, this fires either when the inner
+    /// connection returns the cursor, or when all rows have been consumed from
+    /// it, depending on the connection's `QueryDoneSemantics`.
+    ///
+    /// This includes a flag indicating whether the query succeeded, and the
+    /// number of rows, or `-1` if that isn't known. That is the number of rows
+    /// affected by a statement issued with `execute_returning_count`, or the
+    /// number of rows read from the cursor when the probe fires once the
+    /// cursor is consumed. It is never known for `batch_execute`, nor for
+    /// `load` when the probe fires as the cursor is returned.
+    ///
+    /// The last argument is the text of the query, as passed to
+    /// `query-start`, if the connection is configured with
+    /// `Config::query_text_on_done()`, or the empty string.
+    pub fn query__done(_: &UniqueId, conn_id: Uuid, success: u8, rows: i64, query: &str) {}
+    /// Fires after `query-done`, for queries that took longer than the
+    /// connection's configured slow-query threshold.
+    ///
+    /// This includes the duration of the query in nanoseconds.
+    pub fn query__slow(_: &UniqueId, conn_id: Uuid, elapsed_nanos: u64) {}
+    /// Fires before `query-done`, for queries that fail.
+    ///
+    /// This includes a classification of the error as a `QueryErrorKind`, and
+    /// the names of the constraint and table involved in the error, if the
+    /// database reported them. These are the empty string otherwise, which is
+    /// always the case for backends other than PostgreSQL.
+    pub fn query__error(
+        _: &UniqueId,
+        conn_id: Uuid,
+        error_kind: u8,
+        constraint: &str,
+        table: &str,
+    ) {
+    }
+    /// Fires while the rows of a query are being read, as soon as the number
+    /// of rows exceeds the connection's configured cap.
+    ///
+    /// This fires at most once per query.
+    pub fn query__large_result(conn_id: Uuid, rows: u64) {}
+    /// Fires when all the rows of a query have been read, if there were
+    /// none, after `query-done` if that fires once the cursor is drained.
+    ///
+    /// A query that returned no rows is sometimes a sign of missing data,
+    /// rather than an expected outcome. This only fires if the application
+    /// reads the cursor to its end.
+    pub fn query__empty(_: &UniqueId, conn_id: Uuid) {}
+    /// Fires before each `INSERT`, `UPDATE`, or `DELETE` statement issued with
+    /// `execute_returning_count` outside any transaction, on connections
+    /// configured with `Config::flag_autocommit_writes()`.
+    ///
+    /// The arguments are the text of the query, as passed to `query-start`,
+    /// and its kind, as a `QueryKind`, which is `1`, `2`, or `3`. Such a write
+    /// is committed on its own, which can violate a policy that writes always
+    /// be made in explicit transactions.
+    pub fn query__autocommit_write(_: &UniqueId, conn_id: Uuid, query: &str, kind: u8) {}
+    /// Fires with the plan of a slow `SELECT` statement, on PostgreSQL
+    /// connections that have enabled
+    /// `DTraceConnection::explain_slow_queries()`.
+    ///
+    /// The plan is the text output of `EXPLAIN`, with its rows joined by
+    /// newlines.
+    pub fn query__plan(conn_id: Uuid, plan: &str) {}
+    /// Fires after `query-plan` for slow `SELECT` statements on PostgreSQL
+    /// connections that have enabled
+    /// `DTraceConnection::analyze_slow_queries()`.
+    ///
+    /// This includes the duration of the query as measured by the client, and
+    /// the execution time reported by the server when the statement was run
+    /// again under `EXPLAIN ANALYZE`, both in nanoseconds. The last argument
+    /// is `1` if the query took more than twice as long as its re-execution,
+    /// which suggests it spent most of its time waiting, typically on a lock.
+    pub fn query__lock_wait(
+        conn_id: Uuid,
+        elapsed_nanos: u64,
+        execution_nanos: u64,
+        waited_on_lock: u8,
+    ) {
+    }
+    /// Fires in place of `query-start` and `query-done` for connections
+    /// configured with `Config::batch_queries()`, once for each batch of
+    /// queries completed on that connection.
+    ///
+    /// This includes the number of queries in the batch, and their total
+    /// duration in nanoseconds.
+    pub fn query__batch(conn_id: Uuid, count: u64, total_nanos: u64) {}
+    /// Fires in place of `query-start` and `query-done` for queries that
+    /// repeat the shape of the previous query on a connection configured with
+    /// `Config::coalesce_repeated_queries()`.
+    ///
+    /// This fires once a query of a different shape starts, or the connection
+    /// is closed, with the fingerprint of the repeated shape, as in the `info`
+    /// argument to `query-start`, and the number of repeats, not including the
+    /// first query, which fires the probes as usual.
+    pub fn query__repeated(conn_id: Uuid, fingerprint: u64, count: u64) {}
+    /// Fires just before `query-error` for a query that failed on a
+    /// connection whose previous query succeeded, or with no previous query,
+    /// with the classification of the error as a `QueryErrorKind`, and the
+    /// text of the query, as passed to `query-start`.
+    ///
+    /// This marks the moment a connection goes from healthy to failing,
+    /// rather than every error. A query issued with `load` is moved into the
+    /// inner connection, so its text can't be rendered once it has failed:
+    /// while a connection is healthy, the text of each `load` is rendered
+    /// before it is issued, unless it is already kept for the query, e.g.,
+    /// with `Config::query_text_on_done()`.
+    pub fn query__first_error(_: &UniqueId, conn_id: Uuid, error_kind: u8, query: &str) {}
+    /// Fires for a query that succeeded on a connection whose previous query
+    /// failed, once it completes.
+    pub fn query__recovered(conn_id: Uuid) {}
     /// Fires when we start a transaction.
     ///
     /// This includes the connection ID as well as the depth of the transaction.
@@ -49,7 +340,22 @@ pub mod probes {
     /// The depth is `0` if there is no outstanding transaction, meaning this is
     /// not nested inside another transaction. Querying the transaction status
     /// may fail, in which case `depth == -1`.
-    pub fn transaction__start(conn_id: Uuid, depth: i64) {}
+    ///
+    /// This also includes a flag indicating whether the transaction is nested
+    /// inside another (`is_nested == 1`) or not (`is_nested == 0`), which is
+    /// the same as `depth > 0`.
+    ///
+    /// The last argument is a JSON object with further details of the
+    /// transaction:
+    ///
+    /// - `correlation_id`: the correlation ID in effect, as set with
+    ///   `with_correlation_id()`, or the nil UUID.
+    /// - `role`: the role of the connection, as a `Role`.
+    /// - `shard`: the shard of the connection's database, as set with
+    ///   `Config::shard()`, or the empty string.
+    /// - `context`: the key-value context of the connection, as in the `info`
+    ///   argument to `query-start`.
+    pub fn transaction__start(conn_id: Uuid, depth: i64, is_nested: u8, info: TransactionInfo) {}
     /// Fires when a transaction completes.
     ///
     /// This includes the connection ID as well as the depth of the transaction.
@@ -61,8 +367,382 @@ pub mod probes {
     /// may fail, in which case `depth == -1`.
     ///
     /// This also includes a flag indicating whether the transaction was
-    /// committed (`committed == 1`) or rolled back (`committed == 0`).
-    pub fn transaction__done(conn_id: Uuid, depth: i64, committed: u8) {}
+    /// committed (`committed == 1`) or rolled back (`committed == 0`), and the
+    /// reason the transaction completed, as a `TransactionDoneReason`.
+    ///
+    /// The last arguments are whether the transaction was nested inside
+    /// another, and a JSON object with further details of the transaction,
+    /// both as for `transaction-start`.
+    pub fn transaction__done(
+        conn_id: Uuid,
+        depth: i64,
+        committed: u8,
+        reason: u8,
+        is_nested: u8,
+        info: TransactionInfo,
+    ) {
+    }
+    /// Fires just before `transaction-done` for a transaction that isn't
+    /// nested inside another, with the number of statements issued inside
+    /// it.
+    ///
+    /// This counts every query issued while the transaction was open,
+    /// including those of any transaction nested inside it, but not the
+    /// statements that begin and end the transactions themselves.
+    pub fn transaction__statements(conn_id: Uuid, statement_count: u64) {}
+    /// Fires when we create a savepoint, to start a transaction nested inside
+    /// another.
+    ///
+    /// This fires along with `transaction-start`, with the same depth, which
+    /// is always at least `1`. The name is the one diesel gives the savepoint,
+    /// which is unique among the savepoints open on the connection.
+    pub fn savepoint__start(conn_id: Uuid, depth: i64, name: &str) {}
+    /// Fires when we release a savepoint (`released == 1`), or roll back to
+    /// it (`released == 0`), completing a nested transaction.
+    ///
+    /// This fires along with `transaction-done`, with the same depth and name
+    /// as the matching `savepoint-start`.
+    pub fn savepoint__done(conn_id: Uuid, depth: i64, name: &str, released: u8) {}
+    /// Fires along with `transaction-start`, for a transaction nested deeper
+    /// than the connection's `Config::max_transaction_depth()`, with its
+    /// depth.
+    ///
+    /// Transactions are rarely nested more than a few levels deep on purpose,
+    /// so this usually points to unbounded recursion that wraps each call in a
+    /// transaction.
+    pub fn transaction__deep(conn_id: Uuid, depth: i64) {}
+    /// Fires before each retry of a transaction run with
+    /// `DTraceConnection::transaction_with_retries()`, with the number of
+    /// the attempt about to start, from `2`, and the SQLSTATE of the error
+    /// the previous attempt failed with, e.g., `40001` for a serialization
+    /// failure or `40P01` for a deadlock.
+    ///
+    /// Diesel doesn't expose the SQLSTATE of an error, so it is derived from
+    /// the kind of error diesel reports, and for deadlocks, which diesel
+    /// doesn't classify, from the message. It is the empty string for an
+    /// error it can't be derived for.
+    pub fn transaction__retry(conn_id: Uuid, attempt: u64, sqlstate: &str) {}
+    /// Fires when a transaction run with
+    /// `DTraceConnection::transaction_with_retries()` fails with a retryable
+    /// error on its last attempt, with the number of attempts made.
+    pub fn transaction__retries_exhausted(conn_id: Uuid, attempts: u64) {}
+    /// Fires when committing or rolling back a transaction fails in a way
+    /// that leaves the connection's transaction manager in its error state.
+    ///
+    /// The connection can't be used for transactions after this, and a pool
+    /// will discard it when it is returned.
+    pub fn transaction__broken(conn_id: Uuid) {}
+    /// Fires when a PostgreSQL `PREPARE TRANSACTION` statement, issued
+    /// through `batch_execute`, succeeds, with the transaction's global ID.
+    ///
+    /// This and the other `two_phase-*` probes only fire with the `postgres`
+    /// feature.
+    pub fn two_phase__prepare(conn_id: Uuid, gid: &str) {}
+    /// Fires when a `COMMIT PREPARED` statement, issued through
+    /// `batch_execute`, succeeds.
+    pub fn two_phase__commit(conn_id: Uuid, gid: &str) {}
+    /// Fires when a `ROLLBACK PREPARED` statement, issued through
+    /// `batch_execute`, succeeds.
+    pub fn two_phase__rollback(conn_id: Uuid, gid: &str) {}
+    /// Fires periodically as the data of a PostgreSQL `COPY` wrapped with
+    /// `CopyProgress::new()` flows, and once when it is
+    /// dropped, with the rows and bytes transferred so far.
+    ///
+    /// This only fires with the `postgres` feature.
+    pub fn copy__progress(conn_id: Uuid, rows: u64, bytes: u64) {}
+    /// Fires when we start checking out a connection from a `DTracePool`, or
+    /// an asynchronous pool via `instrument_async_checkout`.
+    pub fn pool__checkout__start(_: &UniqueId) {}
+    /// Fires when a checkout from a `DTracePool` completes, with the ID of the
+    /// connection handed out, a flag indicating whether it succeeded, and the
+    /// time spent waiting for the connection in nanoseconds.
+    ///
+    /// The connection ID is the nil UUID if the checkout failed, or if the
+    /// checkout was from an asynchronous pool via `instrument_async_checkout`.
+    pub fn pool__checkout__done(_: &UniqueId, conn_id: Uuid, success: u8, waited_nanos: u64) {}
+    /// Fires when a checkout from a `DTracePool` finds no idle connection,
+    /// and starts waiting for one.
+    ///
+    /// The ID is the same as that of the enclosing `pool-checkout-start`.
+    pub fn pool__wait__start(_: &UniqueId) {}
+    /// Fires when a checkout that had to wait for a connection gets one, or
+    /// gives up, with a flag indicating which, and the time spent waiting in
+    /// nanoseconds.
+    pub fn pool__wait__done(_: &UniqueId, success: u8, waited_nanos: u64) {}
+    /// Fires when a pool starts validating a connection, e.g., before handing
+    /// it out, for pools configured with `test_on_check_out`.
+    pub fn validate__start(conn_id: Uuid) {}
+    /// Fires when a pool finishes validating a connection, with a flag
+    /// indicating whether the connection is still usable.
+    pub fn validate__done(conn_id: Uuid, success: u8) {}
+    /// Fires when a pool replaces a connection, and the new connection takes
+    /// the ID of the old one.
+    ///
+    /// This only fires for pools using the `ReuseConnectionIds` customizer.
+    pub fn connection__reconnect(old_id: Uuid, new_id: Uuid) {}
+    /// Fires when a connection is closed, either explicitly with
+    /// `DTraceConnection::close()` or when it is dropped.
+    ///
+    /// This includes the number of queries the connection served over its
+    /// lifetime, and its age in seconds.
+    pub fn connection__close(conn_id: Uuid, queries_served: u64, age_secs: u64) {}
+    /// Fires at the end of a call to `DTraceConnection::unwrap_temporarily()`,
+    /// with the time spent in the uninstrumented scope in nanoseconds.
+    ///
+    /// Queries issued on the inner connection within that scope fire no
+    /// probes, and are not counted by the connection.
+    pub fn uninstrumented__scope(conn_id: Uuid, elapsed_nanos: u64) {}
+    /// Fires when `Connection::set_instrumentation` replaces the diesel
+    /// instrumentation of a connection.
+    ///
+    /// This doesn't affect the probes of this crate, but does replace any
+    /// instrumentation installed earlier, which can explain why some other
+    /// source of events stopped reporting for this connection.
+    pub fn instrumentation__replaced(conn_id: Uuid) {}
+    /// Fires when a connection is returned to an r2d2 pool with a transaction
+    /// still open, with the depth of the transaction.
+    ///
+    /// This is usually a transaction that the application forgot to commit or
+    /// roll back. The pool discards the connection.
+    pub fn connection__returned_dirty(conn_id: Uuid, depth: i64) {}
+    /// Fires on the first query issued on a connection after it was
+    /// established, or checked out of a `DTracePool`, with the time between
+    /// the two in nanoseconds.
+    ///
+    /// A long delay means that the application holds a connection it isn't
+    /// using yet, which keeps it from other callers of the pool.
+    pub fn connection__first_query_delay(conn_id: Uuid, delay_nanos: u64) {}
+    /// Fires when a thread gets a `SharedDTraceConnection` that another
+    /// thread was holding, with how long it waited for it in nanoseconds.
+    pub fn connection__lock__wait(conn_id: Uuid, waited_nanos: u64) {}
+    /// Fires after a query that added a prepared statement to the
+    /// connection's statement cache, with the number of statements cached.
+    ///
+    /// Diesel never evicts statements from the cache, so a number that keeps
+    /// growing over a connection's lifetime means that its queries keep
+    /// changing shape, e.g., dynamically built SQL that never repeats.
+    pub fn statement_cache__size(conn_id: Uuid, entries: u64) {}
+    /// Fires after each query issued with `execute_returning_count` or
+    /// `load`, before `query-done`, with `hit == 0` if diesel prepared a new
+    /// statement for the query and added it to the connection's statement
+    /// cache, and `hit == 1` if it didn't.
+    ///
+    /// This is confirmed by diesel, which reports each statement it caches
+    /// to the connection's `Instrumentation`. Diesel reports nothing for a
+    /// statement it reuses from the cache, nor for one it prepares without
+    /// caching, because the query isn't safe to cache, so both of those are
+    /// reported as hits.
+    pub fn query__cache(_: &UniqueId, conn_id: Uuid, hit: u8) {}
+    /// Fires with how far a replica's replay of the primary's WAL is behind,
+    /// in milliseconds, when enabled with
+    /// `DTraceConnection::report_replica_lag()`.
+    ///
+    /// This fires at most once per configured interval on each connection
+    /// whose role is `Replica`, just before a query outside a transaction.
+    pub fn replica__lag(conn_id: Uuid, lag_millis: i64) {}
+    /// Fires on the thread started with `start_background_emission()`, with
+    /// the number of probes it discarded because its channel was full, once
+    /// it has caught up with the rest.
+    pub fn events__dropped(count: u64) {}
+}
+
+/// The marker ending query text truncated to the length set with
+/// [`Config::max_query_text_len`].
+pub const TRUNCATION_MARKER: &str = "...";
+
+/// The longest serialized context passed to the probes, in bytes, see
+/// [`DTraceConnection::set_context`].
+pub const MAX_CONTEXT_LEN: usize = 256;
+
+/// Serialize the key-value context of a connection as `key=value` pairs
+/// separated by commas, leaving out the pairs that don't fit in
+/// [`MAX_CONTEXT_LEN`] bytes.
+fn serialize_context(pairs: &[(String, String)]) -> String {
+    let mut out = String::new();
+    for (key, value) in pairs {
+        let sep = usize::from(!out.is_empty());
+        if out.len() + sep + key.len() + 1 + value.len() > MAX_CONTEXT_LEN {
+            break;
+        }
+        if sep == 1 {
+            out.push(',');
+        }
+        out.push_str(key);
+        out.push('=');
+        out.push_str(value);
+    }
+    out
+}
+
+/// The classification of a connection error, reported by the
+/// `connection-establish-done` probe.
+///
+/// These mirror the variants of diesel's [`ConnectionError`], and are passed to
+/// the probe as a `u8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ConnectionErrorKind {
+    /// The connection was established successfully.
+    Ok = 0,
+    /// The database could not be reached, or rejected the connection, e.g.,
+    /// due to bad credentials.
+    BadConnection = 1,
+    /// The database URL contained a NUL byte.
+    InvalidCString = 2,
+    /// The database URL could not be parsed.
+    InvalidConnectionUrl = 3,
+    /// The connection was established, but configuring it failed.
+    CouldntSetupConfiguration = 4,
+    /// Any other error.
+    Other = 5,
+}
+
+impl ConnectionErrorKind {
+    /// Classify the result of establishing a connection, given the diesel
+    /// error `error`, if `result` failed with one.
+    fn from_result<T, E>(result: &Result<T, E>, error: Option<&ConnectionError>) -> Self {
+        match (result, error) {
+            (Ok(_), _) => ConnectionErrorKind::Ok,
+            (Err(_), Some(ConnectionError::BadConnection(_))) => ConnectionErrorKind::BadConnection,
+            (Err(_), Some(ConnectionError::InvalidCString(_))) => {
+                ConnectionErrorKind::InvalidCString
+            }
+            (Err(_), Some(ConnectionError::InvalidConnectionUrl(_))) => {
+                ConnectionErrorKind::InvalidConnectionUrl
+            }
+            (Err(_), Some(ConnectionError::CouldntSetupConfiguration(_))) => {
+                ConnectionErrorKind::CouldntSetupConfiguration
+            }
+            (Err(_), _) => ConnectionErrorKind::Other,
+        }
+    }
+}
+
+/// The kind of error that caused a query to fail.
+///
+/// This is passed to the `query-error` probe as a `u8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum QueryErrorKind {
+    /// A unique constraint was violated.
+    UniqueViolation = 0,
+    /// A foreign key constraint was violated.
+    ForeignKeyViolation = 1,
+    /// A `NOT NULL` constraint was violated.
+    NotNullViolation = 2,
+    /// A check constraint was violated.
+    CheckViolation = 3,
+    /// The transaction could not be serialized with concurrent transactions,
+    /// and should be retried.
+    SerializationFailure = 4,
+    /// A write was attempted in a read-only transaction.
+    ReadOnlyTransaction = 5,
+    /// The query could not be sent to the database.
+    UnableToSendCommand = 6,
+    /// The connection to the database was closed.
+    ClosedConnection = 7,
+    /// The database reported any other error.
+    OtherDatabaseError = 8,
+    /// The query was expected to return a row, but returned none.
+    NotFound = 9,
+    /// Any other error, e.g., failing to serialize a bind parameter or
+    /// deserialize a row.
+    Other = 10,
+}
+
+impl QueryErrorKind {
+    fn from_error(error: &diesel::result::Error) -> Self {
+        use diesel::result::Error;
+        match error {
+            Error::DatabaseError(kind, _) => match kind {
+                DatabaseErrorKind::UniqueViolation => QueryErrorKind::UniqueViolation,
+                DatabaseErrorKind::ForeignKeyViolation => QueryErrorKind::ForeignKeyViolation,
+                DatabaseErrorKind::NotNullViolation => QueryErrorKind::NotNullViolation,
+                DatabaseErrorKind::CheckViolation => QueryErrorKind::CheckViolation,
+                DatabaseErrorKind::SerializationFailure => QueryErrorKind::SerializationFailure,
+                DatabaseErrorKind::ReadOnlyTransaction => QueryErrorKind::ReadOnlyTransaction,
+                DatabaseErrorKind::UnableToSendCommand => QueryErrorKind::UnableToSendCommand,
+                DatabaseErrorKind::ClosedConnection => QueryErrorKind::ClosedConnection,
+                _ => QueryErrorKind::OtherDatabaseError,
+            },
+            Error::NotFound => QueryErrorKind::NotFound,
+            _ => QueryErrorKind::Other,
+        }
+    }
+}
+
+/// Return the SQLSTATE of `error`, as far as it can be recovered, or the empty
+/// string.
+///
+/// Diesel doesn't expose the SQLSTATE of an error, but each kind of database
+/// error it classifies comes from a single SQLSTATE. Deadlocks aren't
+/// classified, so they are recognized by PostgreSQL's message instead, to tell
+/// them apart from serialization failures.
+fn sqlstate(error: &diesel::result::Error) -> &'static str {
+    let diesel::result::Error::DatabaseError(kind, info) = error else {
+        return "";
+    };
+    match kind {
+        DatabaseErrorKind::UniqueViolation => "23505",
+        DatabaseErrorKind::ForeignKeyViolation => "23503",
+        DatabaseErrorKind::NotNullViolation => "23502",
+        DatabaseErrorKind::CheckViolation => "23514",
+        DatabaseErrorKind::SerializationFailure => "40001",
+        DatabaseErrorKind::ReadOnlyTransaction => "25006",
+        _ if info.message().starts_with("deadlock detected") => "40P01",
+        _ => "",
+    }
+}
+
+/// The kind of statement a query is, passed to the `query-start` probe as a
+/// `u8`.
+///
+/// The kind is determined from the first keyword of the query text, in the
+/// same way for queries issued through `load`, `execute_returning_count`, and
+/// `batch_execute`. A `batch_execute` string containing several statements is
+/// classified by its first statement. Statements that begin with a `WITH`
+/// clause are classified as `Other`, and those referring to the catalog as
+/// `Introspection`, whatever their first keyword.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum QueryKind {
+    /// A `SELECT` statement.
+    Select = 0,
+    /// An `INSERT` statement.
+    Insert = 1,
+    /// An `UPDATE` statement.
+    Update = 2,
+    /// A `DELETE` statement.
+    Delete = 3,
+    /// Any other statement.
+    Other = 4,
+    /// A statement that refers to the database's catalog, through
+    /// `information_schema`, `pg_catalog`, or SQLite's `sqlite_schema`, such
+    /// as the queries made by migration tooling or runtime schema inspection.
+    ///
+    /// This takes precedence over the kind given by the first keyword.
+    Introspection = 5,
+}
+
+/// The diesel method that issued a query, passed to the `query-start` probe
+/// as the `method` key of its JSON argument.
+///
+/// The same statement can often be reached through more than one method,
+/// which render and bind it differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum QueryMethod {
+    /// [`LoadConnection::load`], for queries that return rows.
+    Load = 0,
+    /// [`Connection::execute_returning_count`], for statements that return
+    /// the number of rows affected.
+    Execute = 1,
+    /// [`SimpleConnection::batch_execute`], for raw SQL strings, which may
+    /// contain several statements.
+    BatchExecute = 2,
+    /// [`DTraceConnection::instrument_as_query`], for a pseudo-query spanning
+    /// any number of statements.
+    Closure = 3,
 }
 
 /// A [`Connection`] wrapper that inserts DTrace probe points.
@@ -72,12 +752,792 @@ pub mod probes {
 pub struct DTraceConnection<C: Connection> {
     inner: C,
     id: Uuid,
+    config: Config,
+    // The size of the inner connection's prepared statement cache.
+    statement_cache: cache::StatementCacheSize,
+    closed: bool,
+    query_count: u64,
+    // The number of queries issued in the outermost transaction, if one is
+    // open, shared with the watches of `instrument_async_transaction`, which
+    // report it if the transaction is cancelled.
+    transaction_statements: Arc<AtomicU64>,
+    // The fingerprint of the last query, and the number of times it has
+    // repeated since, when coalescing repeated queries.
+    repeated: Option<(u64, u64)>,
+    // The number of calls to `instrument_as_query` running on this
+    // connection, whose statements don't fire the per-query probes.
+    pseudo_queries: u32,
+    // Whether the last query on this connection failed.
+    failing: bool,
+    // When the connection was established, for measuring its age, and as a
+    // wall-clock time.
+    established: Instant,
+    established_at: SystemTime,
+    // When the connection was established or last checked out, until the
+    // first query after that.
+    awaiting_first_query: Option<Instant>,
+    // The operation name set by `with_op_name`, if any.
+    op_name: Option<String>,
+    // The key-value context set by `set_context`, and its serialized form
+    // passed to the probes.
+    context_pairs: Vec<(String, String)>,
+    context: String,
+    // The configuration used for queries while detailed tracing is on, see
+    // `set_detailed_tracing`.
+    detailed: Option<Config>,
+    // The name of the database, for the OpenTelemetry span attributes.
+    #[cfg(feature = "opentelemetry")]
+    db_name: String,
+    // The depth and start time of each open transaction, for the Chrome trace
+    // and the history of recent events.
+    #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+    transaction_starts: Vec<(i64, Instant)>,
+    // The function used to fetch the plans of slow queries and whether to
+    // analyze them, if enabled, and the text and duration of a slow `load`
+    // whose plan has yet to be fetched.
+    #[cfg(feature = "postgres")]
+    explain: Option<explain::ExplainFn<C>>,
+    #[cfg(feature = "postgres")]
+    analyze: bool,
+    #[cfg(feature = "postgres")]
+    pending_explain: Option<(String, Duration)>,
+    // How often to report the replication lag, if enabled.
+    #[cfg(feature = "postgres")]
+    replica_lag: Option<replica::LagCheck<C>>,
 }
 
 impl<C: Connection> DTraceConnection<C> {
     pub fn id(&self) -> Uuid {
         self.id
     }
+
+    /// Establish a connection using the provided configuration.
+    ///
+    /// [`Connection::establish`] uses the process-wide default configuration
+    /// instead, see [`set_default_config`].
+    pub fn establish_with_config(database_url: &str, config: Config) -> ConnectionResult<Self> {
+        Self::establish_inner(database_url, config, C::establish, |_| false, |e| Some(e))
+    }
+
+    /// Establish a connection to `database_url` with `establish`, firing the
+    /// establishment probes around each attempt, and retrying as configured.
+    ///
+    /// `connection_error` recovers the diesel error from an error returned by
+    /// `establish`, which need not be a [`ConnectionError`] itself, e.g., when
+    /// it comes from an r2d2 connection manager. An error it can't recover is
+    /// reported with a kind of [`ConnectionErrorKind::Other`], and is never
+    /// retried.
+    pub(crate) fn establish_inner<E: std::fmt::Display>(
+        database_url: &str,
+        config: Config,
+        establish: impl Fn(&str) -> Result<C, E>,
+        is_timeout: fn(&ConnectionError) -> bool,
+        connection_error: fn(&E) -> Option<&ConnectionError>,
+    ) -> Result<Self, E> {
+        init::lazy();
+        // Every attempt uses the same connection ID, so that the retries of
+        // one logical connection can be followed.
+        let conn_id = Uuid::new_v4();
+        let retry = config.establish_retry;
+        let max_attempts = retry.map_or(1, |retry| retry.max_attempts.max(1));
+        let mut backoff = retry.map_or(Duration::ZERO, |retry| retry.backoff);
+        let mut attempts = 0;
+        let inner = loop {
+            attempts += 1;
+            let id = UniqueId::new();
+            let permit = EstablishPermit::acquire(conn_id);
+            probes::connection__establish__start!(|| (
+                &id,
+                conn_id,
+                establish::probe_url(database_url),
+                establish::build_version(),
+                permit.concurrent
+            ));
+            let started = observer::enabled().then(clock::now);
+            let conn = establish(database_url);
+            // Release the permit as soon as the attempt completes, so that
+            // waiting to retry doesn't hold up other connections.
+            drop(permit);
+            let error = conn.as_ref().err().and_then(connection_error);
+            if let Some(started) = started {
+                let duration = clock::now().saturating_duration_since(started);
+                observer::notify(|observer| {
+                    let other;
+                    let result = match (&conn, error) {
+                        (Ok(_), _) => Ok(()),
+                        (Err(_), Some(error)) => Err(EstablishError::Connection(error)),
+                        (Err(e), None) => {
+                            other = e.to_string();
+                            Err(EstablishError::Other(&other))
+                        }
+                    };
+                    observer.connection_establish_done(conn_id, result, duration);
+                });
+            }
+            let kind = ConnectionErrorKind::from_result(&conn, error);
+            let timed_out = error.is_some_and(is_timeout);
+            let retry_error = matches!(error, Some(ConnectionError::BadConnection(_)));
+            #[cfg(feature = "prometheus-text")]
+            prometheus::record_establish(kind);
+            probes::connection__establish__done!(|| (
+                &id,
+                conn_id,
+                u8::from(conn.is_ok()),
+                kind as u8,
+                u8::from(timed_out)
+            ));
+            match conn {
+                Ok(inner) => break inner,
+                Err(_) if retry_error && attempts < max_attempts => {
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                Err(e) => {
+                    if retry.is_some() {
+                        probes::connection__establish__give_up!(|| (conn_id, u64::from(attempts)));
+                    }
+                    return Err(e);
+                }
+            }
+        };
+        Ok(Self::from_inner(inner, conn_id, config, database_url))
+    }
+
+    /// Wrap `inner`, a connection that has just been established to
+    /// `database_url`, giving it the ID `id`.
+    fn from_inner(mut inner: C, id: Uuid, config: Config, database_url: &str) -> Self {
+        let statement_cache = cache::StatementCacheSize::install(&mut inner);
+        DTraceConnection {
+            inner,
+            id,
+            statement_cache,
+            config,
+            closed: false,
+            query_count: 0,
+            transaction_statements: Arc::default(),
+            repeated: None,
+            pseudo_queries: 0,
+            failing: false,
+            established: clock::now(),
+            awaiting_first_query: Some(clock::now()),
+            established_at: SystemTime::now(),
+            op_name: None,
+            context_pairs: Vec::new(),
+            context: String::new(),
+            detailed: None,
+            #[cfg(feature = "opentelemetry")]
+            db_name: otel::database_name(database_url),
+            #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+            transaction_starts: Vec::new(),
+            #[cfg(feature = "postgres")]
+            explain: None,
+            #[cfg(feature = "postgres")]
+            analyze: false,
+            #[cfg(feature = "postgres")]
+            pending_explain: None,
+            #[cfg(feature = "postgres")]
+            replica_lag: None,
+        }
+    }
+
+    /// Close the connection.
+    ///
+    /// This fires the `connection-close` probe immediately, and then drops the
+    /// inner connection. Dropping a `DTraceConnection` also fires that probe,
+    /// but this method can be used to mark the retirement of a connection at a
+    /// precise point in the program. The probe fires only once either way.
+    pub fn close(mut self) {
+        self.fire_close();
+    }
+
+    fn fire_close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            batch::flush(self.id);
+            self.flush_repeated();
+            probes::connection__close!(|| (self.id, self.query_count, self.age().as_secs()));
+            observer::notify(|observer| {
+                observer.connection_close(self.id, self.query_count, self.age());
+            });
+        }
+    }
+
+    /// Return the time at which this connection was established.
+    pub fn established_at(&self) -> SystemTime {
+        self.established_at
+    }
+
+    /// Return how long ago this connection was established.
+    ///
+    /// This can be compared against a pool's `max_lifetime`, for example. The
+    /// age of the connection when it is closed is also reported by the
+    /// `connection-close` probe.
+    pub fn age(&self) -> Duration {
+        clock::now().saturating_duration_since(self.established)
+    }
+
+    /// Return the number of queries issued on this connection.
+    ///
+    /// This counts every call to `load`, `execute_returning_count`,
+    /// `batch_execute`, and `instrument_as_query`, whether or not it
+    /// succeeded.
+    pub fn query_count(&self) -> u64 {
+        self.query_count
+    }
+
+    /// Run `f` with direct access to the inner connection, without firing any
+    /// per-query probes.
+    ///
+    /// This is intended for operations where the overhead of the probes
+    /// matters, such as a bulk load. It is the same as using the
+    /// [`DerefMut`] implementation, but documents the intent and marks the
+    /// whole scope with a single `uninstrumented-scope` probe, which fires
+    /// when `f` returns. The connection keeps its ID throughout.
+    pub fn unwrap_temporarily<R>(&mut self, f: impl FnOnce(&mut C) -> R) -> R {
+        let start = clock::now();
+        let result = f(&mut self.inner);
+        let elapsed = clock::now().saturating_duration_since(start);
+        probes::uninstrumented__scope!(|| (self.id, duration_nanos(elapsed)));
+        result
+    }
+
+    /// Run `f`, labelling every query it issues on this connection with
+    /// `op_name`.
+    ///
+    /// The name is passed to the `query-start` probe, which is handy for
+    /// aggregating by a human-meaningful operation, like
+    /// `"fetch_user_by_email"`, rather than by query text:
+    ///
+    /// ```rust,ignore
+    /// let user = conn.with_op_name("fetch_user_by_email", |conn| {
+    ///     users.filter(email.eq(address)).first::<User>(conn)
+    /// })?;
+    /// ```
+    ///
+    /// Calls can be nested, in which case the innermost name applies, and the
+    /// enclosing name is restored when `f` returns. The name is stored on the
+    /// connection itself, so it applies wherever `f` runs.
+    pub fn with_op_name<R>(&mut self, op_name: &str, f: impl FnOnce(&mut Self) -> R) -> R {
+        let previous = self.op_name.replace(op_name.to_string());
+        let result = f(self);
+        self.op_name = previous;
+        result
+    }
+
+    /// Attach `value` under `key` to the probes of this connection, replacing
+    /// any value already attached under `key`.
+    ///
+    /// The context is for static tags, like the service, datacenter, or
+    /// tenant a connection serves, beyond its role and shard. It is passed to
+    /// `query-start`, `transaction-start`, and `transaction-done`, as the
+    /// `context` key of their `info` arguments, serialized as `key=value`
+    /// pairs separated by commas, in the order the keys were first set. The
+    /// pairs are serialized as each is set, rather than for each probe, and
+    /// only as many as fit in [`MAX_CONTEXT_LEN`] bytes are included.
+    pub fn set_context(&mut self, key: &str, value: &str) {
+        match self.context_pairs.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self
+                .context_pairs
+                .push((key.to_string(), value.to_string())),
+        }
+        self.context = serialize_context(&self.context_pairs);
+    }
+
+    /// Remove all the key-value context attached with
+    /// [`DTraceConnection::set_context`].
+    pub fn clear_context(&mut self) {
+        self.context_pairs.clear();
+        self.context.clear();
+    }
+
+    /// Start measuring the time until the next query on this connection, to
+    /// be reported by the `connection-first_query_delay` probe.
+    ///
+    /// [`DTracePool`] calls this on each connection it hands out. It can be
+    /// called on connections checked out of other pools, such as an
+    /// asynchronous one, to measure the same.
+    pub fn mark_checked_out(&mut self) {
+        self.awaiting_first_query = Some(clock::now());
+    }
+
+    /// Return the configuration of this connection.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replace the configuration of this connection.
+    pub fn set_config(&mut self, config: Config) {
+        if self.detailed.is_some() {
+            self.detailed = Some(config.detailed());
+        }
+        self.config = config;
+    }
+
+    /// Turn detailed tracing of this connection on or off.
+    ///
+    /// While it is on, every query on this connection fires the probes with
+    /// full detail, whatever its configuration says: the query text is passed
+    /// to `query-start` and `query-done` as issued, with its bind parameters,
+    /// and neither omitted, redacted, normalized, nor rewritten; every query is
+    /// sampled; and, with the `backtrace` feature, a backtrace is captured for
+    /// every slow or failed query. This allows debugging one connection of a
+    /// pool in production while the others stay lightweight. The configuration
+    /// returned by [`DTraceConnection::config`] is unchanged, and applies again
+    /// once detailed tracing is turned off.
+    pub fn set_detailed_tracing(&mut self, on: bool) {
+        self.detailed = on.then(|| self.config.detailed());
+    }
+
+    /// Return true if detailed tracing of this connection is on, see
+    /// [`DTraceConnection::set_detailed_tracing`].
+    pub fn detailed_tracing(&self) -> bool {
+        self.detailed.is_some()
+    }
+
+    /// Return the configuration applied to queries, which is overridden while
+    /// detailed tracing is on.
+    fn query_config(&self) -> &Config {
+        self.detailed.as_ref().unwrap_or(&self.config)
+    }
+
+    /// Apply the configured transformations to the query text for the probes.
+    fn query_text<'a>(&self, text: Cow<'a, str>) -> Cow<'a, str> {
+        self.truncate_query_text(self.transform_query_text(text))
+    }
+
+    /// Return the query text passed to the `query-start` probe for the query
+    /// `id`, firing the `query-truncated` probe first if it is truncated.
+    fn start_query_text<'a>(&self, id: &UniqueId, text: Cow<'a, str>) -> Cow<'a, str> {
+        let text = self.transform_query_text(text);
+        let bytes = text.len();
+        if matches!(self.query_config().max_query_text_len, Some(max) if bytes > max) {
+            probes::query__truncated!(|| (id, self.id, bytes as u64));
+        }
+        self.truncate_query_text(text)
+    }
+
+    /// Truncate `text` to the length set with `Config::max_query_text_len`,
+    /// if it is longer, marking the cut with [`TRUNCATION_MARKER`].
+    fn truncate_query_text<'a>(&self, text: Cow<'a, str>) -> Cow<'a, str> {
+        let config = self.query_config();
+        let Some(max) = config.max_query_text_len.filter(|&max| text.len() > max) else {
+            return text;
+        };
+        debug_assert!(
+            !config.flags.contains(Flags::STRICT_QUERY_TEXT_LEN),
+            "the text of a query is {} bytes long, more than the maximum of {max}",
+            text.len(),
+        );
+        let mut end = max.saturating_sub(TRUNCATION_MARKER.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Cow::Owned(format!("{}{TRUNCATION_MARKER}", &text[..end]))
+    }
+
+    /// Apply the configured transformations to the query text for the probes,
+    /// other than truncating it.
+    fn transform_query_text<'a>(&self, text: Cow<'a, str>) -> Cow<'a, str> {
+        let flags = self.query_config().flags;
+        if flags.contains(Flags::OMIT_QUERY_TEXT) {
+            return Cow::Borrowed("");
+        }
+        let text = if flags.contains(Flags::REDACT_BIND_PARAMS) {
+            match text {
+                Cow::Borrowed(text) => query::redact_binds(text),
+                Cow::Owned(text) => Cow::Owned(query::redact_binds(&text).into_owned()),
+            }
+        } else {
+            text
+        };
+        let text = if flags.contains(Flags::NORMALIZE_QUERY_SHAPE) {
+            Cow::Owned(query::normalize_shape(&text))
+        } else {
+            text
+        };
+        let Some(rewrite) = self.query_config().rewrite_query_text else {
+            return text;
+        };
+        // Avoid copying the text if the rewrite left it unchanged.
+        let rewritten = match rewrite(&text) {
+            Cow::Borrowed(s) if std::ptr::eq(s, &*text) => None,
+            other => Some(other.into_owned()),
+        };
+        rewritten.map_or(text, Cow::Owned)
+    }
+
+    /// Record that a query with `fingerprint` is starting, returning true if it
+    /// repeats the shape of the previous query, so that its probes are
+    /// coalesced into `query-repeated`.
+    fn coalesce(&mut self, fingerprint: u64) -> bool {
+        match &mut self.repeated {
+            Some((last, count)) if *last == fingerprint => {
+                *count += 1;
+                true
+            }
+            _ => {
+                self.flush_repeated();
+                self.repeated = Some((fingerprint, 0));
+                false
+            }
+        }
+    }
+
+    /// Fire the `query-repeated` probe for the repeats of the last query, if
+    /// there were any.
+    fn flush_repeated(&mut self) {
+        if let Some((fingerprint, count)) = self.repeated.take().filter(|&(_, count)| count > 0) {
+            probes::query__repeated!(|| (self.id, fingerprint, count));
+        }
+    }
+
+    /// Return whether the connection `conn_id` is failing after the `result`
+    /// of the query `id`, given whether its previous query failed, firing
+    /// `query-first_error`, with the text of the query returned by `text`, or
+    /// `query-recovered` if they differ.
+    ///
+    /// This takes the state it updates, rather than the connection, so that
+    /// it can be called while the cursor returned by `load` borrows the inner
+    /// connection, or `text` borrows the connection.
+    fn track_health<T>(
+        failing: bool,
+        id: &UniqueId,
+        conn_id: Uuid,
+        result: &QueryResult<T>,
+        text: impl FnOnce() -> String,
+    ) -> bool {
+        match result {
+            Err(error) if !failing => {
+                probes::query__first_error!(|| (
+                    id,
+                    conn_id,
+                    QueryErrorKind::from_error(error) as u8,
+                    text()
+                ));
+                true
+            }
+            Ok(_) if failing => {
+                probes::query__recovered!(|| conn_id);
+                false
+            }
+            _ => failing,
+        }
+    }
+
+    /// Build the arguments of the `query-start` probe for a query issued with
+    /// `method`, given its `text` before any transformation, with `transform`
+    /// applying the configured transformations to it.
+    ///
+    /// Issuing a query and the dry run of `query_start_args` both build the
+    /// arguments here, so that they can't disagree.
+    fn query_start<'a>(
+        &self,
+        text: Cow<'a, str>,
+        shape_key: Option<TypeId>,
+        method: QueryMethod,
+        in_transaction: bool,
+        transform: impl FnOnce(Cow<'a, str>) -> Cow<'a, str>,
+    ) -> QueryStart<'a> {
+        let kind = query::classify(&text);
+        let info = self.query_info(&text, shape_key, method);
+        QueryStart {
+            query: transform(text),
+            in_transaction,
+            kind,
+            info,
+        }
+    }
+
+    /// Return the details of a query passed to the `query-start` probe, given
+    /// its text before any transformation.
+    fn query_info(&self, text: &str, shape_key: Option<TypeId>, method: QueryMethod) -> QueryInfo {
+        QueryInfo {
+            bytes: text.len() as u64,
+            char_count: text.chars().count() as u64,
+            op_name: self.op_name.as_deref().unwrap_or("").to_string(),
+            correlation_id: context::current_correlation_id(),
+            role: self.config.role as u8,
+            fingerprint: query::fingerprint(shape_key, text),
+            method: method as u8,
+            shard: self.config.shard.clone(),
+            context: self.context.clone(),
+        }
+    }
+
+    /// Return the details of a transaction passed to the `transaction-start`
+    /// and `transaction-done` probes.
+    fn transaction_info(&self) -> TransactionInfo {
+        TransactionInfo {
+            correlation_id: context::current_correlation_id(),
+            role: self.config.role as u8,
+            shard: self.config.shard.clone(),
+            context: self.context.clone(),
+        }
+    }
+}
+
+impl<C> DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    /// Run `f`, reporting it as a single query whose text is `name`, rather
+    /// than each of the statements it issues on this connection.
+    ///
+    /// This is for operations that span several diesel calls but are one
+    /// logical query, such as a stored procedure called in steps. The
+    /// `query-start` probe fires with `name` as the text of the query, and a
+    /// `method` of [`QueryMethod::Closure`], before `f` runs, and
+    /// `query-done` when it returns, succeeding if `f` returns `Ok`. The
+    /// statements `f` issues don't fire `query-start`, `query-error` or
+    /// `query-done` themselves, though the other probes still fire for each.
+    /// If `f` panics, `query-done` fires with `success == 0`, and the
+    /// statements on this connection fire their probes again after that.
+    ///
+    /// Calls can be nested, in which case only the outermost is reported.
+    /// The name goes through the same transformations as the text of any
+    /// other query, such as normalization.
+    pub fn instrument_as_query<T>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut Self) -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        let pending = self.start_query(|| Cow::Borrowed(name), None, QueryMethod::Closure);
+        self.pseudo_queries += 1;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut *self)));
+        self.pseudo_queries -= 1;
+        match result {
+            Ok(result) => {
+                pending.finish(&result, None);
+                result
+            }
+            Err(payload) => {
+                pending.finish_panicked();
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    /// Start instrumenting a query, firing the `query-start` probe.
+    ///
+    /// `render` renders the text of the query. It is only called when the text
+    /// is actually needed, e.g., because the probe is enabled, and at most
+    /// once, however many of the probes, observers and other consumers of the
+    /// text need it. `shape_key` is the key its fingerprint is cached by, see
+    /// [`RenderQuery::shape_key`], and `method` the method issuing it.
+    fn start_query<'a>(
+        &mut self,
+        render: impl Fn() -> Cow<'a, str>,
+        shape_key: Option<TypeId>,
+        method: QueryMethod,
+    ) -> PendingQuery {
+        let measure = self.config.flags.contains(Flags::MEASURE_QUERY_FORMATTING);
+        let rendered = OnceCell::new();
+        let render_time = Cell::new(Duration::ZERO);
+        let text = || {
+            let text = rendered.get_or_init(|| {
+                let started = measure.then(clock::now);
+                let text = render();
+                if let Some(started) = started {
+                    render_time.set(clock::now().saturating_duration_since(started));
+                }
+                text
+            });
+            Cow::Borrowed(&**text)
+        };
+        #[cfg(feature = "postgres")]
+        {
+            self.fetch_pending_plan();
+            self.check_replica_lag();
+        }
+        self.query_count += 1;
+        if let Some(since) = self.awaiting_first_query.take() {
+            let delay = clock::now().saturating_duration_since(since);
+            probes::connection__first_query_delay!(|| (self.id, duration_nanos(delay)));
+        }
+        let in_transaction = DTraceTransactionManager::<C>::depth(self) > 0;
+        if in_transaction {
+            self.transaction_statements.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(feature = "opentelemetry")]
+        let db_name = self.db_name.as_str();
+        #[cfg(not(feature = "opentelemetry"))]
+        let db_name = "";
+        let span = QuerySpan::start::<C::Backend>(db_name, || self.query_text(text()));
+        let op_name = self.op_name.as_deref().unwrap_or("");
+        let mut pending = PendingQuery::new(
+            self.id,
+            self.query_config(),
+            span,
+            || self.query_text(text()).into_owned(),
+            op_name,
+        );
+        #[cfg(feature = "latency-summary")]
+        {
+            pending.kind = Some(query::classify(&text()));
+        }
+        #[cfg(feature = "tracing")]
+        {
+            pending.trace = Some(trace::QueryTrace::start(
+                self.config.tracing_mode,
+                pending.id.as_u64(),
+                self.id,
+                || {
+                    let text = text();
+                    let kind = query::classify(&text);
+                    (self.query_text(text).into_owned(), kind)
+                },
+            ));
+        }
+        let one_in = self.query_config().sample_one_in.max(1);
+        pending.sampled = (self.query_count - 1) % one_in == 0;
+        pending.suppressed = self.pseudo_queries > 0;
+        if self.config.flags.contains(Flags::COALESCE_REPEATED_QUERIES) && self.detailed.is_none() {
+            let repeated = self.coalesce(query::fingerprint(shape_key, &text()));
+            pending.sampled &= !repeated;
+        }
+        // In batched mode, only the `query-batch` probe marks each query.
+        if self.config.batch.is_none() && pending.sampled && !pending.suppressed {
+            if background::enabled() {
+                let start = self.query_start(text(), shape_key, method, in_transaction, |text| {
+                    self.start_query_text(&pending.id, text)
+                });
+                background::send(Deferred::QueryStart {
+                    id: pending.id.clone(),
+                    conn_id: self.id,
+                    query: start.query.into_owned(),
+                    in_transaction: u8::from(start.in_transaction),
+                    kind: start.kind as u8,
+                    info: start.info,
+                });
+            } else {
+                probes::query__start!(|| {
+                    let start =
+                        self.query_start(text(), shape_key, method, in_transaction, |text| {
+                            let started = measure.then(clock::now);
+                            let query = self.start_query_text(&pending.id, text);
+                            if let Some(started) = started {
+                                // The time to render the text, whenever that was
+                                // first needed, and then to transform it.
+                                let format = render_time.get()
+                                    + clock::now().saturating_duration_since(started);
+                                probes::query__format!(|| (self.id, duration_nanos(format)));
+                            }
+                            query
+                        });
+                    (
+                        &pending.id,
+                        self.id,
+                        start.query,
+                        u8::from(start.in_transaction),
+                        start.kind as u8,
+                        start.info,
+                    )
+                });
+            }
+        }
+        if method == QueryMethod::Execute
+            && !in_transaction
+            && self.config.flags.contains(Flags::FLAG_AUTOCOMMIT_WRITES)
+        {
+            let text = text();
+            let kind = query::classify(&text);
+            if matches!(
+                kind,
+                QueryKind::Insert | QueryKind::Update | QueryKind::Delete
+            ) {
+                probes::query__autocommit_write!(|| (
+                    &pending.id,
+                    self.id,
+                    self.query_text(text),
+                    kind as u8
+                ));
+            }
+        }
+        observer::notify(|observer| {
+            let text = text();
+            observer.query_start(&QueryStartEvent {
+                id: pending.id.as_u64(),
+                conn_id: self.id,
+                query: &self.query_text(text.clone()),
+                kind: query::classify(&text),
+                method,
+                in_transaction,
+                #[cfg(feature = "wall-clock")]
+                timestamp: SystemTime::now(),
+            });
+        });
+        pending
+    }
+}
+
+impl<C> DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    /// Run `f` in a transaction, as [`Connection::transaction`] does,
+    /// retrying it up to `max_attempts` times in all while it fails with a
+    /// retryable error.
+    ///
+    /// The errors retried are those set with
+    /// [`Config::retry_transactions_if`], serialization failures by default.
+    /// Each attempt is a transaction of its own, so `f` must be safe to run
+    /// again, and fires the transaction probes as usual. Before each retry,
+    /// `transaction-retry` fires with the number of the attempt and the
+    /// SQLSTATE of the error that failed the last one, and if the last attempt
+    /// fails with a retryable error, `transaction-retries_exhausted` fires, and
+    /// the error is returned. Other errors are returned right away.
+    ///
+    /// A transaction nested in another can't be retried on its own, since a
+    /// serialization failure aborts the outermost transaction, so inside one,
+    /// this runs `f` once, like [`Connection::transaction`].
+    pub fn transaction_with_retries<T, F>(&mut self, max_attempts: u32, mut f: F) -> QueryResult<T>
+    where
+        F: FnMut(&mut Self) -> QueryResult<T>,
+    {
+        if DTraceTransactionManager::<C>::depth(self) != 0 {
+            return self.transaction(f);
+        }
+        let max_attempts = max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            match self.transaction(&mut f) {
+                Err(error) if self.config.is_retryable(&error) => {
+                    if attempt >= max_attempts {
+                        probes::transaction__retries_exhausted!(|| (self.id, u64::from(attempt)));
+                        return Err(error);
+                    }
+                    attempt += 1;
+                    probes::transaction__retry!(|| (self.id, u64::from(attempt), sqlstate(&error)));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<C: EstablishTimeout> DTraceConnection<C> {
+    /// Establish a connection, failing if that takes longer than `timeout`.
+    ///
+    /// This uses the process-wide default configuration. If the timeout
+    /// expires, the `timed_out` argument of the `connection-establish-done`
+    /// probe is `1`.
+    pub fn establish_with_timeout(database_url: &str, timeout: Duration) -> ConnectionResult<Self> {
+        Self::establish_inner(
+            database_url,
+            config::default_config(),
+            |url| C::establish_with_timeout(url, timeout),
+            C::is_timeout,
+            |e| Some(e),
+        )
+    }
+}
+
+impl<C: Connection> Drop for DTraceConnection<C> {
+    fn drop(&mut self) {
+        self.fire_close();
+    }
 }
 
 impl<C: Connection> Deref for DTraceConnection<C> {
@@ -93,12 +1553,27 @@ impl<C: Connection> DerefMut for DTraceConnection<C> {
     }
 }
 
-impl<C: Connection> SimpleConnection for DTraceConnection<C> {
+impl<C> SimpleConnection for DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
     fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
-        let id = UniqueId::new();
-        probes::query__start!(|| (&id, self.id, query));
+        let pending = self.start_query(|| Cow::Borrowed(query), None, QueryMethod::BatchExecute);
         let result = self.inner.batch_execute(query);
-        probes::query__done!(|| (&id, self.id));
+        self.failing = Self::track_health(self.failing, &pending.id, self.id, &result, || {
+            self.query_text(Cow::Borrowed(query)).into_owned()
+        });
+        #[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+        let slow = pending.finish(&result, None);
+        #[cfg(feature = "postgres")]
+        {
+            if result.is_ok() {
+                two_phase::fire(self.id, query);
+            }
+            if let Some(elapsed) = slow {
+                self.fetch_plan(query, elapsed);
+            }
+        }
         result
     }
 }
@@ -106,11 +1581,10 @@ impl<C: Connection> SimpleConnection for DTraceConnection<C> {
 impl<C> LoadConnection for DTraceConnection<C>
 where
     C: Connection<TransactionManager = AnsiTransactionManager> + LoadConnection,
-    C::Backend: Default,
-    <C::Backend as Backend>::QueryBuilder: Default,
+    C::Backend: RenderQuery,
 {
     type Cursor<'conn, 'query>
-        = C::Cursor<'conn, 'query>
+        = DTraceCursor<C::Cursor<'conn, 'query>>
     where
         Self: 'conn;
     type Row<'conn, 'query>
@@ -126,50 +1600,92 @@ where
         T: diesel::query_builder::Query + QueryFragment<Self::Backend> + QueryId + 'query,
         Self::Backend: QueryMetadata<T::SqlType>,
     {
+        let shape_key = <C::Backend as RenderQuery>::shape_key(&source);
         let query = source.as_query();
-        let id = UniqueId::new();
-        probes::query__start!(|| (
-            &id,
-            self.id,
-            debug_query::<Self::Backend, _>(&query).to_string()
-        ));
+        let pending = self.start_query(
+            || Cow::Owned(<C::Backend as RenderQuery>::render(&query)),
+            shape_key,
+            QueryMethod::Load,
+        );
+        let conn_id = self.id;
+        let query_id = pending.id.clone();
+        let semantics = self.config.query_done_semantics;
+        let large_result_rows = self.config.large_result_rows;
+        // The query is consumed by the inner connection, so it can't be
+        // rendered lazily once it turns out to be slow. Render it now, but only
+        // if its plan could be fetched at all: explaining is enabled, the load
+        // completes here rather than once its cursor is drained, and there is
+        // a threshold for it to exceed.
+        #[cfg(feature = "postgres")]
+        let explain_text = (self.explain.is_some()
+            && semantics == QueryDoneSemantics::OnDispatch
+            && self.query_config().slow_query_threshold.is_some())
+        .then(|| <C::Backend as RenderQuery>::render(&query));
+        // Nor can it be rendered lazily once it turns out to fail. Render it
+        // now if the connection is healthy, so that a failure would fire
+        // `query-first_error`, reusing the text kept for the query if any.
+        let first_error_text = (!self.failing).then(|| match pending.text() {
+            Some(text) => text.to_string(),
+            None => self
+                .query_text(Cow::Owned(<C::Backend as RenderQuery>::render(&query)))
+                .into_owned(),
+        });
         let result = self.inner.load(query);
-        probes::query__done!(|| (&id, self.id));
+        self.statement_cache.report(&query_id, conn_id);
+        self.failing = Self::track_health(self.failing, &query_id, conn_id, &result, || {
+            first_error_text.unwrap_or_default()
+        });
+        let pending = match semantics {
+            QueryDoneSemantics::OnCursorDrain if result.is_ok() => Some(pending),
+            _ => {
+                #[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+                let slow = pending.finish(&result, None);
+                #[cfg(feature = "postgres")]
+                if let Some(elapsed) = slow {
+                    self.pending_explain = explain_text.map(|text| (text, elapsed));
+                }
+                None
+            }
+        };
         result
+            .map(|cursor| DTraceCursor::new(cursor, conn_id, query_id, pending, large_result_rows))
     }
 }
 
 impl<C> Connection for DTraceConnection<C>
 where
     C: Connection<TransactionManager = AnsiTransactionManager>,
-    C::Backend: Default,
-    <C::Backend as Backend>::QueryBuilder: Default,
+    C::Backend: RenderQuery,
 {
     type Backend = C::Backend;
     type TransactionManager = DTraceTransactionManager<C>;
 
     fn establish(database_url: &str) -> ConnectionResult<Self> {
-        let id = UniqueId::new();
-        let conn_id = Uuid::new_v4();
-        probes::connection__establish__start!(|| (&id, conn_id, database_url));
-        let conn = C::establish(database_url);
-        probes::connection__establish__done!(|| (&id, conn_id, u8::from(conn.is_ok())));
-        let inner = conn?;
-        Ok(DTraceConnection { inner, id: conn_id })
+        Self::establish_with_config(database_url, config::default_config())
     }
 
     fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
     where
         T: QueryFragment<Self::Backend> + QueryId,
     {
-        let id = UniqueId::new();
-        probes::query__start!(|| (
-            &id,
-            self.id,
-            debug_query::<Self::Backend, _>(&source).to_string()
-        ));
+        let pending = self.start_query(
+            || Cow::Owned(<C::Backend as RenderQuery>::render(source)),
+            <C::Backend as RenderQuery>::shape_key(source),
+            QueryMethod::Execute,
+        );
         let result = self.inner.execute_returning_count(source);
-        probes::query__done!(|| (&id, self.id));
+        self.statement_cache.report(&pending.id, self.id);
+        self.failing = Self::track_health(self.failing, &pending.id, self.id, &result, || {
+            self.query_text(Cow::Owned(<C::Backend as RenderQuery>::render(source)))
+                .into_owned()
+        });
+        let rows = result.as_ref().ok().map(|&rows| rows as u64);
+        #[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+        let slow = pending.finish(&result, rows);
+        #[cfg(feature = "postgres")]
+        if let (Some(elapsed), Some(_)) = (slow, self.explain) {
+            self.fetch_plan(&<C::Backend as RenderQuery>::render(source), elapsed);
+        }
         result
     }
 
@@ -184,6 +1700,13 @@ where
     }
 
     fn set_instrumentation(&mut self, instrumentation: impl diesel::connection::Instrumentation) {
+        probes::instrumentation__replaced!(|| self.id);
+        // None of the other probes rely on the inner connection's
+        // instrumentation, but the size of the statement cache is counted by
+        // it, so keep counting around the new one.
+        let instrumentation = self
+            .statement_cache
+            .instrument(Some(Box::new(instrumentation)));
         self.inner.set_instrumentation(instrumentation)
     }
 }
@@ -191,19 +1714,33 @@ where
 impl<C> diesel::connection::ConnectionSealed for DTraceConnection<C>
 where
     C: Connection<TransactionManager = AnsiTransactionManager>,
-    C::Backend: Default,
-    <C::Backend as Backend>::QueryBuilder: Default,
+    C::Backend: RenderQuery,
 {
 }
 
 impl<C> R2D2Connection for DTraceConnection<C>
 where
     C: R2D2Connection + Connection<TransactionManager = AnsiTransactionManager>,
-    C::Backend: Default,
-    <C::Backend as Backend>::QueryBuilder: Default,
+    C::Backend: RenderQuery,
 {
     fn ping(&mut self) -> QueryResult<()> {
-        self.inner.ping()
+        probes::validate__start!(|| self.id);
+        let result = self.inner.ping();
+        probes::validate__done!(|| (self.id, u8::from(result.is_ok())));
+        result
+    }
+
+    // r2d2 calls this as each connection is returned to the pool, and discards
+    // the connection if it is broken, which includes having been returned with
+    // a transaction still open.
+    fn is_broken(&mut self) -> bool {
+        let depth = DTraceTransactionManager::<C>::depth(self);
+        if depth > 0 {
+            probes::connection__returned_dirty!(|| (self.id, depth));
+        }
+        // The connection is idle in the pool until its next checkout.
+        self.awaiting_first_query = None;
+        self.inner.is_broken()
     }
 }
 
@@ -212,6 +1749,12 @@ where
 /// This manager is responsible for the probes `transaction-start` and
 /// `transaction-done`. See the module-level documentation for more details on
 /// these probes.
+///
+/// [`Connection::begin_test_transaction`] goes through this manager too, so a
+/// test transaction fires `transaction-start` at depth `0`, and transactions
+/// nested inside it report depths starting at `1`, exactly as if the test
+/// transaction were an ordinary one. Since a test transaction is never
+/// committed or rolled back, it has no matching `transaction-done`.
 pub struct DTraceTransactionManager<C> {
     _data: std::marker::PhantomData<C>,
 }
@@ -221,6 +1764,13 @@ where
     C: Connection<TransactionManager = AnsiTransactionManager>,
 {
     /// Compute the current transaction depth for the DTrace probes.
+    ///
+    /// This is `-1` only if the transaction manager is in the error state,
+    /// after it failed to commit or roll back a transaction. It reads the
+    /// status of the inner connection, which is also what
+    /// [`TransactionManager::transaction_manager_status_mut`] returns for this
+    /// connection, so it always agrees with the depth diesel itself uses, test
+    /// transactions included.
     fn depth(conn: &mut DTraceConnection<C>) -> i64 {
         let status = AnsiTransactionManager::transaction_manager_status_mut(&mut conn.inner);
         match status.transaction_depth() {
@@ -229,13 +1779,202 @@ where
             Err(_) => -1,
         }
     }
+
+    /// Return true if the transaction manager is in the error state.
+    fn is_broken(conn: &mut DTraceConnection<C>) -> bool {
+        matches!(
+            AnsiTransactionManager::transaction_manager_status_mut(&mut conn.inner),
+            TransactionManagerStatus::InError
+        )
+    }
+
+    /// Record that the outermost transaction on the connection completed,
+    /// whether it was committed or not, returning the number of statements
+    /// issued in it.
+    fn outermost_done(conn: &mut DTraceConnection<C>) -> u64 {
+        cancel::completed(conn.id);
+        conn.transaction_statements.swap(0, Ordering::Relaxed)
+    }
+
+    /// Fire the `transaction-broken` probe if the transaction manager has
+    /// entered the error state since `was_broken` was computed.
+    fn check_broken(conn: &mut DTraceConnection<C>, was_broken: bool) {
+        if !was_broken && Self::is_broken(conn) {
+            probes::transaction__broken!(|| conn.id);
+        }
+    }
+
+    /// Return when the transaction that just completed, which was at
+    /// `depth`, started, if that was recorded for the Chrome trace and the
+    /// history of recent events.
+    #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+    fn started(conn: &mut DTraceConnection<C>, depth: i64) -> Option<Instant> {
+        // Discard any transactions nested deeper than this one, which we
+        // didn't see complete.
+        while let Some(&(start_depth, started)) = conn.transaction_starts.last() {
+            if start_depth < depth {
+                break;
+            }
+            conn.transaction_starts.pop();
+            if start_depth == depth {
+                return Some(started);
+            }
+        }
+        None
+    }
+
+    /// Fire the `savepoint-done` probe, if the transaction at `depth` that
+    /// just completed was nested inside another.
+    fn savepoint_done(conn: &mut DTraceConnection<C>, depth: i64, released: bool) {
+        if depth > 0 {
+            probes::savepoint__done!(|| (
+                &conn.id,
+                depth,
+                savepoint_name(depth),
+                u8::from(released)
+            ));
+        }
+    }
+
+    /// Record that the transaction at `depth` completed, for `reason`, firing
+    /// `transaction-done` and everything else that marks the end of a
+    /// transaction.
+    ///
+    /// Every way a transaction on the connection can complete goes through
+    /// here, and then [`transaction_done`], so that they all report the same
+    /// things.
+    fn done(
+        conn: &mut DTraceConnection<C>,
+        depth: i64,
+        committed: bool,
+        reason: TransactionDoneReason,
+    ) {
+        Self::savepoint_done(conn, depth, committed);
+        let statements = (depth == 0).then(|| Self::outermost_done(conn));
+        #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+        let started = Self::started(conn, depth);
+        #[cfg(not(any(feature = "chrome-trace", feature = "ring-buffer")))]
+        let started = None;
+        let done = TransactionDone {
+            conn_id: conn.id,
+            depth,
+            committed,
+            reason,
+            statements,
+            started,
+        };
+        transaction_done(&done, || conn.transaction_info());
+    }
+
+    fn rollback(conn: &mut DTraceConnection<C>, reason: TransactionDoneReason) -> QueryResult<()> {
+        let was_broken = Self::is_broken(conn);
+        let result = AnsiTransactionManager::rollback_transaction(&mut conn.inner);
+        let depth = Self::depth(conn);
+        Self::done(conn, depth, false, reason);
+        Self::check_broken(conn, was_broken);
+        result
+    }
+}
+
+/// A transaction that completed, reported by [`transaction_done`].
+pub(crate) struct TransactionDone {
+    pub(crate) conn_id: Uuid,
+    pub(crate) depth: i64,
+    pub(crate) committed: bool,
+    pub(crate) reason: TransactionDoneReason,
+    // The number of statements issued in the transaction, if it was the
+    // outermost.
+    pub(crate) statements: Option<u64>,
+    // When the transaction started, if that was recorded.
+    pub(crate) started: Option<Instant>,
+}
+
+/// Report that a transaction completed, once the connection has updated its
+/// own state, with the details returned by `info`.
+///
+/// This fires `transaction-statements` for an outermost transaction, and then
+/// `transaction-done`, and passes the transaction to the observers, the
+/// Prometheus counters, the Chrome trace, and the history of recent events. It
+/// doesn't need the connection, so that [`instrument_async_transaction`] can
+/// report a transaction cancelled while the connection is out of reach, just as
+/// the transaction manager reports the others.
+pub(crate) fn transaction_done(done: &TransactionDone, info: impl Fn() -> TransactionInfo) {
+    let TransactionDone {
+        conn_id,
+        depth,
+        committed,
+        reason,
+        statements,
+        started,
+    } = *done;
+    if let Some(statements) = statements {
+        probes::transaction__statements!(|| (conn_id, statements));
+    }
+    probes::transaction__done!(|| (
+        &conn_id,
+        depth,
+        u8::from(committed),
+        reason as u8,
+        u8::from(depth > 0),
+        info()
+    ));
+    observer::notify(|observer| observer.transaction_done(conn_id, depth, committed, reason));
+    #[cfg(feature = "prometheus-text")]
+    prometheus::record_transaction(depth, reason);
+    #[cfg(feature = "chrome-trace")]
+    if let Some(started) = started.filter(|_| chrome::enabled()) {
+        chrome::record_transaction(started, conn_id, depth);
+    }
+    #[cfg(feature = "ring-buffer")]
+    if let Some(started) = started.filter(|_| ring::enabled()) {
+        let duration = clock::now().saturating_duration_since(started);
+        ring::record(ring::RecentEventKind::Transaction, conn_id, "", duration);
+    }
+    #[cfg(not(any(feature = "chrome-trace", feature = "ring-buffer")))]
+    let _ = started;
+}
+
+/// Return the name of the savepoint for a transaction at `depth`.
+///
+/// `AnsiTransactionManager` doesn't expose the names of its savepoints, so
+/// this reconstructs the name it uses: a nested transaction started while
+/// `depth` transactions are open creates the savepoint `diesel_savepoint_N`,
+/// where `N` is `depth`, and releases or rolls back to the same name.
+fn savepoint_name(depth: i64) -> String {
+    format!("diesel_savepoint_{depth}")
+}
+
+/// The reason a transaction completed, reported by the `transaction-done`
+/// probe.
+///
+/// These are passed to the probe as a `u8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum TransactionDoneReason {
+    /// The transaction was committed.
+    Commit = 0,
+    /// The transaction was rolled back by an explicit call to
+    /// [`TransactionManager::rollback_transaction`].
+    ExplicitRollback = 1,
+    /// The transaction was rolled back by [`Connection::transaction`], because
+    /// the closure returned an error.
+    ErrorRollback = 2,
+    /// The closure passed to [`Connection::transaction`] panicked.
+    ///
+    /// Like diesel itself, we don't roll back the transaction in this case:
+    /// it remains open on the connection, which a pool then discards.
+    Panic = 3,
+    /// The future running an asynchronous transaction was dropped before the
+    /// transaction completed, see [`instrument_async_transaction`].
+    ///
+    /// As with a panic, the transaction remains open on the connection.
+    Cancelled = 4,
 }
 
 impl<C> TransactionManager<DTraceConnection<C>> for DTraceTransactionManager<C>
 where
     C: Connection<TransactionManager = AnsiTransactionManager>,
-    C::Backend: Default,
-    <C::Backend as Backend>::QueryBuilder: Default,
+    C::Backend: RenderQuery,
 {
     type TransactionStateData = AnsiTransactionManager;
 
@@ -266,27 +2005,337 @@ where
         // of `AnsiTransactionManager`, and destructure a few enums. It should
         // be in the noise for any realistic database application.
         let depth = Self::depth(conn);
-        probes::transaction__start!(|| (&conn.id, depth));
-        AnsiTransactionManager::begin_transaction(&mut conn.inner)
+        probes::transaction__start!(|| (
+            &conn.id,
+            depth,
+            u8::from(depth > 0),
+            conn.transaction_info()
+        ));
+        observer::notify(|observer| observer.transaction_start(conn.id, depth));
+        if conn
+            .config
+            .max_transaction_depth
+            .is_some_and(|max| depth > max)
+        {
+            probes::transaction__deep!(|| (conn.id, depth));
+        }
+        if depth > 0 {
+            probes::savepoint__start!(|| (&conn.id, depth, savepoint_name(depth)));
+        } else {
+            conn.transaction_statements.store(0, Ordering::Relaxed);
+            cancel::started(conn.id, &conn.transaction_statements, || {
+                conn.transaction_info()
+            });
+        }
+        let result = AnsiTransactionManager::begin_transaction(&mut conn.inner);
+        #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+        if result.is_ok() {
+            conn.transaction_starts.push((depth, clock::now()));
+        }
+        result
     }
 
     fn rollback_transaction(conn: &mut DTraceConnection<C>) -> QueryResult<()> {
-        let result = AnsiTransactionManager::rollback_transaction(&mut conn.inner);
-        let depth = Self::depth(conn);
-        probes::transaction__done!(|| (&conn.id, depth, 0));
-        result
+        Self::rollback(conn, TransactionDoneReason::ExplicitRollback)
     }
 
     fn commit_transaction(conn: &mut DTraceConnection<C>) -> QueryResult<()> {
+        let was_broken = Self::is_broken(conn);
         let result = AnsiTransactionManager::commit_transaction(&mut conn.inner);
         let depth = Self::depth(conn);
-        probes::transaction__done!(|| (&conn.id, depth, 1));
+        Self::done(conn, depth, true, TransactionDoneReason::Commit);
+        Self::check_broken(conn, was_broken);
         result
     }
 
+    // This is the same as the default implementation, except that it marks
+    // rollbacks caused by the callback returning an error as such, and fires
+    // `transaction-done` if the callback panics. Diesel doesn't roll back in
+    // that case, so the transaction would otherwise never appear to end.
+    fn transaction<F, R, E>(conn: &mut DTraceConnection<C>, callback: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut DTraceConnection<C>) -> Result<R, E>,
+        E: From<diesel::result::Error>,
+    {
+        Self::run_transaction(conn, |conn| conn, callback)
+    }
+
     fn transaction_manager_status_mut(
         conn: &mut DTraceConnection<C>,
     ) -> &mut TransactionManagerStatus {
         AnsiTransactionManager::transaction_manager_status_mut(&mut conn.inner)
     }
 }
+
+impl<C> DTraceTransactionManager<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    /// Run `callback` in a transaction on `conn`, a connection that wraps the
+    /// `DTraceConnection` returned by `dtrace`, or is that connection itself.
+    ///
+    /// This implements [`TransactionManager::transaction`] for any such
+    /// connection, so that the callback is passed the connection it expects.
+    pub(crate) fn run_transaction<T, F, R, E>(
+        conn: &mut T,
+        dtrace: fn(&mut T) -> &mut DTraceConnection<C>,
+        callback: F,
+    ) -> Result<R, E>
+    where
+        F: FnOnce(&mut T) -> Result<R, E>,
+        E: From<diesel::result::Error>,
+    {
+        let depth = Self::depth(dtrace(conn));
+        Self::begin_transaction(dtrace(conn))?;
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| callback(&mut *conn))) {
+            Ok(result) => result,
+            Err(payload) => {
+                Self::done(dtrace(conn), depth, false, TransactionDoneReason::Panic);
+                panic::resume_unwind(payload);
+            }
+        };
+        let conn = dtrace(conn);
+        match result {
+            Ok(value) => {
+                Self::commit_transaction(conn)?;
+                Ok(value)
+            }
+            Err(user_error) => match Self::rollback(conn, TransactionDoneReason::ErrorRollback) {
+                Ok(()) => Err(user_error),
+                Err(diesel::result::Error::BrokenTransactionManager) => {
+                    // As in diesel, the original error is more interesting
+                    // than the fact that the rollback failed.
+                    Err(user_error)
+                }
+                Err(rollback_error) => Err(rollback_error.into()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{real_clock, MockConnection, MockedClock, Recorded, Recorder};
+    use diesel::connection::{Instrumentation, InstrumentationEvent};
+    use diesel::pg::Pg;
+    use diesel::query_builder::AstPass;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    fn connection() -> DTraceConnection<MockConnection> {
+        DTraceConnection::establish_with_config("", Config::new()).unwrap()
+    }
+
+    #[test]
+    fn transactions_nested_in_a_test_transaction_start_at_depth_one() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection();
+        conn.begin_test_transaction().unwrap();
+        conn.transaction(|conn| conn.transaction(|_| Ok::<_, diesel::result::Error>(())))
+            .unwrap();
+        let done = |depth| Recorded::TransactionDone {
+            depth,
+            committed: true,
+            reason: TransactionDoneReason::Commit,
+        };
+        assert_eq!(
+            recorder.transactions(conn.id()),
+            [
+                Recorded::TransactionStart { depth: 0 },
+                Recorded::TransactionStart { depth: 1 },
+                Recorded::TransactionStart { depth: 2 },
+                done(2),
+                done(1),
+            ]
+        );
+    }
+
+    /// Ignores every event.
+    struct Ignore;
+
+    impl Instrumentation for Ignore {
+        fn on_connection_event(&mut self, _: InstrumentationEvent<'_>) {}
+    }
+
+    #[test]
+    fn probes_fire_whatever_the_instrumentation() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection();
+        conn.batch_execute("SELECT 1").unwrap();
+        conn.set_instrumentation(Ignore);
+        conn.batch_execute("SELECT 2").unwrap();
+        // Disable the inner connection's instrumentation altogether, including
+        // the one counting its statement cache.
+        (*conn).set_instrumentation(None::<Box<dyn Instrumentation>>);
+        conn.batch_execute("SELECT 3").unwrap();
+        let queries: Vec<_> = recorder
+            .queries(conn.id())
+            .into_iter()
+            .map(|(query, _, _)| query)
+            .collect();
+        assert_eq!(queries, ["SELECT 1", "SELECT 2", "SELECT 3"]);
+    }
+
+    /// A statement that counts the times it is rendered.
+    #[derive(Default)]
+    struct Counted(AtomicUsize);
+
+    impl QueryFragment<Pg> for Counted {
+        fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            out.push_sql("UPDATE t SET x = 1");
+            Ok(())
+        }
+    }
+
+    impl QueryId for Counted {
+        type QueryId = ();
+        const HAS_STATIC_QUERY_ID: bool = false;
+    }
+
+    #[test]
+    fn query_text_is_rendered_once() {
+        let _clock = real_clock();
+        let statement = Counted::default();
+        <Pg as RenderQuery>::render(&statement);
+        let once = statement.0.swap(0, Ordering::Relaxed);
+        // Observers, coalescing and the formatting measurement all need the
+        // text.
+        let recorder = Recorder::start();
+        let config = Config::new()
+            .coalesce_repeated_queries(true)
+            .measure_query_formatting(true);
+        let mut conn =
+            DTraceConnection::<MockConnection>::establish_with_config("", config).unwrap();
+        conn.execute_returning_count(&statement).unwrap();
+        assert_eq!(recorder.queries(conn.id()).len(), 1);
+        assert_eq!(statement.0.load(Ordering::Relaxed), once);
+    }
+
+    #[test]
+    fn each_entry_point_classifies_its_query() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection();
+        drop(LoadConnection::load(&mut conn, diesel::sql_query("SELECT 1")).unwrap());
+        conn.execute_returning_count(&diesel::sql_query("UPDATE t SET x = 1"))
+            .unwrap();
+        conn.batch_execute("DELETE FROM t; SELECT 1").unwrap();
+        let classified: Vec<_> = recorder
+            .queries(conn.id())
+            .into_iter()
+            .map(|(_, kind, method)| (kind, method))
+            .collect();
+        assert_eq!(
+            classified,
+            [
+                (QueryKind::Select, QueryMethod::Load),
+                (QueryKind::Update, QueryMethod::Execute),
+                (QueryKind::Delete, QueryMethod::BatchExecute),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_panic_in_a_transaction_completes_it_once() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn = connection();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            conn.transaction(|_| -> QueryResult<()> { panic!("oops") })
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            recorder.transactions(conn.id()),
+            [
+                Recorded::TransactionStart { depth: 0 },
+                Recorded::TransactionDone {
+                    depth: 0,
+                    committed: false,
+                    reason: TransactionDoneReason::Panic,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_cursor_that_yields_an_error_fails_the_query() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let config = Config::new().query_done_semantics(QueryDoneSemantics::OnCursorDrain);
+        let mut conn =
+            DTraceConnection::<MockConnection>::establish_with_config("", config).unwrap();
+        (*conn).fail_rows = true;
+        let rows: Vec<_> = LoadConnection::load(&mut conn, diesel::sql_query("SELECT 1"))
+            .unwrap()
+            .collect();
+        assert!(matches!(rows[..], [Err(_)]));
+        let done: Vec<_> = recorder
+            .events(conn.id())
+            .into_iter()
+            .filter(|event| matches!(event, Recorded::QueryDone { .. }))
+            .collect();
+        assert_eq!(done, [Recorded::QueryDone { ok: false }]);
+    }
+
+    #[test]
+    fn queries_over_the_threshold_are_slow() {
+        let mocked = MockedClock::install();
+        let clock = mocked.clock.clone();
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let config = Config::new()
+            .slow_query_threshold(Duration::from_millis(100))
+            .on_slow_query({
+                let slow = slow.clone();
+                move |event| {
+                    let event = (event.query.to_string(), event.duration);
+                    slow.lock().unwrap().push(event);
+                }
+            });
+        let mut conn =
+            DTraceConnection::<MockConnection>::establish_with_config("", config).unwrap();
+        // Each statement takes as long as the next duration.
+        let mut durations = [150, 50, 100].map(Duration::from_millis).into_iter();
+        (*conn).on_statement = Some(Box::new(move || {
+            clock.advance(durations.next().unwrap());
+        }));
+        conn.batch_execute("SELECT 1").unwrap();
+        conn.batch_execute("SELECT 2").unwrap();
+        conn.batch_execute("SELECT 3").unwrap();
+        assert_eq!(
+            *slow.lock().unwrap(),
+            [("SELECT 1".to_string(), Duration::from_millis(150))]
+        );
+    }
+
+    #[test]
+    fn sqlstate_tells_deadlocks_from_serialization_failures() {
+        let error = |kind, message: &str| {
+            diesel::result::Error::DatabaseError(kind, Box::new(message.to_string()))
+        };
+        assert_eq!(
+            sqlstate(&error(
+                DatabaseErrorKind::SerializationFailure,
+                "could not serialize access due to concurrent update"
+            )),
+            "40001"
+        );
+        assert_eq!(
+            sqlstate(&error(DatabaseErrorKind::Unknown, "deadlock detected")),
+            "40P01"
+        );
+        assert_eq!(
+            sqlstate(&error(DatabaseErrorKind::UniqueViolation, "duplicate key")),
+            "23505"
+        );
+        assert_eq!(
+            sqlstate(&error(DatabaseErrorKind::Unknown, "syntax error")),
+            ""
+        );
+        assert_eq!(sqlstate(&diesel::result::Error::NotFound), "");
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connections whose instrumentation is chosen at runtime.
+
+use crate::config;
+use crate::DTraceConnection;
+use crate::DTraceCursor;
+use crate::DTraceTransactionManager;
+use crate::RenderQuery;
+use diesel::connection::{
+    AnsiTransactionManager, Connection, ConnectionSealed, Instrumentation, LoadConnection,
+    SimpleConnection, TransactionManager, TransactionManagerStatus,
+};
+use diesel::expression::QueryMetadata;
+use diesel::query_builder::{Query, QueryFragment, QueryId};
+use diesel::r2d2::R2D2Connection;
+use diesel::result::{ConnectionResult, QueryResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether [`MaybeInstrumented`] connections established through
+/// [`diesel::Connection::establish`] are instrumented.
+static INSTRUMENT: AtomicBool = AtomicBool::new(true);
+
+/// Set whether [`MaybeInstrumented`] connections established through
+/// [`diesel::Connection::establish`], such as those created by a connection
+/// pool, are instrumented.
+///
+/// This only affects connections established after the call. The default is
+/// `true`.
+pub fn set_instrument_connections(instrument: bool) {
+    INSTRUMENT.store(instrument, Ordering::Relaxed);
+}
+
+/// A connection that is either a [`DTraceConnection`], or the bare connection
+/// it would wrap.
+///
+/// Choosing between `DTraceConnection<C>` and `C` at runtime is awkward,
+/// since they are different types, and the connection type is usually named
+/// throughout an application, e.g., as the connection type of its pool. This
+/// is a single type for both, which implements the same traits as a
+/// `DTraceConnection` by delegating to whichever connection it holds. A bare
+/// connection fires no probes at all.
+///
+/// [`Connection::establish`](diesel::Connection::establish) instruments the
+/// connection unless [`set_instrument_connections(false)`] was called, e.g.,
+/// depending on the environment at startup.
+/// [`MaybeInstrumented::establish_with`] chooses for a single connection.
+///
+/// [`set_instrument_connections(false)`]: set_instrument_connections
+#[derive(Debug)]
+pub enum MaybeInstrumented<C: Connection> {
+    /// An instrumented connection.
+    Instrumented(DTraceConnection<C>),
+    /// A connection that isn't instrumented.
+    Bare(C),
+}
+
+impl<C> MaybeInstrumented<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    /// Establish a connection, instrumented if `instrument` is true.
+    ///
+    /// An instrumented connection uses the process-wide default
+    /// configuration, as with [`Connection::establish`].
+    pub fn establish_with(database_url: &str, instrument: bool) -> ConnectionResult<Self> {
+        if instrument {
+            DTraceConnection::establish_with_config(database_url, config::default_config())
+                .map(Self::Instrumented)
+        } else {
+            C::establish(database_url).map(Self::Bare)
+        }
+    }
+}
+
+impl<C: Connection> MaybeInstrumented<C> {
+    /// Return the instrumented connection, if this is one.
+    pub fn as_instrumented(&self) -> Option<&DTraceConnection<C>> {
+        match self {
+            Self::Instrumented(conn) => Some(conn),
+            Self::Bare(_) => None,
+        }
+    }
+
+    /// Return the instrumented connection mutably, if this is one.
+    pub fn as_instrumented_mut(&mut self) -> Option<&mut DTraceConnection<C>> {
+        match self {
+            Self::Instrumented(conn) => Some(conn),
+            Self::Bare(_) => None,
+        }
+    }
+
+    /// Return the connection being wrapped, whether it's instrumented or not.
+    ///
+    /// Queries issued directly on the inner connection of an instrumented one
+    /// fire no probes.
+    pub fn inner_mut(&mut self) -> &mut C {
+        match self {
+            Self::Instrumented(conn) => conn,
+            Self::Bare(conn) => conn,
+        }
+    }
+}
+
+impl<C> From<DTraceConnection<C>> for MaybeInstrumented<C>
+where
+    C: Connection,
+{
+    fn from(conn: DTraceConnection<C>) -> Self {
+        Self::Instrumented(conn)
+    }
+}
+
+impl<C> SimpleConnection for MaybeInstrumented<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        match self {
+            Self::Instrumented(conn) => conn.batch_execute(query),
+            Self::Bare(conn) => conn.batch_execute(query),
+        }
+    }
+}
+
+impl<C> ConnectionSealed for MaybeInstrumented<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+}
+
+impl<C> Connection for MaybeInstrumented<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    type Backend = C::Backend;
+    type TransactionManager = MaybeTransactionManager<C>;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Self::establish_with(database_url, INSTRUMENT.load(Ordering::Relaxed))
+    }
+
+    fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Self::Backend> + QueryId,
+    {
+        match self {
+            Self::Instrumented(conn) => conn.execute_returning_count(source),
+            Self::Bare(conn) => conn.execute_returning_count(source),
+        }
+    }
+
+    fn transaction_state(
+        &mut self,
+    ) -> &mut <MaybeTransactionManager<C> as TransactionManager<Self>>::TransactionStateData {
+        match self {
+            Self::Instrumented(conn) => conn.transaction_state(),
+            Self::Bare(conn) => conn.transaction_state(),
+        }
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        match self {
+            Self::Instrumented(conn) => conn.instrumentation(),
+            Self::Bare(conn) => conn.instrumentation(),
+        }
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        match self {
+            Self::Instrumented(conn) => conn.set_instrumentation(instrumentation),
+            Self::Bare(conn) => conn.set_instrumentation(instrumentation),
+        }
+    }
+}
+
+impl<C> LoadConnection for MaybeInstrumented<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager> + LoadConnection,
+    C::Backend: RenderQuery,
+{
+    type Cursor<'conn, 'query>
+        = DTraceCursor<C::Cursor<'conn, 'query>>
+    where
+        Self: 'conn;
+    type Row<'conn, 'query>
+        = C::Row<'conn, 'query>
+    where
+        Self: 'conn;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
+    where
+        T: Query + QueryFragment<Self::Backend> + QueryId + 'query,
+        Self::Backend: QueryMetadata<T::SqlType>,
+    {
+        match self {
+            Self::Instrumented(conn) => conn.load(source),
+            Self::Bare(conn) => conn.load(source).map(DTraceCursor::bare),
+        }
+    }
+}
+
+impl<C> R2D2Connection for MaybeInstrumented<C>
+where
+    C: R2D2Connection + Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    fn ping(&mut self) -> QueryResult<()> {
+        match self {
+            Self::Instrumented(conn) => conn.ping(),
+            Self::Bare(conn) => conn.ping(),
+        }
+    }
+
+    fn is_broken(&mut self) -> bool {
+        match self {
+            Self::Instrumented(conn) => conn.is_broken(),
+            Self::Bare(conn) => conn.is_broken(),
+        }
+    }
+}
+
+/// A [`TransactionManager`] for a [`MaybeInstrumented`] connection.
+///
+/// This delegates to the [`DTraceTransactionManager`] of an instrumented
+/// connection, which fires the transaction probes, and to the
+/// [`AnsiTransactionManager`] of a bare one.
+pub struct MaybeTransactionManager<C> {
+    _data: std::marker::PhantomData<C>,
+}
+
+/// Return the instrumented connection of `conn`, which must be one.
+fn instrumented<C: Connection>(conn: &mut MaybeInstrumented<C>) -> &mut DTraceConnection<C> {
+    conn.as_instrumented_mut()
+        .expect("the connection is instrumented")
+}
+
+impl<C> TransactionManager<MaybeInstrumented<C>> for MaybeTransactionManager<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+    C::Backend: RenderQuery,
+{
+    type TransactionStateData = AnsiTransactionManager;
+
+    fn begin_transaction(conn: &mut MaybeInstrumented<C>) -> QueryResult<()> {
+        match conn {
+            MaybeInstrumented::Instrumented(conn) => {
+                DTraceTransactionManager::<C>::begin_transaction(conn)
+            }
+            MaybeInstrumented::Bare(conn) => AnsiTransactionManager::begin_transaction(conn),
+        }
+    }
+
+    fn rollback_transaction(conn: &mut MaybeInstrumented<C>) -> QueryResult<()> {
+        match conn {
+            MaybeInstrumented::Instrumented(conn) => {
+                DTraceTransactionManager::<C>::rollback_transaction(conn)
+            }
+            MaybeInstrumented::Bare(conn) => AnsiTransactionManager::rollback_transaction(conn),
+        }
+    }
+
+    fn commit_transaction(conn: &mut MaybeInstrumented<C>) -> QueryResult<()> {
+        match conn {
+            MaybeInstrumented::Instrumented(conn) => {
+                DTraceTransactionManager::<C>::commit_transaction(conn)
+            }
+            MaybeInstrumented::Bare(conn) => AnsiTransactionManager::commit_transaction(conn),
+        }
+    }
+
+    // An instrumented connection runs the transaction as its own manager
+    // does, so that rollbacks and panics are reported the same way.
+    fn transaction<F, R, E>(conn: &mut MaybeInstrumented<C>, callback: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MaybeInstrumented<C>) -> Result<R, E>,
+        E: From<diesel::result::Error>,
+    {
+        if conn.as_instrumented().is_some() {
+            return DTraceTransactionManager::<C>::run_transaction(conn, instrumented, callback);
+        }
+        Self::begin_transaction(conn)?;
+        match callback(&mut *conn) {
+            Ok(value) => {
+                Self::commit_transaction(conn)?;
+                Ok(value)
+            }
+            Err(user_error) => match Self::rollback_transaction(conn) {
+                Ok(()) | Err(diesel::result::Error::BrokenTransactionManager) => Err(user_error),
+                Err(rollback_error) => Err(rollback_error.into()),
+            },
+        }
+    }
+
+    fn transaction_manager_status_mut(
+        conn: &mut MaybeInstrumented<C>,
+    ) -> &mut TransactionManagerStatus {
+        match conn {
+            MaybeInstrumented::Instrumented(conn) => {
+                DTraceTransactionManager::<C>::transaction_manager_status_mut(conn)
+            }
+            MaybeInstrumented::Bare(conn) => {
+                AnsiTransactionManager::transaction_manager_status_mut(conn)
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A connection that needs no database, and a recorder of the events the
+//! probes report, for the crate's tests.
+//!
+//! The probes themselves can't be observed from within the process, so the
+//! tests check the events reported to observers instead, which are reported
+//! at the same points, with the same values.
+
+use crate::clock::{self, MockClock};
+use crate::observer::{self, Event};
+use crate::QueryKind;
+use crate::QueryMethod;
+use crate::TransactionDoneReason;
+use diesel::connection::{
+    AnsiTransactionManager, Connection, ConnectionSealed, Instrumentation, LoadConnection,
+    SimpleConnection,
+};
+use diesel::expression::QueryMetadata;
+use diesel::pg::{Pg, PgConnection};
+use diesel::query_builder::{Query, QueryFragment, QueryId};
+use diesel::result::{ConnectionResult, Error, QueryResult};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+/// A PostgreSQL connection that completes every statement immediately,
+/// without a database.
+///
+/// Statements affect no rows, and queries return none.
+#[derive(Default)]
+pub(crate) struct MockConnection {
+    transaction_manager: AnsiTransactionManager,
+    instrumentation: Option<Box<dyn Instrumentation>>,
+    /// Called as each statement is issued, e.g., to advance a mock clock.
+    pub(crate) on_statement: Option<Box<dyn FnMut() + Send>>,
+    /// Whether the cursor of each load yields an error, rather than no rows.
+    pub(crate) fail_rows: bool,
+}
+
+impl MockConnection {
+    fn statement(&mut self) {
+        if let Some(on_statement) = &mut self.on_statement {
+            on_statement();
+        }
+    }
+}
+
+impl SimpleConnection for MockConnection {
+    fn batch_execute(&mut self, _query: &str) -> QueryResult<()> {
+        self.statement();
+        Ok(())
+    }
+}
+
+impl ConnectionSealed for MockConnection {}
+
+impl Connection for MockConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(_database_url: &str) -> ConnectionResult<Self> {
+        Ok(Self::default())
+    }
+
+    fn execute_returning_count<T>(&mut self, _source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        self.statement();
+        Ok(0)
+    }
+
+    fn transaction_state(&mut self) -> &mut AnsiTransactionManager {
+        &mut self.transaction_manager
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        &mut self.instrumentation
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.instrumentation = Some(Box::new(instrumentation));
+    }
+}
+
+impl LoadConnection for MockConnection {
+    type Cursor<'conn, 'query>
+        = std::vec::IntoIter<QueryResult<Self::Row<'conn, 'query>>>
+    where
+        Self: 'conn;
+    type Row<'conn, 'query>
+        = <PgConnection as LoadConnection>::Row<'conn, 'query>
+    where
+        Self: 'conn;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        _source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
+    where
+        T: Query + QueryFragment<Pg> + QueryId + 'query,
+        Pg: QueryMetadata<T::SqlType>,
+    {
+        self.statement();
+        let rows = if self.fail_rows {
+            vec![Err(Error::DeserializationError(
+                "the row is corrupt".into(),
+            ))]
+        } else {
+            Vec::new()
+        };
+        Ok(rows.into_iter())
+    }
+}
+
+/// An event reported to observers, without the borrowed parts.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Recorded {
+    Establish {
+        ok: bool,
+    },
+    QueryStart {
+        query: String,
+        kind: QueryKind,
+        method: QueryMethod,
+    },
+    QueryDone {
+        ok: bool,
+    },
+    TransactionStart {
+        depth: i64,
+    },
+    TransactionDone {
+        depth: i64,
+        committed: bool,
+        reason: TransactionDoneReason,
+    },
+}
+
+impl Recorded {
+    fn from_event(event: &Event<'_>) -> Option<(Uuid, Self)> {
+        let recorded = match *event {
+            Event::ConnectionEstablish {
+                conn_id, result, ..
+            } => (conn_id, Recorded::Establish { ok: result.is_ok() }),
+            Event::QueryStart(event) => (
+                event.conn_id,
+                Recorded::QueryStart {
+                    query: event.query.to_string(),
+                    kind: event.kind,
+                    method: event.method,
+                },
+            ),
+            Event::QueryDone(event) => (
+                event.conn_id,
+                Recorded::QueryDone {
+                    ok: event.result.is_ok(),
+                },
+            ),
+            Event::TransactionStart { conn_id, depth } => {
+                (conn_id, Recorded::TransactionStart { depth })
+            }
+            Event::TransactionDone {
+                conn_id,
+                depth,
+                committed,
+                reason,
+            } => (
+                conn_id,
+                Recorded::TransactionDone {
+                    depth,
+                    committed,
+                    reason,
+                },
+            ),
+            _ => return None,
+        };
+        Some(recorded)
+    }
+}
+
+/// Records the events reported to observers from the time it is started.
+///
+/// Tests run concurrently, and each recorder sees the events of every
+/// connection in the process, so tests only look at the events of their own
+/// connections.
+#[derive(Clone, Default)]
+pub(crate) struct Recorder {
+    events: Arc<Mutex<Vec<(Uuid, Recorded)>>>,
+}
+
+impl Recorder {
+    /// Start recording events.
+    pub(crate) fn start() -> Self {
+        let recorder = Self::default();
+        let events = recorder.events.clone();
+        observer::on_event(move |event| {
+            if let Some(recorded) = Recorded::from_event(event) {
+                events
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(recorded);
+            }
+        });
+        recorder
+    }
+
+    /// Return the events recorded for the connection `conn_id`, in order.
+    pub(crate) fn events(&self, conn_id: Uuid) -> Vec<Recorded> {
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(id, _)| *id == conn_id)
+            .map(|(_, recorded)| recorded.clone())
+            .collect()
+    }
+
+    /// Return the queries started on the connection `conn_id`, in order.
+    pub(crate) fn queries(&self, conn_id: Uuid) -> Vec<(String, QueryKind, QueryMethod)> {
+        self.events(conn_id)
+            .into_iter()
+            .filter_map(|recorded| match recorded {
+                Recorded::QueryStart {
+                    query,
+                    kind,
+                    method,
+                } => Some((query, kind, method)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return the transaction events of the connection `conn_id`, in order.
+    pub(crate) fn transactions(&self, conn_id: Uuid) -> Vec<Recorded> {
+        self.events(conn_id)
+            .into_iter()
+            .filter(|recorded| {
+                matches!(
+                    recorded,
+                    Recorded::TransactionStart { .. } | Recorded::TransactionDone { .. }
+                )
+            })
+            .collect()
+    }
+}
+
+/// Serializes the tests that replace the clock with those that read it.
+///
+/// The clock is process-wide, and tests run concurrently, so a test that
+/// installs a [`MockClock`] would otherwise move the clock of every other test
+/// running at the time.
+static CLOCK: RwLock<()> = RwLock::new(());
+
+/// Wait until no test has replaced the clock, and keep it unreplaced while
+/// the guard is alive.
+///
+/// Every test that establishes a connection, issues queries or checks out
+/// connections reads the clock, and must hold this for as long as it does.
+pub(crate) fn real_clock() -> RwLockReadGuard<'static, ()> {
+    CLOCK.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A [`MockClock`] installed for the duration of a test, while no other test
+/// reads the clock, and restored to the default clock when dropped.
+pub(crate) struct MockedClock {
+    pub(crate) clock: Arc<MockClock>,
+    _exclusive: RwLockWriteGuard<'static, ()>,
+}
+
+impl MockedClock {
+    /// Wait until no other test is reading the clock, and install a mock one.
+    pub(crate) fn install() -> Self {
+        let exclusive = CLOCK.write().unwrap_or_else(PoisonError::into_inner);
+        let clock = Arc::new(MockClock::new());
+        clock::set_clock(clock.clone());
+        Self {
+            clock,
+            _exclusive: exclusive,
+        }
+    }
+}
+
+impl Drop for MockedClock {
+    fn drop(&mut self) {
+        clock::reset_clock();
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Observing the events the probes report from within the process.
+//!
+//! Events reach two kinds of consumers: the [`Observer`] registered with
+//! [`set_global_observer`], and the callbacks registered with [`on_event`],
+//! which receive each one as an [`Event`]. The crate reports every event once,
+//! at the point where it fires the corresponding probe, through `notify`,
+//! which passes it to both.
+//!
+//! The events are one of several sinks fed from those points, rather than the
+//! source the others are built on. Each kind of event is emitted from one
+//! place: `DTraceConnection::start_query` as a query starts,
+//! `PendingQuery::done` however it completes, `transaction_done` however a
+//! transaction completes, cancellation included, and so on. That place fires
+//! the probe, and then feeds the observers, the `tracing` span, the Prometheus
+//! counters, the Chrome trace, and the history of recent events in turn, so
+//! that they all see the same events. Those sinks don't consume `Event`s,
+//! because several of them need values the events don't carry, such as the
+//! text of a query as it completes, and because the probes' arguments are only
+//! computed while they're enabled.
+
+use crate::QueryKind;
+use crate::QueryMethod;
+use crate::TransactionDoneReason;
+use diesel::result::{ConnectionError, Error};
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+#[cfg(feature = "wall-clock")]
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Receives the events reported by the probes, as Rust values.
+///
+/// An observer is registered for the whole process with
+/// [`set_global_observer`]. Its methods are called for the events of every
+/// [`DTraceConnection`](crate::DTraceConnection), whether or not DTrace is
+/// tracing the corresponding probes, which still fire as usual. Each method is
+/// named after its probe, and does nothing by default, so an observer only
+/// implements those it is interested in. Only the probes marking the
+/// lifecycle of connections, queries, and transactions are observed.
+///
+/// The methods are called synchronously on the thread issuing the query, so
+/// they should be cheap. A panic in an observer is caught and discarded.
+#[allow(unused_variables)]
+pub trait Observer: Send + Sync + 'static {
+    /// Called when an attempt to establish a connection completes, like the
+    /// `connection-establish-done` probe, with the error if it failed, and
+    /// how long the attempt took.
+    fn connection_establish_done(
+        &self,
+        conn_id: Uuid,
+        result: Result<(), EstablishError<'_>>,
+        duration: Duration,
+    ) {
+    }
+
+    /// Called when a connection is closed, like the `connection-close` probe,
+    /// with the number of queries it issued, and its age.
+    fn connection_close(&self, conn_id: Uuid, query_count: u64, age: Duration) {}
+
+    /// Called just before a query is issued, like the `query-start` probe.
+    fn query_start(&self, event: &QueryStartEvent<'_>) {}
+
+    /// Called when a query completes, like the `query-done` probe.
+    fn query_done(&self, event: &QueryDoneEvent<'_>) {}
+
+    /// Called when a transaction starts, like the `transaction-start` probe,
+    /// with the depth of the transaction.
+    fn transaction_start(&self, conn_id: Uuid, depth: i64) {}
+
+    /// Called when a transaction completes, like the `transaction-done`
+    /// probe, with its depth, whether it was committed, and why it completed.
+    fn transaction_done(
+        &self,
+        conn_id: Uuid,
+        depth: i64,
+        committed: bool,
+        reason: TransactionDoneReason,
+    ) {
+    }
+}
+
+/// A query about to be issued, passed to [`Observer::query_start`].
+#[derive(Clone, Copy, Debug)]
+pub struct QueryStartEvent<'a> {
+    /// The ID of the query, as passed to the `query-*` probes.
+    pub id: u64,
+    /// The ID of the connection issuing the query.
+    pub conn_id: Uuid,
+    /// The text of the query, as passed to the `query-start` probe.
+    pub query: &'a str,
+    /// The kind of statement.
+    pub kind: QueryKind,
+    /// The diesel method issuing the query.
+    pub method: QueryMethod,
+    /// Whether the query is issued inside an open transaction.
+    pub in_transaction: bool,
+    /// The wall-clock time the query started, to correlate it with logs from
+    /// other sources.
+    #[cfg(feature = "wall-clock")]
+    pub timestamp: SystemTime,
+}
+
+/// A query that completed, passed to [`Observer::query_done`].
+#[derive(Clone, Copy, Debug)]
+pub struct QueryDoneEvent<'a> {
+    /// The ID of the query, as passed to the `query-*` probes.
+    pub id: u64,
+    /// The ID of the connection that issued the query.
+    pub conn_id: Uuid,
+    /// The number of rows, if that is known, or the error the query failed
+    /// with.
+    ///
+    /// The number of rows is known in the same cases as for the `query-done`
+    /// probe.
+    pub result: Result<Option<u64>, &'a Error>,
+    /// How long the query took.
+    pub duration: Duration,
+    /// The wall-clock time the query completed.
+    ///
+    /// This is read separately from the monotonic clock `duration` is
+    /// measured with, so it shouldn't be used to compute durations.
+    #[cfg(feature = "wall-clock")]
+    pub timestamp: SystemTime,
+    /// A backtrace of the code that issued the query, if one was captured,
+    /// see [`Config::capture_backtraces`](crate::Config::capture_backtraces).
+    #[cfg(feature = "backtrace")]
+    pub backtrace: Option<&'a Backtrace>,
+}
+
+/// The error an attempt to establish a connection failed with, passed to
+/// [`Observer::connection_establish_done`].
+#[derive(Clone, Copy, Debug)]
+pub enum EstablishError<'a> {
+    /// An error from diesel.
+    Connection(&'a ConnectionError),
+    /// Any other error, e.g., from the connection manager wrapped by a
+    /// [`DTraceConnectionManager`](crate::DTraceConnectionManager), as its
+    /// message.
+    Other(&'a str),
+}
+
+impl fmt::Display for EstablishError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EstablishError::Connection(error) => error.fmt(f),
+            EstablishError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+/// An event reported by the probes, passed to the callbacks registered with
+/// [`on_event`].
+///
+/// Each variant corresponds to a method of [`Observer`], and carries the same
+/// values, except that a query that fails is reported as a
+/// [`Event::QueryError`], followed by its [`Event::QueryDone`], as the probes
+/// do.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// An attempt to establish a connection completed.
+    ConnectionEstablish {
+        /// The ID of the connection.
+        conn_id: Uuid,
+        /// The error establishing the connection failed with, if any.
+        result: Result<(), EstablishError<'a>>,
+        /// How long the attempt took.
+        duration: Duration,
+    },
+    /// A connection was closed.
+    ConnectionClose {
+        /// The ID of the connection.
+        conn_id: Uuid,
+        /// The number of queries the connection issued.
+        query_count: u64,
+        /// The age of the connection.
+        age: Duration,
+    },
+    /// A query is about to be issued.
+    QueryStart(QueryStartEvent<'a>),
+    /// A query failed, just before its [`Event::QueryDone`].
+    QueryError {
+        /// The ID of the query.
+        id: u64,
+        /// The ID of the connection that issued the query.
+        conn_id: Uuid,
+        /// The error the query failed with.
+        error: &'a Error,
+    },
+    /// A query completed.
+    QueryDone(QueryDoneEvent<'a>),
+    /// A transaction started.
+    TransactionStart {
+        /// The ID of the connection.
+        conn_id: Uuid,
+        /// The depth of the transaction.
+        depth: i64,
+    },
+    /// A transaction completed.
+    TransactionDone {
+        /// The ID of the connection.
+        conn_id: Uuid,
+        /// The depth of the transaction.
+        depth: i64,
+        /// Whether the transaction was committed.
+        committed: bool,
+        /// Why the transaction completed.
+        reason: TransactionDoneReason,
+    },
+}
+
+type EventCallback = Arc<dyn Fn(&Event<'_>) + Send + Sync>;
+
+/// Whether an observer is registered, so that queries can skip taking the
+/// lock when there is none.
+static OBSERVING: AtomicBool = AtomicBool::new(false);
+
+static OBSERVER: RwLock<Option<Arc<dyn Observer>>> = RwLock::new(None);
+
+/// Whether any callback is registered with [`on_event`].
+static CONSUMING: AtomicBool = AtomicBool::new(false);
+
+static CALLBACKS: RwLock<Option<Arc<[EventCallback]>>> = RwLock::new(None);
+
+/// Register `observer` to receive the events of every connection in the
+/// process, replacing any observer registered before.
+pub fn set_global_observer(observer: Arc<dyn Observer>) {
+    let mut current = OBSERVER.write().unwrap_or_else(PoisonError::into_inner);
+    *current = Some(observer);
+    OBSERVING.store(true, Ordering::Relaxed);
+}
+
+/// Unregister the observer set with [`set_global_observer`], if any.
+pub fn clear_global_observer() {
+    let mut current = OBSERVER.write().unwrap_or_else(PoisonError::into_inner);
+    *current = None;
+    OBSERVING.store(false, Ordering::Relaxed);
+}
+
+/// Register `callback` to receive the events of every connection in the
+/// process, as [`Event`]s.
+///
+/// Any number of callbacks can be registered, and each receives every event,
+/// in addition to the observer set with [`set_global_observer`], if any. This
+/// is the simplest way to feed the events into a pipeline that handles them
+/// all alike, e.g., by forwarding them to a channel. As with an observer,
+/// callbacks are called synchronously on the thread issuing the query, and a
+/// panic in one is caught and discarded.
+pub fn on_event<F>(callback: F)
+where
+    F: Fn(&Event<'_>) + Send + Sync + 'static,
+{
+    let mut callbacks = CALLBACKS.write().unwrap_or_else(PoisonError::into_inner);
+    let mut updated = callbacks.as_deref().map_or_else(Vec::new, <[_]>::to_vec);
+    updated.push(Arc::new(callback));
+    *callbacks = Some(updated.into());
+    CONSUMING.store(true, Ordering::Relaxed);
+}
+
+/// Unregister all the callbacks registered with [`on_event`].
+pub fn clear_event_callbacks() {
+    let mut callbacks = CALLBACKS.write().unwrap_or_else(PoisonError::into_inner);
+    *callbacks = None;
+    CONSUMING.store(false, Ordering::Relaxed);
+}
+
+/// Return true if an observer or an event callback is registered.
+pub(crate) fn enabled() -> bool {
+    OBSERVING.load(Ordering::Relaxed) || CONSUMING.load(Ordering::Relaxed)
+}
+
+/// Pass an event to the registered observer and event callbacks, if any,
+/// with `f`.
+pub(crate) fn notify(f: impl FnOnce(&dyn Observer)) {
+    if !enabled() {
+        return;
+    }
+    // Don't hold the locks while the consumers run, in case they register
+    // others.
+    let dispatch = Dispatch {
+        observer: OBSERVER
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone(),
+        callbacks: CALLBACKS
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone(),
+    };
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| f(&dispatch)));
+}
+
+/// Passes each event to the registered observer, and then to each event
+/// callback, as an [`Event`].
+struct Dispatch {
+    observer: Option<Arc<dyn Observer>>,
+    callbacks: Option<Arc<[EventCallback]>>,
+}
+
+impl Dispatch {
+    fn observe(&self, f: impl FnOnce(&dyn Observer)) {
+        if let Some(observer) = &self.observer {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| f(&**observer)));
+        }
+    }
+
+    fn emit(&self, event: Event<'_>) {
+        for callback in self.callbacks.iter().flat_map(|callbacks| callbacks.iter()) {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| callback(&event)));
+        }
+    }
+}
+
+impl Observer for Dispatch {
+    fn connection_establish_done(
+        &self,
+        conn_id: Uuid,
+        result: Result<(), EstablishError<'_>>,
+        duration: Duration,
+    ) {
+        self.observe(|observer| observer.connection_establish_done(conn_id, result, duration));
+        self.emit(Event::ConnectionEstablish {
+            conn_id,
+            result,
+            duration,
+        });
+    }
+
+    fn connection_close(&self, conn_id: Uuid, query_count: u64, age: Duration) {
+        self.observe(|observer| observer.connection_close(conn_id, query_count, age));
+        self.emit(Event::ConnectionClose {
+            conn_id,
+            query_count,
+            age,
+        });
+    }
+
+    fn query_start(&self, event: &QueryStartEvent<'_>) {
+        self.observe(|observer| observer.query_start(event));
+        self.emit(Event::QueryStart(*event));
+    }
+
+    fn query_done(&self, event: &QueryDoneEvent<'_>) {
+        self.observe(|observer| observer.query_done(event));
+        if let Err(error) = event.result {
+            self.emit(Event::QueryError {
+                id: event.id,
+                conn_id: event.conn_id,
+                error,
+            });
+        }
+        self.emit(Event::QueryDone(*event));
+    }
+
+    fn transaction_start(&self, conn_id: Uuid, depth: i64) {
+        self.observe(|observer| observer.transaction_start(conn_id, depth));
+        self.emit(Event::TransactionStart { conn_id, depth });
+    }
+
+    fn transaction_done(
+        &self,
+        conn_id: Uuid,
+        depth: i64,
+        committed: bool,
+        reason: TransactionDoneReason,
+    ) {
+        self.observe(|observer| observer.transaction_done(conn_id, depth, committed, reason));
+        self.emit(Event::TransactionDone {
+            conn_id,
+            depth,
+            committed,
+            reason,
+        });
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OpenTelemetry spans for each query.
+//!
+//! With the `opentelemetry` feature, each query creates a span from the global
+//! tracer provider, as a child of the current OpenTelemetry context. The span
+//! carries the `db.system`, `db.name`, and `db.statement` attributes from the
+//! OpenTelemetry semantic conventions for database clients. Without the
+//! feature, [`QuerySpan`] is a no-op.
+
+use std::borrow::Cow;
+
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    trace::{Span, SpanKind, Tracer},
+    Context, KeyValue,
+};
+
+/// The span covering a single query.
+#[cfg(feature = "opentelemetry")]
+pub(crate) struct QuerySpan(BoxedSpan);
+
+/// The span covering a single query.
+#[cfg(not(feature = "opentelemetry"))]
+pub(crate) struct QuerySpan;
+
+impl QuerySpan {
+    /// Start a span for a query against a database of type `B`.
+    ///
+    /// `statement` is only called if the span is actually recorded.
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) fn start<'a, B>(db_name: &str, statement: impl FnOnce() -> Cow<'a, str>) -> Self {
+        let tracer = global::tracer("diesel-dtrace");
+        let mut span = tracer
+            .span_builder("query")
+            .with_kind(SpanKind::Client)
+            .start_with_context(&tracer, &Context::current());
+        if span.is_recording() {
+            span.set_attribute(KeyValue::new("db.system", db_system::<B>()));
+            if !db_name.is_empty() {
+                span.set_attribute(KeyValue::new("db.name", db_name.to_string()));
+            }
+            span.set_attribute(KeyValue::new("db.statement", statement().into_owned()));
+        }
+        Self(span)
+    }
+
+    /// Start a span for a query against a database of type `B`.
+    #[cfg(not(feature = "opentelemetry"))]
+    pub(crate) fn start<'a, B>(_: &str, _: impl FnOnce() -> Cow<'a, str>) -> Self {
+        Self
+    }
+
+    /// End the span.
+    pub(crate) fn end(self) {
+        #[cfg(feature = "opentelemetry")]
+        {
+            let mut span = self.0;
+            span.end();
+        }
+    }
+}
+
+/// Return the `db.system` attribute for the backend `B`.
+#[cfg(feature = "opentelemetry")]
+fn db_system<B>() -> &'static str {
+    let name = std::any::type_name::<B>();
+    if name.contains("::pg::") {
+        "postgresql"
+    } else if name.contains("::mysql::") {
+        "mysql"
+    } else if name.contains("::sqlite::") {
+        "sqlite"
+    } else {
+        "other_sql"
+    }
+}
+
+/// Extract the name of the database from a connection URL, for the `db.name`
+/// attribute.
+///
+/// This is the path of a URL like `postgresql://host/name`, or the URL itself
+/// if it is not in that form, as for a SQLite database file. It is empty for a
+/// URL without a path.
+#[cfg(feature = "opentelemetry")]
+pub(crate) fn database_name(url: &str) -> String {
+    let Some((_scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let path = rest.split_once('/').map_or("", |(_authority, path)| path);
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    path.to_string()
+}
@@ -0,0 +1,480 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An r2d2 pool that hands out instrumented connections.
+
+use crate::clock;
+use crate::config::{self, Config};
+use crate::probes;
+use crate::query::duration_nanos;
+use crate::DTraceConnection;
+use crate::DTraceTransactionManager;
+use crate::RenderQuery;
+use diesel::connection::{AnsiTransactionManager, Connection};
+use diesel::r2d2::{
+    ConnectionManager, CustomizeConnection, Error, ManageConnection, Pool, PoolError,
+    PooledConnection, R2D2Connection, State,
+};
+use diesel::result::ConnectionError;
+use std::any::Any;
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+use usdt::UniqueId;
+use uuid::Uuid;
+
+/// A connection checked out of a [`DTracePool`].
+///
+/// This dereferences to a [`DTraceConnection<C>`], so it can be used anywhere
+/// a `&mut DTraceConnection<C>` is accepted, or any `&mut impl Connection`,
+/// and all queries issued through it fire the usual probes. It is not a
+/// `&mut C`: code that takes, e.g., a `&mut PgConnection` has to change to
+/// take the wrapper, or be generic over the connection. Dereferencing the
+/// wrapper in turn does reach the `C`, but queries issued through that bypass
+/// the probes entirely.
+pub type DTracePooledConnection<C> = PooledConnection<ConnectionManager<DTraceConnection<C>>>;
+
+/// An r2d2 [`Pool`] that yields [`DTraceConnection`]s.
+///
+/// This is a thin wrapper around `Pool<ConnectionManager<DTraceConnection<C>>>`
+/// that exposes the same `get()` / `get_timeout()` surface as r2d2, so that
+/// adopting the instrumented connection type only requires changing the type
+/// of the pool itself, not every signature that uses it. Checking out a
+/// connection fires the `pool-checkout-start` and `pool-checkout-done` probes.
+/// If no connection is available immediately, the time spent waiting for one
+/// is also reported by the `pool-wait-start` and `pool-wait-done` probes.
+pub struct DTracePool<C>
+where
+    C: R2D2Connection + Connection<TransactionManager = AnsiTransactionManager> + Send + 'static,
+    C::Backend: RenderQuery,
+{
+    inner: Pool<ConnectionManager<DTraceConnection<C>>>,
+}
+
+impl<C> DTracePool<C>
+where
+    C: R2D2Connection + Connection<TransactionManager = AnsiTransactionManager> + Send + 'static,
+    C::Backend: RenderQuery,
+{
+    /// Create a pool with the default r2d2 configuration, connecting to the
+    /// provided database URL.
+    ///
+    /// Use the [`From`] implementation to wrap a pool constructed with a
+    /// custom [`diesel::r2d2::Builder`].
+    pub fn new(database_url: impl Into<String>) -> Result<Self, PoolError> {
+        Pool::new(ConnectionManager::new(database_url)).map(Self::from)
+    }
+
+    /// Retrieve a connection from the pool, waiting at most the pool's
+    /// configured connection timeout.
+    pub fn get(&self) -> Result<DTracePooledConnection<C>, PoolError> {
+        self.checkout(None)
+    }
+
+    /// Retrieve a connection from the pool, waiting at most `timeout`.
+    pub fn get_timeout(&self, timeout: Duration) -> Result<DTracePooledConnection<C>, PoolError> {
+        self.checkout(Some(timeout))
+    }
+
+    /// Return information about the current state of the pool.
+    pub fn state(&self) -> State {
+        self.inner.state()
+    }
+
+    /// Return the underlying r2d2 pool.
+    pub fn inner(&self) -> &Pool<ConnectionManager<DTraceConnection<C>>> {
+        &self.inner
+    }
+
+    fn checkout(&self, timeout: Option<Duration>) -> Result<DTracePooledConnection<C>, PoolError> {
+        let id = UniqueId::new();
+        let start = clock::now();
+        probes::pool__checkout__start!(|| &id);
+        let mut result = match self.inner.try_get() {
+            Some(conn) => Ok(conn),
+            None => self.wait(&id, timeout),
+        };
+        if let Ok(conn) = &mut result {
+            conn.mark_checked_out();
+        }
+        let waited = clock::now().saturating_duration_since(start);
+        probes::pool__checkout__done!(|| (
+            &id,
+            result
+                .as_ref()
+                .map(|conn| conn.id())
+                .unwrap_or_else(|_| Uuid::nil()),
+            u8::from(result.is_ok()),
+            duration_nanos(waited)
+        ));
+        result
+    }
+
+    // Wait for a connection, once we know that none is available immediately.
+    fn wait(
+        &self,
+        id: &UniqueId,
+        timeout: Option<Duration>,
+    ) -> Result<DTracePooledConnection<C>, PoolError> {
+        let start = clock::now();
+        probes::pool__wait__start!(|| id);
+        let result = match timeout {
+            Some(timeout) => self.inner.get_timeout(timeout),
+            None => self.inner.get(),
+        };
+        let waited = clock::now().saturating_duration_since(start);
+        probes::pool__wait__done!(|| (id, u8::from(result.is_ok()), duration_nanos(waited)));
+        result
+    }
+}
+
+/// Instrument checking out a connection from an asynchronous pool.
+///
+/// Asynchronous pools like bb8 have no hook around checkouts like the one
+/// [`DTracePool`] provides for r2d2. Instead, wrap the future returned by the
+/// pool's checkout method with this function, e.g.,
+/// `instrument_async_checkout(pool.get()).await`. This fires the
+/// `pool-checkout-start` probe when the future is first polled, and the
+/// `pool-checkout-done` probe when it completes, including the time spent
+/// waiting for the connection.
+///
+/// The checked-out connection is opaque to this function, so the connection ID
+/// passed to `pool-checkout-done` is always the nil UUID.
+///
+/// If the future is dropped while it waits, e.g., because the request it was
+/// serving timed out, `pool-checkout-done` fires as it is dropped, with
+/// `success == 0`, so that every `pool-checkout-start` has a matching
+/// `pool-checkout-done`.
+pub async fn instrument_async_checkout<F, T, E>(checkout: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let mut guard = CheckoutGuard {
+        id: UniqueId::new(),
+        start: clock::now(),
+        done: false,
+    };
+    probes::pool__checkout__start!(|| &guard.id);
+    let result = checkout.await;
+    guard.done(result.is_ok());
+    result
+}
+
+/// Fires `pool-checkout-done` for an asynchronous checkout, when it completes
+/// or, if it never does, when its future is dropped.
+struct CheckoutGuard {
+    id: UniqueId,
+    start: Instant,
+    done: bool,
+}
+
+impl CheckoutGuard {
+    fn done(&mut self, success: bool) {
+        self.done = true;
+        let waited = clock::now().saturating_duration_since(self.start);
+        probes::pool__checkout__done!(|| (
+            &self.id,
+            Uuid::nil(),
+            u8::from(success),
+            duration_nanos(waited)
+        ));
+        #[cfg(test)]
+        tests::CHECKOUTS_DONE.with(|done| done.borrow_mut().push(success));
+    }
+}
+
+impl Drop for CheckoutGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            self.done(false);
+        }
+    }
+}
+
+impl<C> Clone for DTracePool<C>
+where
+    C: R2D2Connection + Connection<TransactionManager = AnsiTransactionManager> + Send + 'static,
+    C::Backend: RenderQuery,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C> From<Pool<ConnectionManager<DTraceConnection<C>>>> for DTracePool<C>
+where
+    C: R2D2Connection + Connection<TransactionManager = AnsiTransactionManager> + Send + 'static,
+    C::Backend: RenderQuery,
+{
+    fn from(inner: Pool<ConnectionManager<DTraceConnection<C>>>) -> Self {
+        Self { inner }
+    }
+}
+
+/// An r2d2 connection customizer that gives each replacement connection the ID
+/// of a connection that the pool retired.
+///
+/// By default, every physical connection gets a fresh ID. When r2d2 replaces a
+/// broken or expired connection, the replacement therefore appears as a
+/// brand-new connection. Installing this customizer on the pool makes a new
+/// connection inherit the ID of a previously released one instead, so that an
+/// ID tracks a pool "slot" rather than a physical connection. The
+/// `connection-reconnect` probe fires with the old and new IDs at each such
+/// transition, and the connection uses the old ID from then on.
+///
+/// Note that the `connection-establish-*` probes for the replacement still
+/// report its fresh ID, since they fire before the pool hands the connection
+/// to this customizer.
+///
+/// ```rust,ignore
+/// let pool = Pool::builder()
+///     .connection_customizer(Box::new(ReuseConnectionIds::default()))
+///     .build(ConnectionManager::<DTraceConnection<PgConnection>>::new(url))?;
+/// let pool = DTracePool::from(pool);
+/// ```
+#[derive(Debug, Default)]
+pub struct ReuseConnectionIds {
+    retired: Mutex<Vec<Uuid>>,
+}
+
+impl<C> CustomizeConnection<DTraceConnection<C>, Error> for ReuseConnectionIds
+where
+    C: Connection + Send + 'static,
+{
+    fn on_acquire(&self, conn: &mut DTraceConnection<C>) -> Result<(), Error> {
+        let retired = self
+            .retired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        if let Some(old_id) = retired {
+            probes::connection__reconnect!(|| (old_id, conn.id));
+            conn.id = old_id;
+        }
+        Ok(())
+    }
+
+    fn on_release(&self, conn: DTraceConnection<C>) {
+        self.retired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(conn.id);
+    }
+}
+
+/// An r2d2 connection manager that instruments the connections of another.
+///
+/// Diesel's [`ConnectionManager`] establishes each connection from a URL.
+/// Applications with their own [`ManageConnection`], e.g., one that picks a
+/// database host, or configures each connection specially, can wrap it with
+/// this manager instead, to get [`DTraceConnection`]s out of it. Connecting
+/// establishes a connection exactly as [`DTraceConnection`] does, through the
+/// wrapped manager's `connect`, firing the same probes and retrying as
+/// configured by [`Config::retry_establish`]. The URL is empty, since the
+/// wrapped manager doesn't expose one. An error that is a
+/// [`ConnectionError`], or diesel's r2d2 [`Error`] wrapping one, is reported
+/// and retried as it would be by `DTraceConnection`. Any other error is
+/// reported with a kind of
+/// [`ConnectionErrorKind::Other`](crate::ConnectionErrorKind::Other), is
+/// passed to observers as a [`ConnectionError::BadConnection`] with its
+/// message, and is never retried. Validating a connection fires the
+/// `validate-start` and `validate-done` probes around its `is_valid`, and a
+/// connection returned with a transaction still open fires
+/// `connection-returned_dirty`, as with diesel's manager.
+///
+/// ```rust,ignore
+/// let manager = DTraceConnectionManager::new(MyManager::new(hosts));
+/// let pool = Pool::builder().build(manager)?;
+/// ```
+#[derive(Debug)]
+pub struct DTraceConnectionManager<M> {
+    inner: M,
+    config: Config,
+}
+
+impl<M> DTraceConnectionManager<M> {
+    /// Wrap `manager`, configuring its connections with the process-wide
+    /// default configuration.
+    pub fn new(manager: M) -> Self {
+        Self::with_config(manager, config::default_config())
+    }
+
+    /// Wrap `manager`, configuring its connections with `config`.
+    pub fn with_config(manager: M, config: Config) -> Self {
+        Self {
+            inner: manager,
+            config,
+        }
+    }
+
+    /// Return the wrapped manager.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+}
+
+impl<M, C> ManageConnection for DTraceConnectionManager<M>
+where
+    M: ManageConnection<Connection = C>,
+    C: Connection<TransactionManager = AnsiTransactionManager> + Send + 'static,
+    C::Backend: RenderQuery,
+{
+    type Connection = DTraceConnection<C>;
+    type Error = M::Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        DTraceConnection::establish_inner(
+            "",
+            self.config.clone(),
+            |_| self.inner.connect(),
+            |_| false,
+            connection_error,
+        )
+    }
+
+    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        probes::validate__start!(|| conn.id);
+        let result = self.inner.is_valid(&mut conn.inner);
+        probes::validate__done!(|| (conn.id, u8::from(result.is_ok())));
+        result
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        let depth = DTraceTransactionManager::<C>::depth(conn);
+        if depth > 0 {
+            probes::connection__returned_dirty!(|| (conn.id, depth));
+        }
+        // As in `R2D2Connection::is_broken`, the connection is idle in the
+        // pool until its next checkout.
+        conn.awaiting_first_query = None;
+        self.inner.has_broken(&mut conn.inner)
+    }
+}
+
+/// Recover the diesel error from an error returned by a connection manager,
+/// if it is one, or wraps one as diesel's own manager does.
+fn connection_error<E: std::error::Error + 'static>(error: &E) -> Option<&ConnectionError> {
+    let error: &dyn Any = error;
+    if let Some(error) = error.downcast_ref::<ConnectionError>() {
+        return Some(error);
+    }
+    match error.downcast_ref::<Error>() {
+        Some(Error::ConnectionError(error)) => Some(error),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{real_clock, MockConnection, Recorded, Recorder};
+    use crate::QueryKind;
+    use crate::QueryMethod;
+    use diesel::connection::SimpleConnection;
+    use std::cell::RefCell;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    thread_local! {
+        /// The `success` of each `pool-checkout-done` fired for an asynchronous
+        /// checkout on this thread.
+        pub(super) static CHECKOUTS_DONE: RefCell<Vec<bool>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn checkouts_done() -> Vec<bool> {
+        CHECKOUTS_DONE.with(|done| done.take())
+    }
+
+    #[test]
+    fn async_checkout_fires_done_once_when_it_completes() {
+        let _clock = real_clock();
+        checkouts_done();
+        let checkout = instrument_async_checkout(std::future::ready(Ok::<_, ()>(())));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(pin!(checkout).poll(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(checkouts_done(), [true]);
+    }
+
+    #[test]
+    fn async_checkout_fires_done_once_when_dropped_while_waiting() {
+        let _clock = real_clock();
+        checkouts_done();
+        let waiting = std::future::pending::<Result<(), ()>>();
+        let mut checkout = Box::pin(instrument_async_checkout(waiting));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(checkout.as_mut().poll(&mut cx).is_pending());
+        assert!(checkouts_done().is_empty());
+        drop(checkout);
+        assert_eq!(checkouts_done(), [false]);
+    }
+
+    /// A connection manager whose first attempt to connect fails.
+    #[derive(Default)]
+    struct FlakyManager {
+        attempts: Mutex<u32>,
+    }
+
+    impl ManageConnection for FlakyManager {
+        type Connection = MockConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<MockConnection, Error> {
+            let mut attempts = self.attempts.lock().unwrap();
+            *attempts += 1;
+            if *attempts == 1 {
+                let error = ConnectionError::BadConnection("connection refused".to_string());
+                return Err(Error::ConnectionError(error));
+            }
+            Ok(MockConnection::default())
+        }
+
+        fn is_valid(&self, _: &mut MockConnection) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut MockConnection) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn manager_establishes_connections_like_dtrace_connection() {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let config = Config::new().retry_establish(3, Duration::ZERO);
+        let manager = DTraceConnectionManager::with_config(FlakyManager::default(), config);
+        let mut conn = manager.connect().unwrap();
+        assert_eq!(*manager.inner().attempts.lock().unwrap(), 2);
+        conn.batch_execute("SELECT 1").unwrap();
+        assert_eq!(
+            recorder.events(conn.id()),
+            [
+                Recorded::Establish { ok: false },
+                Recorded::Establish { ok: true },
+                Recorded::QueryStart {
+                    query: "SELECT 1".to_string(),
+                    kind: QueryKind::Select,
+                    method: QueryMethod::BatchExecute,
+                },
+                Recorded::QueryDone { ok: true },
+            ]
+        );
+        conn.mark_checked_out();
+        assert!(!manager.has_broken(&mut conn));
+        assert!(conn.awaiting_first_query.is_none());
+    }
+}
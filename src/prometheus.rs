@@ -0,0 +1,319 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exporting the crate's statistics in the Prometheus text format.
+//!
+//! The latency of queries comes from the histograms kept for
+//! [`query_latency_summary`](crate::query_latency_summary). The other
+//! statistics are counted here, with relaxed atomic increments at the points
+//! where the corresponding probes fire. [`snapshot_and_reset`] returns them
+//! all as counts over an interval instead, as the difference from their values
+//! at its last call, leaving the counters themselves untouched.
+
+use crate::latency;
+use crate::ConnectionErrorKind;
+use crate::QueryErrorKind;
+use crate::QueryKind;
+use crate::TransactionDoneReason;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+const QUERY_ERROR_KINDS: [QueryErrorKind; 11] = [
+    QueryErrorKind::UniqueViolation,
+    QueryErrorKind::ForeignKeyViolation,
+    QueryErrorKind::NotNullViolation,
+    QueryErrorKind::CheckViolation,
+    QueryErrorKind::SerializationFailure,
+    QueryErrorKind::ReadOnlyTransaction,
+    QueryErrorKind::UnableToSendCommand,
+    QueryErrorKind::ClosedConnection,
+    QueryErrorKind::OtherDatabaseError,
+    QueryErrorKind::NotFound,
+    QueryErrorKind::Other,
+];
+
+const TRANSACTION_DONE_REASONS: [TransactionDoneReason; 5] = [
+    TransactionDoneReason::Commit,
+    TransactionDoneReason::ExplicitRollback,
+    TransactionDoneReason::ErrorRollback,
+    TransactionDoneReason::Panic,
+    TransactionDoneReason::Cancelled,
+];
+
+const CONNECTION_ERROR_KINDS: [ConnectionErrorKind; 6] = [
+    ConnectionErrorKind::Ok,
+    ConnectionErrorKind::BadConnection,
+    ConnectionErrorKind::InvalidCString,
+    ConnectionErrorKind::InvalidConnectionUrl,
+    ConnectionErrorKind::CouldntSetupConfiguration,
+    ConnectionErrorKind::Other,
+];
+
+static QUERY_ERRORS: [AtomicU64; QUERY_ERROR_KINDS.len()] =
+    [const { AtomicU64::new(0) }; QUERY_ERROR_KINDS.len()];
+static TRANSACTIONS: [AtomicU64; TRANSACTION_DONE_REASONS.len()] =
+    [const { AtomicU64::new(0) }; TRANSACTION_DONE_REASONS.len()];
+static ESTABLISHES: [AtomicU64; CONNECTION_ERROR_KINDS.len()] =
+    [const { AtomicU64::new(0) }; CONNECTION_ERROR_KINDS.len()];
+
+/// Count a query that failed with an error of `kind`.
+pub(crate) fn record_query_error(kind: QueryErrorKind) {
+    QUERY_ERRORS[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a transaction that completed at `depth` for `reason`, if it was an
+/// outermost one.
+pub(crate) fn record_transaction(depth: i64, reason: TransactionDoneReason) {
+    if depth == 0 {
+        TRANSACTIONS[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count an attempt to establish a connection, which ended with `kind`.
+pub(crate) fn record_establish(kind: ConnectionErrorKind) {
+    ESTABLISHES[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The counts of the crate's statistics over an interval, returned by
+/// [`snapshot_and_reset`].
+///
+/// Each map has an entry for every variant, including those with a count of
+/// zero.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of queries completed, by kind of statement.
+    pub queries: HashMap<QueryKind, u64>,
+    /// The number of failed queries, by kind of error.
+    pub query_errors: HashMap<QueryErrorKind, u64>,
+    /// The number of outermost transactions completed, by the reason they
+    /// completed.
+    pub transactions: HashMap<TransactionDoneReason, u64>,
+    /// The number of attempts to establish a connection, by their outcome.
+    pub establishes: HashMap<ConnectionErrorKind, u64>,
+}
+
+/// The values of the counters at a point in time.
+struct Counts {
+    queries: [u64; latency::KINDS.len()],
+    query_errors: [u64; QUERY_ERROR_KINDS.len()],
+    transactions: [u64; TRANSACTION_DONE_REASONS.len()],
+    establishes: [u64; CONNECTION_ERROR_KINDS.len()],
+}
+
+impl Counts {
+    fn load() -> Self {
+        Self {
+            queries: latency::counts(),
+            query_errors: load_all(&QUERY_ERRORS),
+            transactions: load_all(&TRANSACTIONS),
+            establishes: load_all(&ESTABLISHES),
+        }
+    }
+}
+
+/// The values of the counters at the last call to [`snapshot_and_reset`].
+static BASELINE: Mutex<Counts> = Mutex::new(Counts {
+    queries: [0; latency::KINDS.len()],
+    query_errors: [0; QUERY_ERROR_KINDS.len()],
+    transactions: [0; TRANSACTION_DONE_REASONS.len()],
+    establishes: [0; CONNECTION_ERROR_KINDS.len()],
+});
+
+/// Return the statistics counted since the last call, or since the process
+/// started, and start a new interval.
+///
+/// This is for metrics pipelines that collect counts per interval, e.g., on
+/// every scrape, rather than counters that only grow. The counters
+/// themselves are never reset: this returns how much each grew since the
+/// last call, and remembers their values for the next, so that
+/// [`prometheus_text`] and
+/// [`query_latency_summary`](crate::query_latency_summary) are unaffected,
+/// and can be used alongside it. Calls are serialized, so every increment is
+/// counted in exactly one interval, but the counters are read one after the
+/// other, rather than all at once. An event completing while the snapshot is
+/// taken can land in either interval, e.g., a failed query counted in
+/// `query_errors` of this snapshot, and in `queries` of the next.
+pub fn snapshot_and_reset() -> Stats {
+    let mut baseline = BASELINE.lock().unwrap_or_else(PoisonError::into_inner);
+    let current = Counts::load();
+    let stats = Stats {
+        queries: deltas(&latency::KINDS, &current.queries, &baseline.queries),
+        query_errors: deltas(
+            &QUERY_ERROR_KINDS,
+            &current.query_errors,
+            &baseline.query_errors,
+        ),
+        transactions: deltas(
+            &TRANSACTION_DONE_REASONS,
+            &current.transactions,
+            &baseline.transactions,
+        ),
+        establishes: deltas(
+            &CONNECTION_ERROR_KINDS,
+            &current.establishes,
+            &baseline.establishes,
+        ),
+    };
+    *baseline = current;
+    stats
+}
+
+/// Return the value of each of `counts`.
+fn load_all<const N: usize>(counts: &[AtomicU64; N]) -> [u64; N] {
+    std::array::from_fn(|i| counts[i].load(Ordering::Relaxed))
+}
+
+/// Return how much the count of each of `keys` grew from `baseline` to
+/// `current`.
+fn deltas<T: Copy + Eq + Hash, const N: usize>(
+    keys: &[T; N],
+    current: &[u64; N],
+    baseline: &[u64; N],
+) -> HashMap<T, u64> {
+    keys.iter()
+        .zip(current.iter().zip(baseline))
+        .map(|(&key, (current, baseline))| (key, current.wrapping_sub(*baseline)))
+        .collect()
+}
+
+/// Render the statistics of all connections so far in the Prometheus text
+/// exposition format.
+///
+/// This includes:
+///
+/// - `diesel_dtrace_query_duration_seconds`, a summary of the latency of
+///   completed queries, with the 0.5, 0.9, and 0.99 quantiles, by the `kind`
+///   of statement.
+/// - `diesel_dtrace_query_errors_total`, the number of failed queries, by the
+///   `kind` of error.
+/// - `diesel_dtrace_transactions_total`, the number of outermost transactions
+///   completed, by the `reason` they completed.
+/// - `diesel_dtrace_connection_establishes_total`, the number of attempts to
+///   establish a connection, by their `result`, which is `ok` or the kind of
+///   error.
+///
+/// Label values are the names of [`QueryKind`], [`QueryErrorKind`],
+/// [`TransactionDoneReason`], and [`ConnectionErrorKind`] in snake case,
+/// e.g., `unique_violation`. The statistics are the same for every call,
+/// apart from what completed in between, so the text can be served as is
+/// from an HTTP handler that Prometheus scrapes.
+pub fn prometheus_text() -> String {
+    let mut out = String::new();
+    render(&mut out).expect("writing to a String doesn't fail");
+    out
+}
+
+fn render(out: &mut String) -> fmt::Result {
+    let name = "diesel_dtrace_query_duration_seconds";
+    writeln!(out, "# HELP {name} The latency of completed queries.")?;
+    writeln!(out, "# TYPE {name} summary")?;
+    let summaries = latency::query_latency_summary();
+    for kind in latency::KINDS {
+        let Some(summary) = summaries.get(&kind) else {
+            continue;
+        };
+        let kind = snake_case(kind);
+        for (quantile, value) in [
+            ("0.5", summary.p50),
+            ("0.9", summary.p90),
+            ("0.99", summary.p99),
+        ] {
+            writeln!(
+                out,
+                "{name}{{kind=\"{kind}\",quantile=\"{quantile}\"}} {}",
+                value.as_secs_f64()
+            )?;
+        }
+        writeln!(
+            out,
+            "{name}_sum{{kind=\"{kind}\"}} {}",
+            summary.sum.as_secs_f64()
+        )?;
+        writeln!(out, "{name}_count{{kind=\"{kind}\"}} {}", summary.count)?;
+    }
+    counter(
+        out,
+        "diesel_dtrace_query_errors_total",
+        "The number of failed queries.",
+        "kind",
+        QUERY_ERROR_KINDS.iter().zip(&QUERY_ERRORS),
+    )?;
+    counter(
+        out,
+        "diesel_dtrace_transactions_total",
+        "The number of outermost transactions completed.",
+        "reason",
+        TRANSACTION_DONE_REASONS.iter().zip(&TRANSACTIONS),
+    )?;
+    counter(
+        out,
+        "diesel_dtrace_connection_establishes_total",
+        "The number of attempts to establish a connection.",
+        "result",
+        CONNECTION_ERROR_KINDS.iter().zip(&ESTABLISHES),
+    )
+}
+
+/// Render the counter `name`, with one sample for each of `values`, labelled
+/// `label`.
+fn counter<'a, T: fmt::Debug + 'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a T, &'a AtomicU64)>,
+) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} counter")?;
+    for (value, count) in values {
+        let value = snake_case(value);
+        let count = count.load(Ordering::Relaxed);
+        writeln!(out, "{name}{{{label}=\"{value}\"}} {count}")?;
+    }
+    Ok(())
+}
+
+/// Return the name of the variant `value` in snake case.
+fn snake_case(value: impl fmt::Debug) -> String {
+    let name = format!("{value:?}");
+    let mut out = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_leave_the_exported_counters_alone() {
+        let kind = QueryErrorKind::CheckViolation;
+        let exported = || QUERY_ERRORS[kind as usize].load(Ordering::Relaxed);
+        record_query_error(kind);
+        let before = exported();
+        assert!(snapshot_and_reset().query_errors[&kind] >= 1);
+        assert!(exported() >= before);
+        record_query_error(kind);
+        assert!(snapshot_and_reset().query_errors[&kind] >= 1);
+        assert!(exported() > before);
+    }
+}
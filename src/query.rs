@@ -0,0 +1,1174 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for instrumenting queries and manipulating their text.
+
+use crate::background::{self, Deferred};
+#[cfg(feature = "backtrace")]
+use crate::backtrace::BacktraceCapture;
+use crate::batch::{self, BatchLimits};
+#[cfg(feature = "chrome-trace")]
+use crate::chrome;
+use crate::clock;
+use crate::config::{Flags, SlowQueryCallback, SlowQueryEvent};
+use crate::context::{self, QueryContext};
+#[cfg(feature = "latency-summary")]
+use crate::latency;
+use crate::observer::{self, QueryDoneEvent};
+use crate::otel::QuerySpan;
+use crate::probes;
+#[cfg(feature = "prometheus-text")]
+use crate::prometheus;
+#[cfg(feature = "ring-buffer")]
+use crate::ring::{self, RecentEventKind};
+#[cfg(feature = "tracing")]
+use crate::trace::QueryTrace;
+use crate::Config;
+use crate::QueryErrorKind;
+use crate::QueryKind;
+use diesel::backend::Backend;
+use diesel::debug_query;
+use diesel::query_builder::{QueryBuilder, QueryFragment, QueryId};
+use diesel::result::{Error, QueryResult};
+use serde::Serialize;
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{PoisonError, RwLock};
+#[cfg(feature = "wall-clock")]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+use usdt::UniqueId;
+use uuid::Uuid;
+
+/// Details of a query that are passed to the `query-start` probe as JSON.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryInfo {
+    pub(crate) bytes: u64,
+    pub(crate) char_count: u64,
+    pub(crate) op_name: String,
+    pub(crate) correlation_id: Uuid,
+    pub(crate) role: u8,
+    pub(crate) fingerprint: u64,
+    pub(crate) method: u8,
+    pub(crate) shard: String,
+    pub(crate) context: String,
+}
+
+/// The arguments of the `query-start` probe, other than the IDs of the query
+/// and connection, built by `DTraceConnection::query_start`.
+pub(crate) struct QueryStart<'a> {
+    pub(crate) query: Cow<'a, str>,
+    pub(crate) in_transaction: bool,
+    pub(crate) kind: QueryKind,
+    pub(crate) info: QueryInfo,
+}
+
+/// Details of a transaction that are passed to the `transaction-start` and
+/// `transaction-done` probes as JSON.
+#[derive(Clone, Debug, Serialize)]
+pub struct TransactionInfo {
+    pub(crate) correlation_id: Uuid,
+    pub(crate) role: u8,
+    pub(crate) shard: String,
+    pub(crate) context: String,
+}
+
+thread_local! {
+    // The ID shared by all queries on this thread, when they don't get their
+    // own, see `Config::unique_query_ids`.
+    static SHARED_ID: UniqueId = UniqueId::new();
+}
+
+/// A query that has started, but whose `query-done` probe has not yet fired.
+pub(crate) struct PendingQuery {
+    pub(crate) id: UniqueId,
+    pub(crate) conn_id: Uuid,
+    // The time the query started, only recorded if we need the duration.
+    started: Option<Instant>,
+    slow_threshold: Option<Duration>,
+    slow_callback: Option<SlowQueryCallback>,
+    // The limits of the batch the query is added to, in batched mode.
+    batch: Option<BatchLimits>,
+    // Whether the query fires the `query-start` and `query-done` probes, see
+    // `Config::sample_queries`.
+    pub(crate) sampled: bool,
+    // Whether the query is issued inside `instrument_as_query`, whose
+    // pseudo-query fires `query-start`, `query-error` and `query-done`
+    // instead.
+    pub(crate) suppressed: bool,
+    // The text and operation name of the query, only recorded if they're
+    // needed once the query completes.
+    text: Option<(String, String)>,
+    // Whether the text is passed to the `query-done` probe.
+    text_on_done: bool,
+    // Whether the query is written to the Chrome trace or the history of
+    // recent events.
+    #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+    recorded: bool,
+    // Whether the query is passed to the observer set with
+    // `set_global_observer`.
+    observed: bool,
+    // When to capture a backtrace of the code issuing the query.
+    #[cfg(feature = "backtrace")]
+    backtraces: Option<BacktraceCapture>,
+    // The kind of statement, for the latency summary.
+    #[cfg(feature = "latency-summary")]
+    pub(crate) kind: Option<QueryKind>,
+    // The `tracing` span and events of the query.
+    #[cfg(feature = "tracing")]
+    pub(crate) trace: Option<QueryTrace>,
+    span: QuerySpan,
+}
+
+impl PendingQuery {
+    /// Return the text of the query, as passed to `query-start`, if it is
+    /// kept until the query completes.
+    pub(crate) fn text(&self) -> Option<&str> {
+        self.text.as_ref().map(|(text, _)| text.as_str())
+    }
+
+    /// Start tracking a new query on a connection.
+    ///
+    /// This must be created before firing the `query-start` probe, so that the
+    /// ID is available to it. `text` is only called if the text is needed
+    /// after the query completes, for a slow-query callback or the Chrome
+    /// trace.
+    pub(crate) fn new(
+        conn_id: Uuid,
+        config: &Config,
+        span: QuerySpan,
+        text: impl FnOnce() -> String,
+        op_name: &str,
+    ) -> Self {
+        let slow_threshold = config.slow_query_threshold;
+        let slow_callback = slow_threshold
+            .filter(|_| !config.flags.contains(Flags::SLOW_QUERY_CALLBACK_DISABLED))
+            .and_then(|_| config.slow_query_callback.clone());
+        #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+        let recorded = recording();
+        #[cfg(not(any(feature = "chrome-trace", feature = "ring-buffer")))]
+        let recorded = false;
+        let observed = observer::enabled();
+        #[cfg(feature = "backtrace")]
+        let backtraces = config.backtraces.is_some();
+        #[cfg(not(feature = "backtrace"))]
+        let backtraces = false;
+        let text_on_done = config.flags.contains(Flags::QUERY_TEXT_ON_DONE);
+        let text = (slow_callback.is_some() || recorded || text_on_done)
+            .then(|| (text(), op_name.to_string()));
+        let id = if config.flags.contains(Flags::SHARED_QUERY_IDS) {
+            SHARED_ID.with(UniqueId::clone)
+        } else {
+            UniqueId::new()
+        };
+        context::enter(QueryContext {
+            id: id.as_u64(),
+            conn_id,
+        });
+        Self {
+            id,
+            conn_id,
+            started: (slow_threshold.is_some()
+                || recorded
+                || observed
+                || config.batch.is_some()
+                || cfg!(feature = "latency-summary")
+                || cfg!(feature = "tracing")
+                || backtraces)
+                .then(clock::now),
+            slow_threshold,
+            slow_callback,
+            batch: config.batch,
+            sampled: true,
+            suppressed: false,
+            text,
+            text_on_done,
+            #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+            recorded,
+            observed,
+            #[cfg(feature = "backtrace")]
+            backtraces: config.backtraces,
+            #[cfg(feature = "latency-summary")]
+            kind: None,
+            #[cfg(feature = "tracing")]
+            trace: None,
+            span,
+        }
+    }
+
+    /// Fire the probes marking the completion of the query, which returned
+    /// `result`, and produced `rows` rows if that is known.
+    ///
+    /// This is how every query completes, whichever method issued it, so
+    /// that the outcome is reported the same way for all of them. It fires
+    /// the `query-error` probe first, if the query failed, and then the
+    /// `query-done` probe with the outcome. Returns the duration of the query
+    /// if it took longer than the slow-query threshold.
+    pub(crate) fn finish<T>(self, result: &QueryResult<T>, rows: Option<u64>) -> Option<Duration> {
+        if let Err(error) = result {
+            self.error(error);
+        }
+        self.done(result.as_ref().map(|_| rows))
+    }
+
+    /// Fire the `query-error` probe for the error that caused this query to
+    /// fail.
+    fn error(&self, error: &Error) {
+        if self.suppressed {
+            return;
+        }
+        #[cfg(feature = "prometheus-text")]
+        prometheus::record_query_error(QueryErrorKind::from_error(error));
+        if background::enabled() {
+            let (constraint, table) = constraint_and_table(error);
+            background::send(Deferred::QueryError {
+                id: self.id.clone(),
+                conn_id: self.conn_id,
+                error_kind: QueryErrorKind::from_error(error) as u8,
+                constraint: constraint.to_string(),
+                table: table.to_string(),
+            });
+            return;
+        }
+        probes::query__error!(|| {
+            let (constraint, table) = constraint_and_table(error);
+            (
+                &self.id,
+                self.conn_id,
+                QueryErrorKind::from_error(error) as u8,
+                constraint,
+                table,
+            )
+        });
+    }
+
+    /// Fire the probes marking the completion of a query whose rows were
+    /// read from a cursor, once there are no more, or the cursor is dropped.
+    pub(crate) fn finish_cursor(self, rows: u64) {
+        self.done(Ok(Some(rows)));
+    }
+
+    /// Fire the probes marking the failure of a query whose cursor yielded
+    /// `error` instead of a row.
+    pub(crate) fn fail_cursor(self, error: &Error) {
+        self.error(error);
+        self.done(Err(error));
+    }
+
+    /// Fire the probes marking the failure of a pseudo-query whose closure
+    /// panicked.
+    pub(crate) fn finish_panicked(self) {
+        let error = Error::QueryBuilderError("the instrumented closure panicked".into());
+        self.done(Err(&error));
+    }
+
+    /// Fire the probes marking the completion of the query, with the number
+    /// of rows, if known, or the error it failed with.
+    fn done(self, result: Result<Option<u64>, &Error>) -> Option<Duration> {
+        if self.batch.is_none() && self.sampled && !self.suppressed {
+            let success = u8::from(result.is_ok());
+            let rows = match result {
+                Ok(Some(rows)) => i64::try_from(rows).unwrap_or(i64::MAX),
+                _ => -1,
+            };
+            let query = match &self.text {
+                Some((query, _)) if self.text_on_done => query.as_str(),
+                _ => "",
+            };
+            if background::enabled() {
+                background::send(Deferred::QueryDone {
+                    id: self.id.clone(),
+                    conn_id: self.conn_id,
+                    success,
+                    rows,
+                    query: query.to_string(),
+                });
+            } else {
+                probes::query__done!(|| (&self.id, self.conn_id, success, rows, query));
+            }
+        }
+        context::exit(self.id.as_u64());
+        self.span.end();
+        let Some(started) = self.started else {
+            return None;
+        };
+        let elapsed = clock::now().saturating_duration_since(started);
+        #[cfg(feature = "tracing")]
+        if let Some(trace) = &self.trace {
+            trace.done(self.id.as_u64(), self.conn_id, result, elapsed);
+        }
+        #[cfg(feature = "latency-summary")]
+        if let Some(kind) = self.kind {
+            latency::record(kind, elapsed);
+        }
+        if let Some(limits) = self.batch {
+            batch::record(self.conn_id, elapsed, limits);
+        }
+        let slow = self
+            .slow_threshold
+            .is_some_and(|threshold| elapsed > threshold);
+        #[cfg(feature = "backtrace")]
+        let backtrace = self
+            .backtraces
+            .filter(|_| self.observed || self.slow_callback.is_some())
+            .and_then(|capture| capture.capture(slow, result.is_err()));
+        if self.observed {
+            observer::notify(|observer| {
+                observer.query_done(&QueryDoneEvent {
+                    id: self.id.as_u64(),
+                    conn_id: self.conn_id,
+                    result,
+                    duration: elapsed,
+                    #[cfg(feature = "wall-clock")]
+                    timestamp: SystemTime::now(),
+                    #[cfg(feature = "backtrace")]
+                    backtrace: backtrace.as_ref(),
+                });
+            });
+        }
+        if slow {
+            probes::query__slow!(|| (&self.id, self.conn_id, duration_nanos(elapsed)));
+            if let (Some(callback), Some((query, op_name))) = (&self.slow_callback, &self.text) {
+                let event = SlowQueryEvent {
+                    query,
+                    op_name,
+                    duration: elapsed,
+                    conn_id: self.conn_id,
+                    #[cfg(feature = "backtrace")]
+                    backtrace: backtrace.as_ref(),
+                };
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| (callback.0)(&event)));
+            }
+        }
+        #[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+        if let (true, Some((query, _))) = (self.recorded, &self.text) {
+            #[cfg(feature = "chrome-trace")]
+            if chrome::enabled() {
+                chrome::record_query(query, started, elapsed, self.conn_id);
+            }
+            #[cfg(feature = "ring-buffer")]
+            if ring::enabled() {
+                ring::record(RecentEventKind::Query, self.conn_id, query, elapsed);
+            }
+        }
+        slow.then_some(elapsed)
+    }
+}
+
+/// Return the names of the constraint and table involved in `error`, or the
+/// empty string for those the database didn't report.
+fn constraint_and_table(error: &Error) -> (&str, &str) {
+    match error {
+        Error::DatabaseError(_, info) => (
+            info.constraint_name().unwrap_or(""),
+            info.table_name().unwrap_or(""),
+        ),
+        _ => ("", ""),
+    }
+}
+
+/// Return true if completed queries are being recorded, other than by the
+/// probes.
+#[cfg(any(feature = "chrome-trace", feature = "ring-buffer"))]
+fn recording() -> bool {
+    #[cfg(feature = "chrome-trace")]
+    if chrome::enabled() {
+        return true;
+    }
+    #[cfg(feature = "ring-buffer")]
+    if ring::enabled() {
+        return true;
+    }
+    false
+}
+
+/// A backend whose queries can be rendered as text for the probes.
+///
+/// This is the only requirement a [`DTraceConnection`](crate::DTraceConnection)
+/// places on the backend of the connection it wraps. It is implemented for all
+/// backends that implement [`Default`], and whose query builder implements
+/// [`Default`], by rendering the query with [`diesel::debug_query`]. That
+/// includes all of diesel's built-in backends.
+///
+/// `debug_query` needs to construct both a backend and a query builder to
+/// render the query, and diesel connections don't expose theirs. Backends that
+/// carry configuration, and so can't implement `Default`, can implement this
+/// trait directly instead, rendering the query however is appropriate for
+/// them. [`render_with`] does so with a backend and query builder that they
+/// construct themselves:
+///
+/// ```rust,ignore
+/// impl RenderQuery for MyBackend {
+///     fn render<T: QueryFragment<Self>>(query: &T) -> String {
+///         let backend = MyBackend::new(PlaceholderStyle::Numbered);
+///         let builder = MyQueryBuilder::new(PlaceholderStyle::Numbered);
+///         diesel_dtrace::render_with(query, &backend, builder)
+///             .unwrap_or_else(|e| format!("<failed to render query: {e}>"))
+///     }
+/// }
+/// ```
+pub trait RenderQuery: Backend {
+    /// Render `query` as text, including its bind parameters if possible.
+    fn render<T: QueryFragment<Self>>(query: &T) -> String;
+
+    /// Return a key shared by all queries that render the same SQL as
+    /// `query` on this backend, apart from their bind parameters, or `None`
+    /// if the SQL of queries of its type can vary.
+    ///
+    /// The fingerprint passed to the `query-start` probe is only computed
+    /// once per key, and from the text of every query without one. The
+    /// default returns `None`, so that no fingerprint is cached.
+    fn shape_key<T: QueryFragment<Self> + QueryId>(query: &T) -> Option<TypeId> {
+        let _ = query;
+        None
+    }
+}
+
+impl<B> RenderQuery for B
+where
+    B: Backend + Default + 'static,
+    B::QueryBuilder: Default,
+{
+    fn render<T: QueryFragment<Self>>(query: &T) -> String {
+        debug_query::<B, _>(query).to_string()
+    }
+
+    /// Queries of a type with a static `QueryId` render the same SQL unless
+    /// diesel considers them unsafe to cache as prepared statements, as it
+    /// does, e.g., for `eq_any` with a list of values on some backends, whose
+    /// SQL has a placeholder per value. The key is that of the query type
+    /// together with the backend.
+    fn shape_key<T: QueryFragment<Self> + QueryId>(query: &T) -> Option<TypeId> {
+        if !T::HAS_STATIC_QUERY_ID
+            || !matches!(query.is_safe_to_cache_prepared(&B::default()), Ok(true))
+        {
+            return None;
+        }
+        Some(TypeId::of::<(B, T::QueryId)>())
+    }
+}
+
+/// Render the SQL of `query` using the provided backend and query builder.
+///
+/// This is a building block for implementations of [`RenderQuery`] for
+/// backends that can't be rendered with `debug_query`. Unlike `debug_query`,
+/// it only renders the SQL, with placeholders for the bind parameters, since
+/// diesel doesn't expose a way to format their values generically.
+pub fn render_with<B, T>(
+    query: &T,
+    backend: &B,
+    mut builder: B::QueryBuilder,
+) -> QueryResult<String>
+where
+    B: Backend,
+    T: QueryFragment<B>,
+{
+    query.to_sql(&mut builder, backend)?;
+    Ok(builder.finish())
+}
+
+/// Convert a duration to nanoseconds for passing to a probe, saturating at
+/// `u64::MAX`.
+pub(crate) fn duration_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Return `sql` with each value in the `-- binds: [...]` suffix that diesel's
+/// `debug_query` appends replaced with `?`, if there is one.
+///
+/// This keeps the number of bind parameters, but not their values.
+pub(crate) fn redact_binds(sql: &str) -> Cow<'_, str> {
+    let Some((query, binds)) = sql.rsplit_once(" -- binds: ") else {
+        return Cow::Borrowed(sql);
+    };
+    let placeholders = vec!["?"; count_binds(binds)].join(", ");
+    Cow::Owned(format!("{query} -- binds: [{placeholders}]"))
+}
+
+/// Return the number of values in `list`, a list of bind parameters as
+/// rendered by `debug_query`, i.e., formatted with `Debug`.
+fn count_binds(list: &str) -> usize {
+    let list = list.trim();
+    let items = list
+        .strip_prefix('[')
+        .and_then(|list| list.strip_suffix(']'))
+        .unwrap_or(list);
+    if items.trim().is_empty() {
+        return 0;
+    }
+    // Only commas outside of any quoted or nested value separate values.
+    let mut count = 1;
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    for ch in items.chars() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if ch == '\\' => escaped = true,
+            Some(q) if ch == q => quote = None,
+            Some(_) => {}
+            None => match ch {
+                '"' | '\'' => quote = Some(ch),
+                '[' | '(' | '{' => depth += 1,
+                ']' | ')' | '}' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => count += 1,
+                _ => {}
+            },
+        }
+    }
+    count
+}
+
+/// Return the canonical "shape" of a SQL string.
+///
+/// Numeric and single-quoted string literals are replaced with `?`, and SQL
+/// line comments are removed. The latter include the `-- binds: [...]` suffix
+/// that diesel's `debug_query` appends, so that two executions of the same
+/// prepared statement with different bind values produce the same shape. Each
+/// run of whitespace becomes a single space, and a list of values after `IN`,
+/// like `IN (1, 2, $3)`, is collapsed to `IN (?)`, so that lists of different
+/// lengths produce the same shape too.
+///
+/// Quoted identifiers and bind placeholders like `$1` are otherwise left
+/// as-is, as are digits that are part of an identifier, such as `t1`.
+pub(crate) fn normalize_shape(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut prev: Option<char> = None;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' => {
+                // Consume the string literal, including doubled quotes, which
+                // escape a single quote within the literal.
+                while let Some(c) = chars.next() {
+                    if c == '\'' {
+                        if chars.peek() == Some(&'\'') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                out.push('?');
+            }
+            '"' => {
+                out.push(ch);
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if c == '"' {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                // The comment, up to the end of the line, separates what's on
+                // either side of it like any other whitespace.
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                push_space(&mut out);
+            }
+            c if c.is_whitespace() => push_space(&mut out),
+            c if c.is_ascii_digit() && !prev.is_some_and(is_ident_char) => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' {
+                        chars.next();
+                    } else if c == 'e' || c == 'E' {
+                        chars.next();
+                        if matches!(chars.peek(), Some('+') | Some('-')) {
+                            chars.next();
+                        }
+                    } else {
+                        break;
+                    }
+                }
+                out.push('?');
+            }
+            c => out.push(c),
+        }
+        prev = out.chars().next_back();
+    }
+    out.truncate(out.trim_end().len());
+    collapse_in_lists(&out)
+}
+
+/// Push a space onto the shape `out`, unless it is empty or already ends with
+/// one.
+fn push_space(out: &mut String) {
+    if !out.is_empty() && !out.ends_with(' ') {
+        out.push(' ');
+    }
+}
+
+/// Return `shape` with each list of values after an `IN` keyword collapsed to
+/// `IN (?)`.
+fn collapse_in_lists(shape: &str) -> String {
+    let mut out = String::with_capacity(shape.len());
+    let mut rest = shape;
+    while let Some(start) = rest.find(['I', 'i']) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let keyword = rest.get(..2).is_some_and(|w| w.eq_ignore_ascii_case("IN"))
+            && !out.ends_with(|c| is_ident_char(c) || c == '"');
+        match keyword.then(|| value_list_len(&rest[2..])).flatten() {
+            Some(len) => {
+                out.push_str(&rest[..2]);
+                out.push_str(" (?)");
+                rest = &rest[2 + len..];
+            }
+            None => {
+                out.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Return the length of the parenthesized list of values at the start of
+/// `shape`, like ` (?, -?, $3)`, or `None` if it doesn't start with one.
+fn value_list_len(shape: &str) -> Option<usize> {
+    let list = shape.strip_prefix(' ').unwrap_or(shape).strip_prefix('(')?;
+    let end = list.find(')')?;
+    let is_value = |item: &str| {
+        let item = item.trim();
+        let item = item.strip_prefix('-').unwrap_or(item);
+        item == "?"
+            || item
+                .strip_prefix('$')
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    };
+    list[..end]
+        .split(',')
+        .all(is_value)
+        .then(|| shape.len() - list.len() + end + 1)
+}
+
+static FINGERPRINTS: RwLock<Option<HashMap<TypeId, u64>>> = RwLock::new(None);
+
+/// Return the fingerprint of the query rendered as `sql`, a hash of its
+/// normalized shape.
+///
+/// Queries with a `shape_key`, see [`RenderQuery::shape_key`], always render
+/// the same SQL, apart from their bind parameters, so their fingerprint is
+/// only computed the first time, and cached by that key after that. Other
+/// queries are hashed every time.
+pub(crate) fn fingerprint(shape_key: Option<TypeId>, sql: &str) -> u64 {
+    let Some(shape_key) = shape_key else {
+        return fnv1a(&normalize_shape(sql));
+    };
+    let cached = FINGERPRINTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .and_then(|cache| cache.get(&shape_key).copied());
+    if let Some(fingerprint) = cached {
+        return fingerprint;
+    }
+    let fingerprint = fnv1a(&normalize_shape(sql));
+    FINGERPRINTS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(HashMap::new)
+        .insert(shape_key, fingerprint);
+    fingerprint
+}
+
+/// Hash `s` with 64-bit FNV-1a.
+///
+/// Unlike the standard library's hashers, this is stable across processes and
+/// Rust releases, so fingerprints can be compared between traces.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Classify a query by its first keyword.
+///
+/// Leading whitespace, comments and opening parentheses are skipped. Anything
+/// other than a `SELECT`, `INSERT`, `UPDATE` or `DELETE` is
+/// [`QueryKind::Other`], including statements beginning with a `WITH` clause.
+/// For a string containing several statements, this is the kind of the first.
+/// Queries that refer to one of the catalog schemas are
+/// [`QueryKind::Introspection`] instead.
+pub(crate) fn classify(sql: &str) -> QueryKind {
+    if is_introspection(sql) {
+        return QueryKind::Introspection;
+    }
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, after)| after);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, after)| after);
+        } else {
+            break;
+        }
+    }
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let keyword = &rest[..end];
+    if keyword.eq_ignore_ascii_case("SELECT") {
+        QueryKind::Select
+    } else if keyword.eq_ignore_ascii_case("INSERT") {
+        QueryKind::Insert
+    } else if keyword.eq_ignore_ascii_case("UPDATE") {
+        QueryKind::Update
+    } else if keyword.eq_ignore_ascii_case("DELETE") {
+        QueryKind::Delete
+    } else {
+        QueryKind::Other
+    }
+}
+
+/// The names of the schemas, or tables, through which databases expose their
+/// catalog.
+const CATALOGS: &[&str] = &[
+    "information_schema",
+    "pg_catalog",
+    "sqlite_master",
+    "sqlite_schema",
+];
+
+/// Return true if `sql` refers to one of the `CATALOGS`, ignoring case.
+///
+/// Only whole identifiers count, quoted or not, so a catalog name within a
+/// longer identifier, a string literal, or a comment, including the `-- binds:`
+/// comment listing the bind parameters, doesn't make a query introspection.
+fn is_introspection(sql: &str) -> bool {
+    let is_catalog = |ident: &str| {
+        CATALOGS
+            .iter()
+            .any(|catalog| ident.eq_ignore_ascii_case(catalog))
+    };
+    let mut chars = sql.char_indices().peekable();
+    while let Some((start, ch)) = chars.next() {
+        match ch {
+            '\'' => {
+                // Consume the string literal, including doubled quotes.
+                while let Some((_, c)) = chars.next() {
+                    if c == '\'' {
+                        if chars.peek().is_some_and(|&(_, c)| c == '\'') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+            '"' => {
+                let mut ident = String::new();
+                while let Some((_, c)) = chars.next() {
+                    if c == '"' {
+                        if chars.peek().is_some_and(|&(_, c)| c == '"') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    ident.push(c);
+                }
+                if is_catalog(&ident) {
+                    return true;
+                }
+            }
+            '-' if chars.peek().is_some_and(|&(_, c)| c == '-') => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek().is_some_and(|&(_, c)| c == '*') => {
+                chars.next();
+                let mut prev = None;
+                for (_, c) in chars.by_ref() {
+                    if prev == Some('*') && c == '/' {
+                        break;
+                    }
+                    prev = Some(c);
+                }
+            }
+            c if is_ident_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek().filter(|&&(_, c)| is_ident_char(c)) {
+                    chars.next();
+                    end = i + c.len_utf8();
+                }
+                if is_catalog(&sql[start..end]) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{real_clock, MockConnection, Recorder};
+    use crate::{Config, DTraceConnection, QueryMethod};
+    use diesel::pg::Pg;
+    use diesel::sql_types::{Bool, Integer, Text};
+    use diesel::RunQueryDsl;
+
+    #[test]
+    fn redact_binds_keeps_the_number_of_binds() {
+        assert_eq!(
+            redact_binds(r#"SELECT $1, $2 -- binds: [[1, 2], "a, \"b\""]"#),
+            "SELECT $1, $2 -- binds: [?, ?]"
+        );
+        assert_eq!(
+            redact_binds("SELECT 1 -- binds: []"),
+            "SELECT 1 -- binds: []"
+        );
+        assert_eq!(redact_binds("SELECT 1"), "SELECT 1");
+    }
+
+    #[test]
+    fn normalize_shape_replaces_literals() {
+        assert_eq!(
+            normalize_shape("SELECT * FROM t1 WHERE id = 42 AND score > 1.5e-3"),
+            "SELECT * FROM t1 WHERE id = ? AND score > ?"
+        );
+        assert_eq!(
+            normalize_shape("SELECT 'it''s', \"col 2\" FROM t WHERE a = $1"),
+            "SELECT ?, \"col 2\" FROM t WHERE a = $1"
+        );
+        assert_eq!(
+            normalize_shape("SELECT name FROM users WHERE id = $1 -- binds: [7]"),
+            "SELECT name FROM users WHERE id = $1"
+        );
+    }
+
+    #[test]
+    fn normalize_shape_collapses_whitespace() {
+        assert_eq!(
+            normalize_shape("  SELECT id,\n\t\tname  FROM users -- all of them\n  WHERE id = 1\n"),
+            "SELECT id, name FROM users WHERE id = ?"
+        );
+        assert_eq!(normalize_shape("SELECT '  two  spaces  '"), "SELECT ?");
+    }
+
+    #[test]
+    fn normalize_shape_collapses_in_lists() {
+        for sql in [
+            "SELECT id FROM users WHERE id IN (1)",
+            "SELECT id FROM users WHERE id IN (1, 2, 3)",
+            "SELECT id FROM users WHERE id in(-1,'a', $3 )",
+            "SELECT id FROM users WHERE id IN\n  (1,\n   2)",
+        ] {
+            let expected = if sql.contains(" in(") {
+                "SELECT id FROM users WHERE id in (?)"
+            } else {
+                "SELECT id FROM users WHERE id IN (?)"
+            };
+            assert_eq!(normalize_shape(sql), expected, "{sql}");
+        }
+        // Only lists of values are collapsed, and only after `IN` itself.
+        for (sql, expected) in [
+            (
+                "SELECT id FROM users WHERE id IN (SELECT id FROM admins)",
+                "SELECT id FROM users WHERE id IN (SELECT id FROM admins)",
+            ),
+            (
+                "SELECT id FROM users WHERE (id, kind) IN ((1, 2), (3, 4))",
+                "SELECT id FROM users WHERE (id, kind) IN ((?, ?), (?, ?))",
+            ),
+            (
+                "SELECT \"IN\" (1, 2) FROM t JOIN (VALUES (1, 2)) v",
+                "SELECT \"IN\" (?, ?) FROM t JOIN (VALUES (?, ?)) v",
+            ),
+            ("INSERT INTO t VALUES (1, 2)", "INSERT INTO t VALUES (?, ?)"),
+            (
+                "SELECT id FROM t WHERE id IN ()",
+                "SELECT id FROM t WHERE id IN ()",
+            ),
+        ] {
+            assert_eq!(normalize_shape(sql), expected, "{sql}");
+        }
+    }
+
+    #[test]
+    fn catalog_identifiers_are_introspection() {
+        for sql in [
+            "SELECT table_name FROM information_schema.tables",
+            r#"SELECT "relname" FROM "pg_catalog"."pg_class""#,
+            "select name from SQLITE_SCHEMA",
+            r#"SELECT 1 FROM "Information_Schema".tables"#,
+        ] {
+            assert_eq!(classify(sql), QueryKind::Introspection, "{sql}");
+        }
+    }
+
+    #[test]
+    fn catalog_names_outside_identifiers_are_not_introspection() {
+        for (sql, kind) in [
+            (
+                "INSERT INTO notes (body) VALUES ('see information_schema')",
+                QueryKind::Insert,
+            ),
+            ("SELECT 'it''s pg_catalog' FROM t", QueryKind::Select),
+            (
+                r#"INSERT INTO notes (body) VALUES ($1) -- binds: ["pg_catalog.pg_class"]"#,
+                QueryKind::Insert,
+            ),
+            (
+                "UPDATE my_information_schema_x SET x = 1",
+                QueryKind::Update,
+            ),
+            ("DELETE FROM t /* not pg_catalog */", QueryKind::Delete),
+            (r#"SELECT "pg_catalog_copy".x FROM t"#, QueryKind::Select),
+        ] {
+            assert_eq!(classify(sql), kind, "{sql}");
+        }
+    }
+
+    /// Issue an `UPDATE` with several bind parameters through `sql_query` on
+    /// a connection configured with `config`, and return the text of the
+    /// query as passed to the probes.
+    fn sql_query_text(config: Config) -> String {
+        let _clock = real_clock();
+        let recorder = Recorder::start();
+        let mut conn =
+            DTraceConnection::<MockConnection>::establish_with_config("", config).unwrap();
+        diesel::sql_query("UPDATE users SET name = $1, admin = $2 WHERE id = $3")
+            .bind::<Text, _>("hunter2")
+            .bind::<Bool, _>(true)
+            .bind::<Integer, _>(7)
+            .execute(&mut conn)
+            .unwrap();
+        let mut queries = recorder.queries(conn.id());
+        assert_eq!(queries.len(), 1);
+        let (text, kind, method) = queries.remove(0);
+        assert_eq!(kind, QueryKind::Update);
+        assert_eq!(method, QueryMethod::Execute);
+        text
+    }
+
+    #[test]
+    fn sql_query_text_includes_binds() {
+        assert_eq!(
+            sql_query_text(Config::new()),
+            r#"UPDATE users SET name = $1, admin = $2 WHERE id = $3 -- binds: ["hunter2", true, 7]"#
+        );
+    }
+
+    /// A backend that takes its style of quoting identifiers from its
+    /// configuration, and so has no default.
+    #[derive(Debug)]
+    struct Quoting {
+        quote: char,
+    }
+
+    impl Backend for Quoting {
+        type QueryBuilder = QuotingQueryBuilder;
+        type RawValue<'a> = &'a [u8];
+        type BindCollector<'a> = diesel::query_builder::bind_collector::RawBytesBindCollector<Self>;
+    }
+
+    impl diesel::sql_types::TypeMetadata for Quoting {
+        type TypeMetadata = ();
+        type MetadataLookup = ();
+    }
+
+    macro_rules! has_sql_types {
+        ($($ty:ident),*) => {
+            $(
+                impl diesel::sql_types::HasSqlType<diesel::sql_types::$ty> for Quoting {
+                    fn metadata(_: &mut ()) {}
+                }
+            )*
+        };
+    }
+
+    has_sql_types!(SmallInt, Integer, BigInt, Float, Double, Text, Binary, Date, Time, Timestamp);
+
+    impl diesel::backend::SqlDialect for Quoting {
+        type ReturningClause = ();
+        type OnConflictClause = ();
+        type InsertWithDefaultKeyword = ();
+        type BatchInsertSupport = ();
+        type ConcatClause = ();
+        type DefaultValueClauseForInsert = ();
+        type EmptyFromClauseSyntax = ();
+        type SelectStatementSyntax = ();
+        type ExistsSyntax = ();
+        type ArrayComparison = ();
+        type AliasSyntax = ();
+    }
+
+    impl diesel::backend::TrustedBackend for Quoting {}
+
+    struct QuotingQueryBuilder {
+        quote: char,
+        sql: String,
+    }
+
+    impl QueryBuilder<Quoting> for QuotingQueryBuilder {
+        fn push_sql(&mut self, sql: &str) {
+            self.sql.push_str(sql);
+        }
+
+        fn push_identifier(&mut self, identifier: &str) -> QueryResult<()> {
+            self.sql.push(self.quote);
+            self.sql.push_str(identifier);
+            self.sql.push(self.quote);
+            Ok(())
+        }
+
+        fn push_bind_param(&mut self) {
+            self.sql.push('?');
+        }
+
+        fn finish(self) -> String {
+            self.sql
+        }
+    }
+
+    impl RenderQuery for Quoting {
+        fn render<T: QueryFragment<Self>>(query: &T) -> String {
+            let backend = Quoting { quote: '`' };
+            let builder = QuotingQueryBuilder {
+                quote: backend.quote,
+                sql: String::new(),
+            };
+            render_with(query, &backend, builder)
+                .unwrap_or_else(|e| format!("<failed to render query: {e}>"))
+        }
+    }
+
+    /// `SELECT * FROM` a table.
+    struct SelectAll(&'static str);
+
+    impl QueryFragment<Quoting> for SelectAll {
+        fn walk_ast<'b>(
+            &'b self,
+            mut out: diesel::query_builder::AstPass<'_, 'b, Quoting>,
+        ) -> QueryResult<()> {
+            out.push_sql("SELECT * FROM ");
+            out.push_identifier(self.0)
+        }
+    }
+
+    #[test]
+    fn render_with_a_backend_without_default() {
+        assert_eq!(
+            <Quoting as RenderQuery>::render(&SelectAll("users")),
+            "SELECT * FROM `users`"
+        );
+        let backend = Quoting { quote: '"' };
+        let builder = QuotingQueryBuilder {
+            quote: backend.quote,
+            sql: String::new(),
+        };
+        assert_eq!(
+            render_with(&SelectAll("users"), &backend, builder).unwrap(),
+            r#"SELECT * FROM "users""#
+        );
+    }
+
+    /// `SELECT 1 WHERE 1 IN` a list of values, with a placeholder per value.
+    struct InList(Vec<i32>);
+
+    impl QueryId for InList {
+        type QueryId = Self;
+        const HAS_STATIC_QUERY_ID: bool = true;
+    }
+
+    impl QueryFragment<Pg> for InList {
+        fn walk_ast<'b>(
+            &'b self,
+            mut out: diesel::query_builder::AstPass<'_, 'b, Pg>,
+        ) -> QueryResult<()> {
+            out.unsafe_to_cache_prepared();
+            out.push_sql("SELECT 1 WHERE 1 IN (");
+            for (i, value) in self.0.iter().enumerate() {
+                if i > 0 {
+                    out.push_sql(", ");
+                }
+                out.push_bind_param::<Integer, _>(value)?;
+            }
+            out.push_sql(")");
+            Ok(())
+        }
+    }
+
+    /// `SELECT 1`, with a static `QueryId`.
+    struct SelectOne;
+
+    impl QueryId for SelectOne {
+        type QueryId = Self;
+        const HAS_STATIC_QUERY_ID: bool = true;
+    }
+
+    impl QueryFragment<Pg> for SelectOne {
+        fn walk_ast<'b>(
+            &'b self,
+            mut out: diesel::query_builder::AstPass<'_, 'b, Pg>,
+        ) -> QueryResult<()> {
+            out.push_sql("SELECT 1");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_queries_with_a_fixed_shape_have_a_shape_key() {
+        assert_eq!(
+            <Pg as RenderQuery>::shape_key(&SelectOne),
+            Some(TypeId::of::<(Pg, SelectOne)>())
+        );
+        assert_ne!(
+            <Pg as RenderQuery>::shape_key(&SelectOne),
+            Some(TypeId::of::<SelectOne>())
+        );
+        assert_eq!(<Pg as RenderQuery>::shape_key(&InList(vec![1, 2])), None);
+        assert_eq!(
+            <Pg as RenderQuery>::shape_key(&diesel::sql_query("SELECT 1")),
+            None
+        );
+    }
+
+    #[test]
+    fn fingerprints_are_cached_by_shape_key() {
+        let key = <Pg as RenderQuery>::shape_key(&SelectOne);
+        let first = fingerprint(key, "SELECT 1");
+        assert_eq!(first, fnv1a(&normalize_shape("SELECT 1")));
+        // A second query with the same key reuses the first one's fingerprint.
+        assert_eq!(fingerprint(key, "SELECT 2, 3"), first);
+        assert_ne!(fingerprint(None, "SELECT 2, 3"), first);
+    }
+
+    #[test]
+    fn sql_query_binds_are_redacted() {
+        assert_eq!(
+            sql_query_text(Config::new().redact_bind_params(true)),
+            "UPDATE users SET name = $1, admin = $2 WHERE id = $3 -- binds: [?, ?, ?]"
+        );
+    }
+}
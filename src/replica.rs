@@ -0,0 +1,115 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reporting the replication lag of PostgreSQL replicas.
+
+use crate::clock;
+use crate::probes;
+use crate::DTraceConnection;
+use crate::DTraceTransactionManager;
+use crate::Role;
+use diesel::connection::{AnsiTransactionManager, Connection};
+use diesel::pg::PgConnection;
+use diesel::result::QueryResult;
+use diesel::sql_types::{BigInt, Nullable};
+use diesel::{QueryableByName, RunQueryDsl};
+use std::time::{Duration, Instant};
+
+/// A function that fetches the replication lag of a connection's database in
+/// milliseconds, or `None` if it isn't a replica.
+pub(crate) type LagFn<C> = fn(&mut C) -> QueryResult<Option<i64>>;
+
+/// How often to fetch the replication lag of a connection, and when it was
+/// last fetched.
+#[derive(Debug)]
+pub(crate) struct LagCheck<C> {
+    fetch: LagFn<C>,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl DTraceConnection<PgConnection> {
+    /// Report the replication lag of this connection's database at most once
+    /// every `interval`, or stop reporting it if `interval` is `None`.
+    ///
+    /// This only applies to connections whose [`Config::role`](crate::Config::role)
+    /// is [`Role::Replica`]. On those, a query that is issued at least
+    /// `interval` after the lag was last fetched is preceded by a query of
+    /// how far the replica's replay of the primary's WAL is behind, and the
+    /// `replica-lag` probe fires with the result, in milliseconds. A replica
+    /// that has replayed everything it received reports `0`, even if the
+    /// primary has been idle for a while.
+    ///
+    /// The lag is fetched directly on the inner connection, so it fires no
+    /// probes of its own. It's never fetched inside a transaction, where it
+    /// could abort the transaction if it failed, and the probe doesn't fire
+    /// if fetching it fails, or the database turns out not to be a replica.
+    /// This is disabled by default, since it issues an extra query.
+    pub fn report_replica_lag(&mut self, interval: Option<Duration>) {
+        self.replica_lag = interval.map(|interval| LagCheck {
+            fetch: lag as LagFn<PgConnection>,
+            interval,
+            last: None,
+        });
+    }
+}
+
+impl<C> DTraceConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    /// Fetch the replication lag and fire the `replica-lag` probe with it, if
+    /// this connection is configured to report it and it's due.
+    pub(crate) fn check_replica_lag(&mut self) {
+        if self.config.role != Role::Replica || DTraceTransactionManager::<C>::depth(self) > 0 {
+            return;
+        }
+        let Some(check) = &mut self.replica_lag else {
+            return;
+        };
+        let now = clock::now();
+        if check
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < check.interval)
+        {
+            return;
+        }
+        check.last = Some(now);
+        let fetch = check.fetch;
+        if let Ok(Some(lag_millis)) = fetch(&mut self.inner) {
+            probes::replica__lag!(|| (self.id, lag_millis));
+        }
+    }
+}
+
+/// The result of the query of the replication lag.
+#[derive(QueryableByName)]
+struct Lag {
+    #[diesel(sql_type = Nullable<BigInt>)]
+    lag_millis: Option<i64>,
+}
+
+/// Fetch the replication lag of the database of `conn`, in milliseconds.
+///
+/// On a primary, `pg_last_xact_replay_timestamp()` is null, and so is the lag.
+fn lag(conn: &mut PgConnection) -> QueryResult<Option<i64>> {
+    let lag = diesel::sql_query(
+        "SELECT CASE \
+            WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+            ELSE (EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000)::BIGINT \
+        END AS lag_millis",
+    )
+    .get_result::<Lag>(conn)?;
+    Ok(lag.lag_millis)
+}
@@ -0,0 +1,408 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded, in-memory history of recent queries and transactions.
+//!
+//! The history is a ring of slots, each a seqlock: a sequence number that is
+//! odd while the slot is being written, and a copy of the event in atomics,
+//! including up to [`MAX_QUERY_LEN`] bytes of its text. Recording an event
+//! claims the next slot with an atomic increment, marks it as being written,
+//! stores the event, and marks it as written, without taking any lock.
+//! Reading a slot copies it and checks that its sequence number didn't change
+//! meanwhile, so a reader never delays a query either. If the ring wraps all
+//! the way around while an event is being written, the later event that lands
+//! on the same slot is dropped rather than waiting, and a slot being written
+//! when the history is read is left out of it.
+//!
+//! Each thread keeps a reference to the ring it records into, which it only
+//! replaces, under a lock, when [`set_recent_events_capacity`] has replaced the
+//! ring since. A thread may record a few more events into the ring that was
+//! replaced, which are lost with it, and keeps that ring alive until it next
+//! records an event.
+
+use crate::TRUNCATION_MARKER;
+use std::cell::RefCell;
+use std::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// The number of events retained by default.
+const DEFAULT_CAPACITY: usize = 128;
+
+/// The number of bytes of the text of a query retained with it. Longer texts
+/// are truncated, and end with [`TRUNCATION_MARKER`].
+const MAX_QUERY_LEN: usize = 1024;
+
+const QUERY_WORDS: usize = MAX_QUERY_LEN / 8;
+
+/// A query or transaction that completed recently, returned by
+/// [`recent_events`].
+#[derive(Clone, Debug)]
+pub struct RecentEvent {
+    /// The time the query or transaction completed.
+    pub timestamp: SystemTime,
+    /// The ID of the connection.
+    pub conn_id: Uuid,
+    /// Whether this was a query or a transaction.
+    pub kind: RecentEventKind,
+    /// The text of the query, as passed to the probes, or the empty string for
+    /// a transaction. Only the first 1024 bytes of the text are retained.
+    pub query: String,
+    /// How long the query or transaction took.
+    pub duration: Duration,
+}
+
+/// The kind of a [`RecentEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecentEventKind {
+    /// A query, from `query-start` to `query-done`.
+    Query,
+    /// A transaction, from `transaction-start` to `transaction-done`.
+    Transaction,
+}
+
+/// One event in the ring, stored in atomics so that it can be read while it
+/// is being written.
+struct Slot {
+    // Zero while the slot is empty, and otherwise twice one more than the
+    // sequence number of the event in it, plus one while it is being written.
+    state: AtomicU64,
+    // The time since the Unix epoch the event completed at, in nanoseconds.
+    timestamp: AtomicU64,
+    conn_id: [AtomicU64; 2],
+    // Whether this is a transaction rather than a query.
+    transaction: AtomicU64,
+    // The duration of the event, in nanoseconds.
+    duration: AtomicU64,
+    query_len: AtomicUsize,
+    // The text of the query, in little-endian words.
+    query: [AtomicU64; QUERY_WORDS],
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            state: AtomicU64::new(0),
+            timestamp: AtomicU64::new(0),
+            conn_id: [AtomicU64::new(0), AtomicU64::new(0)],
+            transaction: AtomicU64::new(0),
+            duration: AtomicU64::new(0),
+            query_len: AtomicUsize::new(0),
+            query: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Store `event`, recorded with the sequence number `seq`, unless another
+    /// event is being written, or was written with a later sequence number.
+    fn write(&self, seq: u64, event: &Event<'_>) {
+        let written = (seq + 1) << 1;
+        let state = self.state.load(Ordering::Relaxed);
+        if state & 1 == 1 || state > written {
+            return;
+        }
+        if self
+            .state
+            .compare_exchange(state, written | 1, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        // Order the stores of the event after marking the slot as being
+        // written, so that a reader seeing any of them sees that mark too.
+        fence(Ordering::Release);
+        let nanos = |d: Duration| u64::try_from(d.as_nanos()).unwrap_or(u64::MAX);
+        let since_epoch = event
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.timestamp.store(nanos(since_epoch), Ordering::Relaxed);
+        let conn_id = event.conn_id.as_u128();
+        self.conn_id[0].store((conn_id >> 64) as u64, Ordering::Relaxed);
+        self.conn_id[1].store(conn_id as u64, Ordering::Relaxed);
+        let transaction = event.kind == RecentEventKind::Transaction;
+        self.transaction
+            .store(u64::from(transaction), Ordering::Relaxed);
+        self.duration
+            .store(nanos(event.duration), Ordering::Relaxed);
+        let query = truncate(event.query);
+        self.query_len.store(query.len(), Ordering::Relaxed);
+        for (word, chunk) in self.query.iter().zip(query.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            word.store(u64::from_le_bytes(bytes), Ordering::Relaxed);
+        }
+        self.state.store(written, Ordering::Release);
+    }
+
+    /// Return the event in the slot and its sequence number, if it holds one
+    /// that isn't being written.
+    fn read(&self) -> Option<(u64, RecentEvent)> {
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            if state == 0 || state & 1 == 1 {
+                return None;
+            }
+            let timestamp = self.timestamp.load(Ordering::Relaxed);
+            let conn_id = Uuid::from_u128(
+                (u128::from(self.conn_id[0].load(Ordering::Relaxed)) << 64)
+                    | u128::from(self.conn_id[1].load(Ordering::Relaxed)),
+            );
+            let transaction = self.transaction.load(Ordering::Relaxed) == 1;
+            let duration = self.duration.load(Ordering::Relaxed);
+            let len = self.query_len.load(Ordering::Relaxed).min(MAX_QUERY_LEN);
+            let mut query: Vec<u8> = self
+                .query
+                .iter()
+                .take(len.div_ceil(8))
+                .flat_map(|word| word.load(Ordering::Relaxed).to_le_bytes())
+                .collect();
+            // Order the loads of the event before checking that the slot
+            // didn't change, so that a torn copy is never returned.
+            fence(Ordering::Acquire);
+            if self.state.load(Ordering::Relaxed) != state {
+                continue;
+            }
+            query.truncate(len);
+            let event = RecentEvent {
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_nanos(timestamp),
+                conn_id,
+                kind: if transaction {
+                    RecentEventKind::Transaction
+                } else {
+                    RecentEventKind::Query
+                },
+                query: String::from_utf8(query)
+                    .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+                duration: Duration::from_nanos(duration),
+            };
+            return Some(((state >> 1) - 1, event));
+        }
+    }
+}
+
+/// Return the bytes of `query` retained in a slot, truncated to at most
+/// [`MAX_QUERY_LEN`] bytes on a character boundary.
+fn truncate(query: &str) -> std::borrow::Cow<'_, [u8]> {
+    if query.len() <= MAX_QUERY_LEN {
+        return query.as_bytes().into();
+    }
+    let mut end = MAX_QUERY_LEN - TRUNCATION_MARKER.len();
+    while !query.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{TRUNCATION_MARKER}", &query[..end])
+        .into_bytes()
+        .into()
+}
+
+/// An event being recorded, borrowing its text.
+struct Event<'a> {
+    timestamp: SystemTime,
+    conn_id: Uuid,
+    kind: RecentEventKind,
+    query: &'a str,
+    duration: Duration,
+}
+
+struct Ring {
+    slots: Box<[Slot]>,
+    next: AtomicU64,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| Slot::new()).collect(),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, event: &Event<'_>) {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        self.slots[(seq % self.slots.len() as u64) as usize].write(seq, event);
+    }
+}
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CAPACITY);
+
+static RING: RwLock<Option<Arc<Ring>>> = RwLock::new(None);
+
+/// Incremented each time the ring is replaced, so that each thread knows when
+/// to replace its reference to it.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static RECORDER: RefCell<(u64, Option<Arc<Ring>>)> = const { RefCell::new((0, None)) };
+}
+
+/// Return the recently completed queries and transactions, oldest first.
+///
+/// This retains the last 128 events by default, which can be changed with
+/// [`set_recent_events_capacity`]. Only the first 1024 bytes of the text of
+/// each query are retained.
+///
+/// Neither this nor recording an event takes a lock shared with the other,
+/// so this never delays a query. An event being recorded while this copies
+/// the history is left out of it.
+pub fn recent_events() -> Vec<RecentEvent> {
+    let Some(ring) = RING.read().unwrap_or_else(PoisonError::into_inner).clone() else {
+        return Vec::new();
+    };
+    let mut events: Vec<_> = ring.slots.iter().filter_map(Slot::read).collect();
+    events.sort_by_key(|(seq, _)| *seq);
+    events.into_iter().map(|(_, event)| event).collect()
+}
+
+/// Set the number of recent events retained, discarding those retained so far.
+///
+/// A capacity of `0` disables the history, so that queries pay no cost for it.
+pub fn set_recent_events_capacity(capacity: usize) {
+    let mut ring = RING.write().unwrap_or_else(PoisonError::into_inner);
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    *ring = None;
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Return true if events are being retained.
+pub(crate) fn enabled() -> bool {
+    CAPACITY.load(Ordering::Relaxed) > 0
+}
+
+/// Retain an event that just completed.
+pub(crate) fn record(kind: RecentEventKind, conn_id: Uuid, query: &str, duration: Duration) {
+    let event = Event {
+        timestamp: SystemTime::now(),
+        conn_id,
+        kind,
+        query,
+        duration,
+    };
+    // This fails only while the thread is exiting, in which case the event
+    // isn't retained.
+    let _ = RECORDER.try_with(|recorder| {
+        let mut recorder = recorder.borrow_mut();
+        let generation = GENERATION.load(Ordering::Acquire);
+        if recorder.0 != generation {
+            *recorder = (generation, current());
+        }
+        if let Some(ring) = &recorder.1 {
+            ring.push(&event);
+        }
+    });
+}
+
+/// Return the ring, creating it if this is the first event since the capacity
+/// was set.
+fn current() -> Option<Arc<Ring>> {
+    if let Some(ring) = RING.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        return Some(ring.clone());
+    }
+    let mut ring = RING.write().unwrap_or_else(PoisonError::into_inner);
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    if capacity == 0 {
+        return None;
+    }
+    Some(
+        ring.get_or_insert_with(|| Arc::new(Ring::new(capacity)))
+            .clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events_of(conn_id: Uuid) -> Vec<RecentEvent> {
+        recent_events()
+            .into_iter()
+            .filter(|event| event.conn_id == conn_id)
+            .collect()
+    }
+
+    #[test]
+    fn recorded_events_are_read_back() {
+        let conn_id = Uuid::new_v4();
+        record(
+            RecentEventKind::Query,
+            conn_id,
+            "SELECT 'é'",
+            Duration::from_micros(3),
+        );
+        record(
+            RecentEventKind::Transaction,
+            conn_id,
+            "",
+            Duration::from_secs(1),
+        );
+        let events = events_of(conn_id);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, RecentEventKind::Query);
+        assert_eq!(events[0].query, "SELECT 'é'");
+        assert_eq!(events[0].duration, Duration::from_micros(3));
+        assert_eq!(events[1].kind, RecentEventKind::Transaction);
+        assert_eq!(events[1].query, "");
+        assert!(events[0].timestamp <= events[1].timestamp);
+    }
+
+    #[test]
+    fn long_queries_are_truncated_on_a_character_boundary() {
+        let conn_id = Uuid::new_v4();
+        let query = format!("SELECT '{}'", "é".repeat(MAX_QUERY_LEN));
+        record(RecentEventKind::Query, conn_id, &query, Duration::ZERO);
+        let events = events_of(conn_id);
+        assert!(events[0].query.len() <= MAX_QUERY_LEN);
+        assert!(events[0].query.ends_with(TRUNCATION_MARKER));
+        assert!(query.starts_with(events[0].query.trim_end_matches(TRUNCATION_MARKER)));
+    }
+
+    #[test]
+    fn the_ring_keeps_the_latest_events_in_order() {
+        let ring = Ring::new(4);
+        let conn_id = Uuid::new_v4();
+        for i in 0..10 {
+            let query = i.to_string();
+            ring.push(&Event {
+                timestamp: SystemTime::now(),
+                conn_id,
+                kind: RecentEventKind::Query,
+                query: &query,
+                duration: Duration::ZERO,
+            });
+        }
+        let mut events: Vec<_> = ring.slots.iter().filter_map(Slot::read).collect();
+        events.sort_by_key(|(seq, _)| *seq);
+        let queries: Vec<_> = events.into_iter().map(|(_, event)| event.query).collect();
+        assert_eq!(queries, ["6", "7", "8", "9"]);
+    }
+
+    #[test]
+    fn a_slot_being_written_is_left_out() {
+        let slot = Slot::new();
+        slot.state.store(3, Ordering::Relaxed);
+        assert!(slot.read().is_none());
+        // Nor is it overwritten by another event meanwhile.
+        slot.write(
+            5,
+            &Event {
+                timestamp: SystemTime::now(),
+                conn_id: Uuid::nil(),
+                kind: RecentEventKind::Query,
+                query: "SELECT 1",
+                duration: Duration::ZERO,
+            },
+        );
+        assert_eq!(slot.state.load(Ordering::Relaxed), 3);
+    }
+}
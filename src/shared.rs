@@ -0,0 +1,84 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A connection shared between threads behind a mutex.
+
+use crate::clock;
+use crate::probes;
+use crate::query::duration_nanos;
+use crate::DTraceConnection;
+use diesel::connection::Connection;
+use std::sync::{Arc, LockResult, Mutex, MutexGuard, TryLockError};
+
+/// A [`DTraceConnection`] shared between threads, which reports contention for
+/// it.
+///
+/// This is an `Arc<Mutex<DTraceConnection<C>>>`, for applications that share
+/// a single connection rather than using a pool. Waiting for another thread to
+/// finish with the connection can be a real bottleneck, which is otherwise
+/// invisible to the probes. When [`SharedDTraceConnection::lock`] finds the
+/// connection in use, it fires the `connection-lock-wait` probe once it gets
+/// the connection, with how long it waited. Locking a connection that is free
+/// fires nothing.
+///
+/// Clones refer to the same connection.
+#[derive(Debug)]
+pub struct SharedDTraceConnection<C: Connection> {
+    inner: Arc<Mutex<DTraceConnection<C>>>,
+}
+
+impl<C: Connection> SharedDTraceConnection<C> {
+    /// Share `conn` between threads.
+    pub fn new(conn: DTraceConnection<C>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// Acquire the connection, blocking until no other thread holds it.
+    ///
+    /// As with [`Mutex::lock`], this returns an error if another thread
+    /// panicked while holding the connection, but the connection can still be
+    /// recovered from the error.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, DTraceConnection<C>>> {
+        match self.inner.try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(error)) => return Err(error),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        let start = clock::now();
+        let result = self.inner.lock();
+        let waited = clock::now().saturating_duration_since(start);
+        let conn = match &result {
+            Ok(guard) => guard,
+            Err(error) => error.get_ref(),
+        };
+        probes::connection__lock__wait!(|| (conn.id(), duration_nanos(waited)));
+        result
+    }
+}
+
+impl<C: Connection> Clone for SharedDTraceConnection<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C: Connection> From<DTraceConnection<C>> for SharedDTraceConnection<C> {
+    fn from(conn: DTraceConnection<C>) -> Self {
+        Self::new(conn)
+    }
+}
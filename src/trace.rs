@@ -0,0 +1,141 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `tracing` spans and events for each query.
+//!
+//! With the `tracing` feature, each query is reported to the current `tracing`
+//! subscriber as a span, as events when it starts and completes, or both, as
+//! chosen with [`Config::tracing_mode`](crate::Config::tracing_mode). The
+//! span and events are at the `DEBUG` level, and carry the same details as the
+//! probes, as structured fields.
+
+use crate::query::duration_nanos;
+use crate::QueryErrorKind;
+use crate::QueryKind;
+use diesel::result::Error;
+use std::time::Duration;
+use tracing::{field, Level, Span};
+use uuid::Uuid;
+
+/// How queries are reported to `tracing`, set with
+/// [`Config::tracing_mode`](crate::Config::tracing_mode).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TracingMode {
+    /// A `query` span covering each query, from the `query-start` probe to
+    /// `query-done`.
+    #[default]
+    Spans,
+    /// A `query started` event when each query starts, and a `query done` or
+    /// `query failed` event when it completes.
+    Events,
+    /// Both the spans and the events, with the events inside the span.
+    Both,
+}
+
+impl TracingMode {
+    fn spans(self) -> bool {
+        matches!(self, Self::Spans | Self::Both)
+    }
+
+    fn events(self) -> bool {
+        matches!(self, Self::Events | Self::Both)
+    }
+}
+
+/// The span of a single query, and whether events are emitted for it.
+pub(crate) struct QueryTrace {
+    span: Span,
+    events: bool,
+}
+
+impl QueryTrace {
+    /// Start reporting a query with the ID `id` on the connection `conn_id`.
+    ///
+    /// `query` returns the text and kind of the query, and is only called if
+    /// the span or events are enabled.
+    pub(crate) fn start(
+        mode: TracingMode,
+        id: u64,
+        conn_id: Uuid,
+        query: impl FnOnce() -> (String, QueryKind),
+    ) -> Self {
+        let span = if mode.spans() {
+            tracing::debug_span!(
+                "query",
+                id,
+                %conn_id,
+                kind = field::Empty,
+                query = field::Empty,
+                rows = field::Empty,
+                duration_ns = field::Empty,
+                error_kind = field::Empty,
+            )
+        } else {
+            Span::none()
+        };
+        let events = mode.events() && tracing::enabled!(Level::DEBUG);
+        if !span.is_disabled() || events {
+            let (query, kind) = query();
+            span.record("kind", field::debug(kind));
+            span.record("query", query.as_str());
+            if events {
+                span.in_scope(|| {
+                    tracing::debug!(id, %conn_id, ?kind, query = query.as_str(), "query started");
+                });
+            }
+        }
+        Self { span, events }
+    }
+
+    /// Report the completion of the query, with the number of rows, if
+    /// known, or the error it failed with, after `duration`.
+    pub(crate) fn done(
+        &self,
+        id: u64,
+        conn_id: Uuid,
+        result: Result<Option<u64>, &Error>,
+        duration: Duration,
+    ) {
+        let duration_ns = duration_nanos(duration);
+        self.span.record("duration_ns", duration_ns);
+        match result {
+            Ok(rows) => {
+                if let Some(rows) = rows {
+                    self.span.record("rows", rows);
+                }
+                if self.events {
+                    self.span.in_scope(|| {
+                        tracing::debug!(id, %conn_id, rows, duration_ns, "query done");
+                    });
+                }
+            }
+            Err(error) => {
+                let error_kind = QueryErrorKind::from_error(error);
+                self.span.record("error_kind", field::debug(error_kind));
+                if self.events {
+                    self.span.in_scope(|| {
+                        tracing::debug!(
+                            id,
+                            %conn_id,
+                            ?error_kind,
+                            %error,
+                            duration_ns,
+                            "query failed"
+                        );
+                    });
+                }
+            }
+        }
+    }
+}
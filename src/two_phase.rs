@@ -0,0 +1,147 @@
+// Copyright 2024 Oxide Computer Company
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recognition of PostgreSQL's two-phase commit statements.
+
+use crate::probes;
+use uuid::Uuid;
+
+/// A step in the lifecycle of a prepared transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Prepare,
+    Commit,
+    Rollback,
+}
+
+/// Fire the `two_phase-*` probe for `sql`, which was executed successfully, if
+/// it is a two-phase commit statement.
+pub(crate) fn fire(conn_id: Uuid, sql: &str) {
+    let Some((step, gid)) = parse(sql) else {
+        return;
+    };
+    match step {
+        Step::Prepare => probes::two_phase__prepare!(|| (conn_id, &gid)),
+        Step::Commit => probes::two_phase__commit!(|| (conn_id, &gid)),
+        Step::Rollback => probes::two_phase__rollback!(|| (conn_id, &gid)),
+    }
+}
+
+/// Parse a `PREPARE TRANSACTION`, `COMMIT PREPARED`, or `ROLLBACK PREPARED`
+/// statement at the start of `sql`, returning the step and the transaction's
+/// global identifier.
+fn parse(sql: &str) -> Option<(Step, String)> {
+    let (step, rest) = if let Some(rest) = strip_keyword(sql, "PREPARE") {
+        (Step::Prepare, strip_keyword(rest, "TRANSACTION")?)
+    } else if let Some(rest) = strip_keyword(sql, "COMMIT") {
+        (Step::Commit, strip_keyword(rest, "PREPARED")?)
+    } else {
+        let rest = strip_keyword(sql, "ROLLBACK")?;
+        (Step::Rollback, strip_keyword(rest, "PREPARED")?)
+    };
+
+    // The identifier is a string literal, in which a quote is escaped by
+    // doubling it.
+    let mut chars = rest.trim_start().strip_prefix('\'')?.chars();
+    let mut gid = String::new();
+    loop {
+        match chars.next()? {
+            '\'' if chars.as_str().starts_with('\'') => {
+                chars.next();
+                gid.push('\'');
+            }
+            '\'' => break,
+            c => gid.push(c),
+        }
+    }
+    Some((step, gid))
+}
+
+/// Strip `keyword` from the start of `sql`, ignoring case and leading
+/// whitespace, returning the rest of the statement.
+fn strip_keyword<'a>(sql: &'a str, keyword: &str) -> Option<&'a str> {
+    let sql = sql.trim_start();
+    let head = sql.get(..keyword.len())?;
+    let rest = &sql[keyword.len()..];
+    let whole_word = !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    (head.eq_ignore_ascii_case(keyword) && whole_word).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(step: Step, gid: &str) -> Option<(Step, String)> {
+        Some((step, String::from(gid)))
+    }
+
+    #[test]
+    fn each_statement_is_recognized() {
+        assert_eq!(
+            parse("PREPARE TRANSACTION 'tx-1'"),
+            step(Step::Prepare, "tx-1")
+        );
+        assert_eq!(parse("COMMIT PREPARED 'tx-1'"), step(Step::Commit, "tx-1"));
+        assert_eq!(
+            parse("ROLLBACK PREPARED 'tx-1';"),
+            step(Step::Rollback, "tx-1")
+        );
+    }
+
+    #[test]
+    fn keywords_ignore_case_and_whitespace() {
+        assert_eq!(
+            parse("  prepare\n\tTransaction  'tx-1'"),
+            step(Step::Prepare, "tx-1")
+        );
+        assert_eq!(parse("Commit prepared'tx-1'"), step(Step::Commit, "tx-1"));
+    }
+
+    #[test]
+    fn doubled_quotes_are_unescaped() {
+        assert_eq!(parse("COMMIT PREPARED 'it''s'"), step(Step::Commit, "it's"));
+        assert_eq!(parse("COMMIT PREPARED ''''"), step(Step::Commit, "'"));
+        assert_eq!(parse("COMMIT PREPARED ''"), step(Step::Commit, ""));
+    }
+
+    #[test]
+    fn other_statements_are_not_recognized() {
+        for sql in [
+            "PREPARE foo AS SELECT 1",
+            "PREPARE TRANSACTIONS 'tx-1'",
+            "COMMIT",
+            "COMMIT PREPAREDX 'tx-1'",
+            "ROLLBACK",
+            "ROLLBACK TO SAVEPOINT a",
+            "COMMITTED PREPARED 'tx-1'",
+            "SELECT 'COMMIT PREPARED ''tx-1'''",
+            // The identifier must be a complete string literal.
+            "COMMIT PREPARED tx",
+            "COMMIT PREPARED 'tx",
+        ] {
+            assert_eq!(parse(sql), None, "{sql}");
+        }
+    }
+
+    #[test]
+    fn keywords_are_whole_words() {
+        assert_eq!(strip_keyword(" COMMIT x", "commit"), Some(" x"));
+        assert_eq!(strip_keyword("COMMIT", "COMMIT"), Some(""));
+        assert_eq!(strip_keyword("COMMIT_1", "COMMIT"), None);
+        assert_eq!(strip_keyword("COMMITS", "COMMIT"), None);
+        assert_eq!(strip_keyword("COMM", "COMMIT"), None);
+        // A keyword is never matched in the middle of a character.
+        assert_eq!(strip_keyword("COMMIé", "COMMIT"), None);
+    }
+}